@@ -0,0 +1,188 @@
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::Command;
+use std::time::Duration;
+
+use super::ardu::{self, ArduSketch};
+use super::utils::MyError;
+
+/// A plug that doesn't answer within this is considered unreachable
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_RESPONSE: u64 = 64 * 1024;
+
+/// What switches the charger on and off
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Actuator {
+    /// The stepper rig, moved by the sketch flashed with avrdude
+    Arduino,
+    /// Tasmota plug at host[:port], http://host/cm?cmnd=Power%20On
+    Tasmota(String),
+    /// Shelly plug at host[:port], the RPC API of the Plus and Gen3 devices
+    /// or the /relay API of the Gen1 ones
+    Shelly { host: String, gen1: bool },
+    /// Shell commands run through `sh -c`
+    Command { connect: String, disconnect: String },
+}
+
+impl Actuator {
+    /// `arduino`, `tasmota HOST`, `shelly HOST`, `shelly1 HOST` or `command`, whose
+    /// commands are set apart
+    pub fn match_string(str_actuator: &str) -> Result<Self, MyError> {
+        let (kind, host) = match str_actuator.split_once(char::is_whitespace) {
+            Some((kind, host)) => (kind, host.trim()),
+            None => (str_actuator, ""),
+        };
+        match (kind, host) {
+            ("arduino", "") => Ok(Self::Arduino),
+            ("command", "") => Ok(Self::Command {
+                connect: String::new(),
+                disconnect: String::new(),
+            }),
+            ("tasmota", host) if !host.is_empty() => Ok(Self::Tasmota(host.to_owned())),
+            ("shelly", host) if !host.is_empty() => Ok(Self::Shelly {
+                host: host.to_owned(),
+                gen1: false,
+            }),
+            ("shelly1", host) if !host.is_empty() => Ok(Self::Shelly {
+                host: host.to_owned(),
+                gen1: true,
+            }),
+            _ => Err(MyError::ConfigError(format!(
+                "'{str_actuator}' is not a valid actuator, use arduino, tasmota HOST, shelly HOST, shelly1 HOST or command"
+            ))),
+        }
+    }
+
+    /// Send `command`, only the arduino has something to do for DoNothing.
+    /// Whether the charger really moved is seen in the battery status
+    pub fn switch(&self, command: ArduSketch) -> Result<(), MyError> {
+        let on = match command {
+            ArduSketch::Connect => true,
+            ArduSketch::Disconnect => false,
+            ArduSketch::DoNothing => {
+                return match self {
+                    Actuator::Arduino => ardu::flash(command),
+                    _ => Ok(()),
+                }
+            }
+        };
+        match self {
+            Actuator::Arduino => ardu::flash(command),
+            Actuator::Tasmota(host) => {
+                let power = if on { "On" } else { "Off" };
+                let body = http_get(host, &format!("/cm?cmnd=Power%20{power}"))?;
+                // {"POWER":"ON"}, or POWER1 on the plugs with more relays
+                let reported = serde_json::from_str::<serde_json::Value>(&body)
+                    .ok()
+                    .and_then(|json| {
+                        json.as_object()?
+                            .iter()
+                            .find(|(key, _)| key.starts_with("POWER"))
+                            .and_then(|(_, value)| value.as_str().map(str::to_owned))
+                    });
+                match reported {
+                    Some(state) if state.eq_ignore_ascii_case(power) => Ok(()),
+                    _ => Err(MyError::ActuatorError(format!(
+                        "Tasmota {host} didn't switch {power}: {body}"
+                    ))),
+                }
+            }
+            Actuator::Shelly { host, gen1: false } => {
+                http_get(host, &format!("/rpc/Switch.Set?id=0&on={on}")).map(|_| ())
+            }
+            Actuator::Shelly { host, gen1: true } => {
+                let turn = if on { "on" } else { "off" };
+                let body = http_get(host, &format!("/relay/0?turn={turn}"))?;
+                let ison = serde_json::from_str::<serde_json::Value>(&body)
+                    .ok()
+                    .and_then(|json| json["ison"].as_bool());
+                match ison {
+                    Some(ison) if ison == on => Ok(()),
+                    _ => Err(MyError::ActuatorError(format!(
+                        "Shelly {host} didn't turn {turn}: {body}"
+                    ))),
+                }
+            }
+            Actuator::Command {
+                connect,
+                disconnect,
+            } => {
+                let shell_command = if on { connect } else { disconnect };
+                let status = Command::new("sh")
+                    .args(["-c", shell_command])
+                    .status()
+                    .map_err(|err| {
+                        MyError::ActuatorError(format!("Failed to run '{shell_command}': {err}"))
+                    })?;
+                match status.success() {
+                    true => Ok(()),
+                    false => Err(MyError::ActuatorError(format!(
+                        "'{shell_command}' failed: {status}"
+                    ))),
+                }
+            }
+        }
+    }
+}
+
+/// A plain HTTP/1.1 GET, the body of a 200 response
+fn http_get(host: &str, path: &str) -> Result<String, MyError> {
+    let failed = |err: &dyn std::fmt::Display| {
+        MyError::ActuatorError(format!("Failed to reach http://{host}{path}: {err}"))
+    };
+    let address = match host.contains(':') {
+        true => host.to_owned(),
+        false => format!("{host}:80"),
+    };
+    let address = address
+        .to_socket_addrs()
+        .map_err(|err| failed(&err))?
+        .next()
+        .ok_or_else(|| failed(&"no address"))?;
+
+    let mut stream =
+        TcpStream::connect_timeout(&address, HTTP_TIMEOUT).map_err(|err| failed(&err))?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+    write!(
+        stream,
+        "GET {path} HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n"
+    )
+    .map_err(|err| failed(&err))?;
+
+    let mut response = Vec::new();
+    stream
+        .take(MAX_RESPONSE)
+        .read_to_end(&mut response)
+        .map_err(|err| failed(&err))?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let status = head.lines().next().unwrap_or_default();
+    let chunked = head
+        .lines()
+        .any(|line| line.eq_ignore_ascii_case("transfer-encoding: chunked"));
+    match status.split(' ').nth(1) {
+        Some("200") if chunked => Ok(dechunk(body)),
+        Some("200") => Ok(body.to_owned()),
+        _ => Err(failed(&status)),
+    }
+}
+
+/// The body of a chunked response, as sent by some firmwares
+fn dechunk(mut body: &str) -> String {
+    let mut decoded = String::new();
+    while let Some((size, rest)) = body.split_once("\r\n") {
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let Ok(size) = usize::from_str_radix(size, 16) else {
+            break;
+        };
+        match (rest.get(..size), rest.get(size..)) {
+            (Some(chunk), Some(rest)) if size > 0 => {
+                decoded.push_str(chunk);
+                body = rest.trim_start_matches("\r\n");
+            }
+            _ => break,
+        }
+    }
+    decoded
+}
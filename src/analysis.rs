@@ -0,0 +1,383 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use chrono::{DateTime, Duration, FixedOffset, NaiveDate};
+
+use super::battery_health::BatteryIdentity;
+use super::history::HistoryRecord;
+use super::utils::MyError;
+
+/// Standard errors on each side of the slope for the bounds, about 95%
+const CONFIDENCE_Z: f64 = 1.96;
+const SECONDS_PER_DAY: f64 = 24.0 * 60.0 * 60.0;
+/// Longer gaps between two records mean the daemon wasn't running or the laptop was
+/// off, the time in between isn't counted
+pub const MAX_GAP: Duration = Duration::hours(1);
+
+/// Points of charge lost from `current` to the `next` record, a cycle is 100 of them
+/// whatever the charges in between. A drop over a gap happened while suspended or off,
+/// it isn't counted.
+pub fn discharged(current: &HistoryRecord, next: &HistoryRecord) -> f64 {
+    if next.time - current.time > MAX_GAP {
+        return 0.0;
+    }
+    match (current.battery_percentage, next.battery_percentage) {
+        (Some(current), Some(next)) if next < current => f64::from(current - next),
+        _ => 0.0,
+    }
+}
+
+/// Least squares line through (x, y) points
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinearFit {
+    pub slope: f64,
+    pub intercept: f64,
+    pub slope_stderr: f64,
+    pub mean_x: f64,
+    pub mean_y: f64,
+    pub points: usize,
+}
+
+impl LinearFit {
+    /// None with less than 3 points or when every x is the same
+    pub fn fit(points: &[(f64, f64)]) -> Option<Self> {
+        let n = points.len();
+        if n < 3 {
+            return None;
+        }
+        let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n as f64;
+        let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n as f64;
+        let sxx = points
+            .iter()
+            .map(|(x, _)| (x - mean_x).powi(2))
+            .sum::<f64>();
+        let sxy = points
+            .iter()
+            .map(|(x, y)| (x - mean_x) * (y - mean_y))
+            .sum::<f64>();
+        if sxx == 0.0 {
+            return None;
+        }
+
+        let slope = sxy / sxx;
+        let intercept = mean_y - slope * mean_x;
+        let residuals = points
+            .iter()
+            .map(|(x, y)| (y - (intercept + slope * x)).powi(2))
+            .sum::<f64>();
+        let slope_stderr = (residuals / (n - 2) as f64 / sxx).sqrt();
+
+        Some(LinearFit {
+            slope,
+            intercept,
+            slope_stderr,
+            mean_x,
+            mean_y,
+            points: n,
+        })
+    }
+
+    pub fn at(&self, x: f64) -> f64 {
+        self.intercept + self.slope * x
+    }
+
+    /// x where a line through the centroid with `slope` reaches `y`, None if it never does
+    fn reaches_with_slope(&self, y: f64, slope: f64) -> Option<f64> {
+        if slope >= 0.0 {
+            return None;
+        }
+        Some(self.mean_x + (y - self.mean_y) / slope)
+    }
+
+    /// x where the fitted line reaches `y`, None if it isn't going down
+    pub fn reaches(&self, y: f64) -> Option<f64> {
+        self.reaches_with_slope(y, self.slope)
+    }
+
+    /// Earliest and latest x to reach `y` with the slope moved by the confidence bounds,
+    /// the latest is None when the flattest slope never gets there
+    pub fn reaches_bounds(&self, y: f64) -> (Option<f64>, Option<f64>) {
+        let margin = CONFIDENCE_Z * self.slope_stderr;
+        (
+            self.reaches_with_slope(y, self.slope - margin),
+            self.reaches_with_slope(y, self.slope + margin),
+        )
+    }
+}
+
+/// One point per day, so that days logged every minute don't outweigh the others
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct DailyHealth {
+    /// Days since the first record
+    day: f64,
+    health: f64,
+    /// Equivalent full cycles since the first record, at the end of the day
+    cycles: f64,
+    has_percentage: bool,
+}
+
+fn daily_health(records: &[HistoryRecord]) -> Vec<DailyHealth> {
+    let first_time = records[0].time;
+    let mut days: BTreeMap<NaiveDate, (f64, usize, f64, bool)> = BTreeMap::new();
+    let mut cycles = 0.0;
+    let mut last = None;
+
+    for record in records {
+        if let Some(last) = last {
+            cycles += discharged(last, record) / 100.0;
+        }
+        last = Some(record);
+
+        let day = days
+            .entry(record.time.date_naive())
+            .or_insert((0.0, 0, 0.0, false));
+        day.0 += f64::from(record.battery_health);
+        day.1 += 1;
+        day.2 = cycles;
+        day.3 |= record.battery_percentage.is_some();
+    }
+
+    days.into_iter()
+        .map(
+            |(date, (health_sum, count, cycles, has_percentage))| DailyHealth {
+                day: (date - first_time.date_naive()).num_days() as f64,
+                health: health_sum / count as f64,
+                cycles,
+                has_percentage,
+            },
+        )
+        .collect()
+}
+
+/// When the battery will reach a health target
+#[derive(Debug, Clone, PartialEq)]
+pub struct Forecast {
+    pub target: f64,
+    pub expected: Option<DateTime<FixedOffset>>,
+    pub earliest: Option<DateTime<FixedOffset>>,
+    pub latest: Option<DateTime<FixedOffset>>,
+}
+
+/// How many more cycles the battery can do before a health target
+#[derive(Debug, Clone, PartialEq)]
+pub struct CycleForecast {
+    pub target: f64,
+    pub expected: Option<f64>,
+    pub fewest: Option<f64>,
+    pub most: Option<f64>,
+}
+
+/// Capacity fade of the battery from its history
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthReport {
+    pub samples: usize,
+    pub first: DateTime<FixedOffset>,
+    pub last: DateTime<FixedOffset>,
+    /// Mean health of the last day
+    pub current_health: f64,
+    /// Health percentage points per day
+    pub per_day: LinearFit,
+    /// Health percentage points per cycle, only with the battery percentage logged
+    pub per_cycle: Option<LinearFit>,
+    pub total_cycles: f64,
+    pub forecasts: Vec<Forecast>,
+    pub cycle_forecasts: Vec<CycleForecast>,
+}
+
+impl HealthReport {
+    /// Fit the trends on `records`, of any order, and forecast each of `targets`
+    pub fn new(records: &[HistoryRecord], targets: &[f64]) -> Result<Self, MyError> {
+        let mut records = records.to_vec();
+        records.sort_by_key(|record| record.time);
+        records.dedup_by_key(|record| record.time);
+        if records.is_empty() {
+            return Err(MyError::HealthStatsError("The history is empty".to_owned()));
+        }
+
+        let days = daily_health(&records);
+        let per_day = LinearFit::fit(
+            &days
+                .iter()
+                .map(|day| (day.day, day.health))
+                .collect::<Vec<_>>(),
+        )
+        .ok_or_else(|| {
+            MyError::HealthStatsError(format!(
+                "At least 3 different days are needed for a trend, the history has {}",
+                days.len()
+            ))
+        })?;
+        let per_cycle = LinearFit::fit(
+            &days
+                .iter()
+                .filter(|day| day.has_percentage)
+                .map(|day| (day.cycles, day.health))
+                .collect::<Vec<_>>(),
+        );
+
+        let first = records[0].time;
+        let last = records[records.len() - 1].time;
+        let total_cycles = days.last().map_or(0.0, |day| day.cycles);
+        // A nearly flat trend gets there past the last date chrono has, that is never
+        let to_date = |day: f64| {
+            let seconds = day * SECONDS_PER_DAY;
+            if !seconds.is_finite() {
+                return None;
+            }
+            // The cast saturates, try_seconds rejects what is out of range
+            Duration::try_seconds(seconds as i64)
+                .and_then(|offset| first.checked_add_signed(offset))
+        };
+
+        let forecasts = targets
+            .iter()
+            .map(|&target| {
+                let (earliest, latest) = per_day.reaches_bounds(target);
+                Forecast {
+                    target,
+                    expected: per_day.reaches(target).and_then(to_date),
+                    earliest: earliest.and_then(to_date),
+                    latest: latest.and_then(to_date),
+                }
+            })
+            .collect();
+        let cycle_forecasts = per_cycle
+            .map(|per_cycle| {
+                targets
+                    .iter()
+                    .map(|&target| {
+                        let (fewest, most) = per_cycle.reaches_bounds(target);
+                        let remaining = |cycles: f64| (cycles - total_cycles).max(0.0);
+                        CycleForecast {
+                            target,
+                            expected: per_cycle.reaches(target).map(remaining),
+                            fewest: fewest.map(remaining),
+                            most: most.map(remaining),
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(HealthReport {
+            samples: records.len(),
+            first,
+            last,
+            current_health: days[days.len() - 1].health,
+            per_day,
+            per_cycle,
+            total_cycles,
+            forecasts,
+            cycle_forecasts,
+        })
+    }
+}
+
+/// The records of each battery, in the order they were first logged. Rows logged before
+/// the batteries were told apart go to the first battery with their design capacity.
+pub fn split_by_battery(
+    records: &[HistoryRecord],
+) -> Vec<(Option<BatteryIdentity>, Vec<HistoryRecord>)> {
+    let mut records = records.to_vec();
+    records.sort_by_key(|record| record.time);
+
+    let mut batteries: Vec<(Option<BatteryIdentity>, Vec<HistoryRecord>)> = Vec::new();
+    for record in &records {
+        if let Some(battery) = &record.battery {
+            if !batteries
+                .iter()
+                .any(|(known, _)| known.as_ref() == Some(battery))
+            {
+                batteries.push((Some(battery.clone()), Vec::new()));
+            }
+        }
+    }
+    for record in records {
+        let battery = record.battery.clone().or_else(|| {
+            batteries
+                .iter()
+                .filter_map(|(battery, _)| battery.as_ref())
+                .find(|battery| battery.charge_full_design == record.charge_full_design)
+                .cloned()
+        });
+        match batteries.iter_mut().find(|(known, _)| *known == battery) {
+            Some((_, battery_records)) => battery_records.push(record),
+            None => batteries.insert(0, (battery, vec![record])),
+        }
+    }
+    batteries
+}
+
+fn format_date(date: Option<DateTime<FixedOffset>>) -> String {
+    date.map_or("never".to_owned(), |date| {
+        date.format("%Y-%m-%d").to_string()
+    })
+}
+
+fn format_cycles(cycles: Option<f64>) -> String {
+    cycles.map_or("never".to_owned(), |cycles| format!("{cycles:.0}"))
+}
+
+impl fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Battery health from {} to {}, {} samples",
+            self.first.format("%Y-%m-%d"),
+            self.last.format("%Y-%m-%d"),
+            self.samples
+        )?;
+        writeln!(f, "Current health: {:.2}%", self.current_health)?;
+        writeln!(
+            f,
+            "Trend: {:+.2}% per year (±{:.2}, about 95%) over {} days",
+            self.per_day.slope * 365.0,
+            CONFIDENCE_Z * self.per_day.slope_stderr * 365.0,
+            self.per_day.points
+        )?;
+        match &self.per_cycle {
+            Some(per_cycle) => writeln!(
+                f,
+                "Per cycle: {:+.4}% per cycle (±{:.4}) over {:.1} cycles",
+                per_cycle.slope,
+                CONFIDENCE_Z * per_cycle.slope_stderr,
+                self.total_cycles
+            )?,
+            None => writeln!(f, "Per cycle: not enough days with the battery percentage")?,
+        }
+
+        for forecast in &self.forecasts {
+            if self.current_health <= forecast.target {
+                writeln!(f, "{}%: already reached", forecast.target)?;
+            } else if forecast.expected.is_none() {
+                writeln!(
+                    f,
+                    "{}%: the health isn't going down, no forecast",
+                    forecast.target
+                )?;
+            } else {
+                writeln!(
+                    f,
+                    "{}%: expected {} (between {} and {})",
+                    forecast.target,
+                    format_date(forecast.expected),
+                    format_date(forecast.earliest),
+                    format_date(forecast.latest)
+                )?;
+            }
+        }
+        for forecast in &self.cycle_forecasts {
+            if self.current_health > forecast.target && forecast.expected.is_some() {
+                writeln!(
+                    f,
+                    "{}%: after {} more cycles (between {} and {})",
+                    forecast.target,
+                    format_cycles(forecast.expected),
+                    format_cycles(forecast.fewest),
+                    format_cycles(forecast.most)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,9 +1,19 @@
+use std::fmt;
 use std::io::{stdout, Write};
-use std::{process::Command, thread::sleep, time::Duration};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use std::{process::Command, time::Duration};
 
-use super::CHARGE_UPPER_LIMIT;
+use chrono::{DateTime, Local};
 
-use super::battery_health::{get_battery_percentage, get_battery_state, BatteryState};
+use super::{controller_command, CHARGE_UPPER_LIMIT};
+
+use super::actuator::Actuator;
+use super::battery_health::{BatterySnapshot, BatteryState};
+use super::sampler::recv_latest;
+use super::shutdown::Shutdown;
+use super::utils::MyError;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum ArduSketch {
@@ -12,6 +22,28 @@ pub enum ArduSketch {
     Connect,
 }
 
+impl fmt::Display for ArduSketch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArduSketch::DoNothing => write!(f, "do_nothing"),
+            ArduSketch::Disconnect => write!(f, "disconnect_charger"),
+            ArduSketch::Connect => write!(f, "connect_charger"),
+        }
+    }
+}
+
+impl ArduSketch {
+    /// The names written by Display, as in the actuator event log
+    pub fn match_string(sketch: &str) -> Result<Self, MyError> {
+        match sketch {
+            "do_nothing" => Ok(Self::DoNothing),
+            "disconnect_charger" => Ok(Self::Disconnect),
+            "connect_charger" => Ok(Self::Connect),
+            _ => Err(MyError::ActuatorError(format!("Unknown sketch '{sketch}'"))),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum CommandState {
     ToExecute,
@@ -19,107 +51,222 @@ pub enum CommandState {
     Stopped,
 }
 
+/// Where to leave the charger when the daemon is stopped
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ShutdownState {
+    Connected,
+    Unchanged,
+}
+
+impl ShutdownState {
+    pub fn match_string(str_state: &str) -> Result<Self, MyError> {
+        match str_state {
+            "connected" => Ok(Self::Connected),
+            "unchanged" => Ok(Self::Unchanged),
+            _ => Err(MyError::ConfigError(format!(
+                "'{str_state}' is not a valid shutdown_state, use connected or unchanged"
+            ))),
+        }
+    }
+}
+
+/// Who decides the charger, the charge limits or a command received over MQTT
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum ChargerMode {
+    #[default]
+    Auto,
+    /// Keep the charger connected whatever the percentage
+    Connect,
+    /// Keep the charger disconnected whatever the percentage
+    Disconnect,
+}
+
+impl ChargerMode {
+    pub fn match_string(mode: &str) -> Result<Self, MyError> {
+        match mode {
+            "auto" => Ok(Self::Auto),
+            "connect" => Ok(Self::Connect),
+            "disconnect" => Ok(Self::Disconnect),
+            _ => Err(MyError::ActuatorError(format!(
+                "'{mode}' is not a valid charger mode, use auto, connect or disconnect"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ChargerMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::Connect => write!(f, "connect"),
+            Self::Disconnect => write!(f, "disconnect"),
+        }
+    }
+}
+
+/// The charger mode, set by the MQTT bridge and read by the controller
+#[derive(Debug, Clone, Default)]
+pub struct SharedMode(Arc<Mutex<ChargerMode>>);
+
+impl SharedMode {
+    pub fn get(&self) -> ChargerMode {
+        *self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub fn set(&self, mode: ChargerMode) {
+        *self.0.lock().unwrap_or_else(|err| err.into_inner()) = mode;
+    }
+}
+
+/// A switched charger shows in the battery status well within this
+pub const REACTION_TIMEOUT: Duration = Duration::from_secs(120);
+
 const DO_NOTHING_PATH: &str = "/home/giulio/arduino_embedded/do_nothing";
 const CONNECT_PATH: &str = "/home/giulio/arduino_embedded/connect_charger";
 const DISCONNECT_PATH: &str = "/home/giulio/arduino_embedded/disconnect_charger";
 
+/// Flash the given sketch on the arduino with avrdude
+fn flash_sketch(sketch_dir: &str, sketch_name: &str) -> Result<(), MyError> {
+    let status = Command::new("avrdude")
+        .args([
+            "-c",
+            "arduino",
+            "-p",
+            "m328p",
+            "-P",
+            "/dev/ttyACM0",
+            "-U",
+            &format!("flash:w:target/avr-atmega328p/release/{sketch_name}.elf"),
+        ])
+        .current_dir(sketch_dir)
+        .status()
+        .map_err(|err| MyError::ActuatorError(format!("Failed to run avrdude: {err}")))?;
+
+    if !status.success() {
+        return Err(MyError::ActuatorError(format!(
+            "avrdude failed flashing {sketch_name}: {status}"
+        )));
+    }
+    Ok(())
+}
+
+/// Err once the battery has ignored `command` for longer than REACTION_TIMEOUT, the
+/// snapshot times are used so that a replayed history times out too
+fn check_deadline(
+    command: ArduSketch,
+    switched_at: DateTime<Local>,
+    snapshot: &BatterySnapshot,
+) -> Result<(), MyError> {
+    let waited = (snapshot.time - switched_at).to_std().unwrap_or_default();
+    if waited > REACTION_TIMEOUT {
+        return Err(MyError::ActuatorError(format!(
+            "The battery is still {} {}s after {command}",
+            snapshot.state,
+            waited.as_secs()
+        )));
+    }
+    Ok(())
+}
+
+/// The snapshot where the battery reacted to a command and how long it took
+#[derive(Debug, Clone)]
+pub struct Reaction {
+    pub snapshot: BatterySnapshot,
+    pub latency: Duration,
+}
+
+/// Only flash the sketch, without waiting for the battery to react
+pub fn flash(sketch: ArduSketch) -> Result<(), MyError> {
+    match sketch {
+        ArduSketch::DoNothing => flash_sketch(DO_NOTHING_PATH, "do_nothing"),
+        ArduSketch::Disconnect => flash_sketch(DISCONNECT_PATH, "disconnect_charger"),
+        ArduSketch::Connect => flash_sketch(CONNECT_PATH, "connect_charger"),
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct ArduCommand {
     pub command_type: ArduSketch,
     pub state: CommandState,
 }
 impl ArduCommand {
-    pub fn execute(&self) {
+    /// Switch the charger with `actuator` and wait for the battery to react in the
+    /// `snapshots`, returns early, without a reaction, when the sampler stops.
+    /// DoNothing lasts until `mode` changes or the charge asks for another command.
+    pub fn execute(
+        &self,
+        actuator: &Actuator,
+        mode: &SharedMode,
+        snapshots: &Receiver<BatterySnapshot>,
+        shutdown: &Shutdown,
+    ) -> Result<Option<Reaction>, MyError> {
+        let started = Instant::now();
+        let switched_at = Local::now();
+        actuator.switch(self.command_type)?;
+        let mut reaction = None;
         match self.command_type {
             ArduSketch::DoNothing => {
-                Command::new("avrdude")
-                    .args([
-                        "-c",
-                        "arduino",
-                        "-p",
-                        "m328p",
-                        "-P",
-                        "/dev/ttyACM0",
-                        "-U",
-                        "flash:w:target/avr-atmega328p/release/do_nothing.elf",
-                    ])
-                    .current_dir(DO_NOTHING_PATH)
-                    .status()
-                    .unwrap();
                 println!("DoNothing is being executed!\n");
-                
+                // A new mode is shown in the daemon status even if it keeps DoNothing
+                let entered_mode = mode.get();
                 'do_nothing: loop {
-                    let batt_perc = get_battery_percentage().expect("Failed getting batt percentage");
-                    if CHARGE_UPPER_LIMIT - 0.1 < batt_perc || batt_perc < CHARGE_UPPER_LIMIT + 0.1{
+                    let Some(snapshot) = recv_latest(snapshots) else {
+                        break 'do_nothing;
+                    };
+                    let batt_perc = snapshot.percentage();
+                    let mode = mode.get();
+                    // Right at the limit the charge goes back and forth, the charger stays put
+                    let at_limit = mode == ChargerMode::Auto
+                        && (batt_perc - CHARGE_UPPER_LIMIT).abs() < 0.1;
+                    let holding = at_limit || controller_command(&snapshot, mode) == ArduSketch::DoNothing;
+                    if mode == entered_mode && holding {
                         print!("batt_charg:{}  \r", batt_perc);
-                        stdout().flush().unwrap();
-                        sleep(Duration::from_secs(1));
+                        stdout().flush()?;
                         continue;
-                    }else {
+                    } else {
                         break 'do_nothing;
                     }
-                    }
-                },
+                }
+            },
             ArduSketch::Disconnect => {
-                Command::new("avrdude")
-                    .args([
-                        "-c",
-                        "arduino",
-                        "-p",
-                        "m328p",
-                        "-P",
-                        "/dev/ttyACM0",
-                        "-U",
-                        "flash:w:target/avr-atmega328p/release/disconnect_charger.elf",
-                    ])
-                    .current_dir(DISCONNECT_PATH)
-                    .status()
-                    .unwrap();
                 println!("Disconnect is being executed!");
                 'disconnecting: loop {
                     //let batt_perc = get_battery_percentage().expect("Failed getting batt percentage");
-                    let batt_state = BatteryState::match_string(&get_battery_state().unwrap());
-                    match batt_state {
+                    let Some(snapshot) = recv_latest(snapshots) else {
+                        break 'disconnecting;
+                    };
+                    match snapshot.state {
                         BatteryState::Discharging => {
-                            sleep(Duration::from_secs(13));
+                            reaction = Some(Reaction { snapshot, latency: started.elapsed() });
+                            shutdown.sleep(Duration::from_secs(13));
                             break 'disconnecting;
                         }
-                        BatteryState::Charging | BatteryState::Full => {
-                            sleep(Duration::from_secs(1))
+                        BatteryState::Charging | BatteryState::Full | BatteryState::NotCharging => {
+                            check_deadline(self.command_type, switched_at, &snapshot)?
                         }
                     }
                 }
             }
             ArduSketch::Connect => {
-                Command::new("avrdude")
-                    .args([
-                        "-c",
-                        "arduino",
-                        "-p",
-                        "m328p",
-                        "-P",
-                        "/dev/ttyACM0",
-                        "-U",
-                        "flash:w:target/avr-atmega328p/release/connect_charger.elf",
-                    ])
-                    .current_dir(CONNECT_PATH)
-                    .status()
-                    .unwrap();
                 println!("Connect is being executed!");
                 'connecting: loop {
                     //let batt_perc = get_battery_percentage().expect("Failed getting batt percentage");
-                    let batt_state = BatteryState::match_string(&get_battery_state().unwrap());
-                    match batt_state {
+                    let Some(snapshot) = recv_latest(snapshots) else {
+                        break 'connecting;
+                    };
+                    match snapshot.state {
                         BatteryState::Charging => {
-                            sleep(Duration::from_secs(19));
+                            reaction = Some(Reaction { snapshot, latency: started.elapsed() });
+                            shutdown.sleep(Duration::from_secs(19));
                             break 'connecting;
                         }
-                        BatteryState::Discharging | BatteryState::Full => {
-                            sleep(Duration::from_secs(1))
+                        BatteryState::Discharging | BatteryState::Full | BatteryState::NotCharging => {
+                            check_deadline(self.command_type, switched_at, &snapshot)?
                         }
                     }
                 }
             }
         }
+        Ok(reaction)
     }
 }
@@ -1,7 +1,8 @@
 use super::utils::*;
-use std::{io, path::Path};
+use chrono::{DateTime, Local};
+use std::{fmt, path::Path};
 
-const BATTERY_FILES_PATH: &str = "/sys/class/power_supply/BAT1";
+pub const BATTERY_FILES_PATH: &str = "/sys/class/power_supply/BAT1";
 const BATTERY_FILES: [&str; 21] = [
     "charge_full",        // 0
     "charge_full_design", // 1
@@ -26,71 +27,198 @@ const BATTERY_FILES: [&str; 21] = [
     "status",
 ];
 
-pub struct BatteryStatistics {
-    pub charge_full: String,
-    pub charge_full_design: String,
+/// One consistent reading of the battery, every consumer works on the same values
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatterySnapshot {
+    pub time: DateTime<Local>,
+    pub charge_now: u32,
+    pub charge_full: u32,
+    pub charge_full_design: u32,
+    pub state: BatteryState,
+    /// In µA, None when the battery doesn't report it
+    pub current_now: Option<u32>,
+    /// In µV
+    pub voltage_now: Option<u32>,
+    /// In µW, only some batteries have it
+    pub power_now: Option<u32>,
+    /// As reported by the firmware, often stuck at 0
+    pub cycle_count: Option<u32>,
+    /// None for snapshots that weren't read from sysfs
+    pub identity: Option<BatteryIdentity>,
 }
 
-impl BatteryStatistics {
-    pub fn new() -> Self {
-        let charge_full_path = Path::new(BATTERY_FILES_PATH).join(BATTERY_FILES[0]);
-        let charge_full = read_file_as_string(&charge_full_path).unwrap();
-
-        let charge_full_design_path = Path::new(BATTERY_FILES_PATH).join(BATTERY_FILES[1]);
-        let charge_full_design = read_file_as_string(&charge_full_design_path).unwrap();
+impl BatterySnapshot {
+    /// Read the battery in BATTERY_FILES_PATH
+    pub fn read() -> Result<Self, MyError> {
+        Self::read_from(Path::new(BATTERY_FILES_PATH))
+    }
 
-        BatteryStatistics {
-            charge_full,
+    /// Read a power_supply directory, a fake one can be used in tests
+    pub fn read_from(battery_path: &Path) -> Result<Self, MyError> {
+        if !battery_path.is_dir() {
+            return Err(MyError::BatteryError(format!(
+                "Battery not found at {}",
+                battery_path.display()
+            )));
+        }
+        let read_value = |file: &str| -> Result<u32, MyError> {
+            parse_battery_value(&read_file_as_string(&battery_path.join(file))?)
+        };
+
+        let charge_full_design = read_value(BATTERY_FILES[1])?;
+        Ok(BatterySnapshot {
+            time: Local::now(),
+            charge_now: read_value("charge_now")?,
+            charge_full: read_value(BATTERY_FILES[0])?,
             charge_full_design,
+            state: BatteryState::match_string(&read_file_as_string(&battery_path.join("status"))?)?,
+            current_now: read_optional_value(&battery_path.join(BATTERY_FILES[17])),
+            voltage_now: read_optional_value(&battery_path.join(BATTERY_FILES[16])),
+            power_now: read_optional_value(&battery_path.join("power_now")),
+            cycle_count: read_optional_value(&battery_path.join(BATTERY_FILES[19])),
+            identity: Some(BatteryIdentity::read_from(battery_path, charge_full_design)),
+        })
+    }
+
+    pub fn percentage(&self) -> f32 {
+        self.charge_now as f32 / self.charge_full as f32 * 100.0
+    }
+
+    /// Watts drawn or charged, power_now or else voltage_now × current_now
+    pub fn power(&self) -> Option<f64> {
+        match (self.power_now, self.voltage_now, self.current_now) {
+            (Some(power_now), _, _) => Some(f64::from(power_now) / 1e6),
+            (None, Some(voltage_now), Some(current_now)) => {
+                Some(f64::from(voltage_now) * f64::from(current_now) / 1e12)
+            }
+            _ => None,
         }
     }
+
+    /// charge_full/charge_full_design in percent
+    pub fn health(&self) -> f32 {
+        self.charge_full as f32 / self.charge_full_design as f32 * 100.0
+    }
 }
 
-impl Default for BatteryStatistics {
+/// Half of a 3000 charge discharging now, the tests change only what they look at with `..`
+#[cfg(test)]
+impl Default for BatterySnapshot {
     fn default() -> Self {
-        Self::new()
+        BatterySnapshot {
+            time: Local::now(),
+            charge_now: 1500,
+            charge_full: 3000,
+            charge_full_design: 3500,
+            state: BatteryState::Discharging,
+            current_now: None,
+            voltage_now: None,
+            power_now: None,
+            cycle_count: None,
+            identity: None,
+        }
     }
 }
 
-pub fn get_battery_state() -> Result<String, io::Error> {
-    let battery_path = Path::new(BATTERY_FILES_PATH);
-
-    let status = battery_path.join("status");
-
-    read_file_as_string(&status)
+/// Tells one battery pack from another, a swapped pack starts a new history segment
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BatteryIdentity {
+    pub manufacturer: String,
+    pub model_name: String,
+    pub serial_number: String,
+    pub charge_full_design: u32,
 }
 
-pub fn get_battery_percentage() -> io::Result<f32> {
-    let battery_path = Path::new(BATTERY_FILES_PATH);
-
-    let charge_full = battery_path.join("charge_full");
-    let charge_now = battery_path.join("charge_now");
-
-    let charge_now_content = read_file_as_string(&charge_now)?;
-    let charge_full_content = read_file_as_string(&charge_full)?;
+impl BatteryIdentity {
+    /// Attributes the battery doesn't have are left empty
+    pub fn read_from(battery_path: &Path, charge_full_design: u32) -> Self {
+        let read_text = |file: &str| {
+            read_file_as_string(&battery_path.join(file))
+                .unwrap_or_default()
+                .replace(';', " ")
+        };
+        BatteryIdentity {
+            manufacturer: read_text(BATTERY_FILES[9]),
+            model_name: read_text(BATTERY_FILES[11]),
+            serial_number: read_text(BATTERY_FILES[18]),
+            charge_full_design,
+        }
+    }
 
-    let battery_level = (charge_now_content.parse::<u32>().unwrap() as f32
-        / charge_full_content.parse::<u32>().unwrap() as f32)
-        * 100.0;
+    /// manufacturer;model_name;serial_number;charge_full_design, as stored in the history
+    pub fn key(&self) -> String {
+        format!(
+            "{};{};{};{}",
+            self.manufacturer, self.model_name, self.serial_number, self.charge_full_design
+        )
+    }
 
-    //println!("Battery level: {}%", battery_level);
+    pub fn from_key(key: &str) -> Result<Self, MyError> {
+        let invalid = || MyError::BatteryError(format!("'{key}' is not a valid battery identity"));
+        let fields = key.split(';').collect::<Vec<_>>();
+        let [manufacturer, model_name, serial_number, charge_full_design] = fields[..] else {
+            return Err(invalid());
+        };
+        Ok(BatteryIdentity {
+            manufacturer: manufacturer.to_owned(),
+            model_name: model_name.to_owned(),
+            serial_number: serial_number.to_owned(),
+            charge_full_design: charge_full_design.parse().map_err(|_| invalid())?,
+        })
+    }
+}
 
-    Ok(battery_level)
+impl fmt::Display for BatteryIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} s/n {}, {} design",
+            self.manufacturer, self.model_name, self.serial_number, self.charge_full_design
+        )
+    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum BatteryState {
     Discharging,
     Charging,
-    Full
+    Full,
+    /// Charger connected but the firmware is holding the charge
+    NotCharging,
 }
 impl BatteryState {
-    pub fn match_string(str_state: &str) -> Self {
+    pub fn match_string(str_state: &str) -> Result<Self, MyError> {
         match str_state {
-            "Discharging" => Self::Discharging,
-            "Charging" => Self::Charging,
-            "Full" => Self::Full,
-            _ => panic!("Invalid str_state"),
+            "Discharging" => Ok(Self::Discharging),
+            "Charging" => Ok(Self::Charging),
+            "Full" => Ok(Self::Full),
+            "Not charging" => Ok(Self::NotCharging),
+            _ => Err(MyError::BatteryError(format!("Invalid battery state '{str_state}'"))),
+        }
+    }
+}
+
+impl fmt::Display for BatteryState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Same strings as the sysfs status file
+        match self {
+            BatteryState::Discharging => write!(f, "Discharging"),
+            BatteryState::Charging => write!(f, "Charging"),
+            BatteryState::Full => write!(f, "Full"),
+            BatteryState::NotCharging => write!(f, "Not charging"),
         }
     }
 }
+
+fn parse_battery_value(content: &str) -> Result<u32, MyError> {
+    content
+        .parse::<u32>()
+        .map_err(|err| MyError::BatteryError(format!("'{content}' is not a valid battery value: {err}")))
+}
+
+/// Attributes missing on some batteries, or signed on some drivers: the magnitude or None
+fn read_optional_value(path: &Path) -> Option<u32> {
+    let content = read_file_as_string(path).ok()?;
+    let value = content.parse::<i64>().ok()?.unsigned_abs();
+    u32::try_from(value).ok()
+}
@@ -0,0 +1,138 @@
+use std::io;
+use std::process::{Command, ExitStatus};
+use std::time::{Duration, Instant};
+
+use super::battery_health::BatteryState;
+
+/// What to do when the battery stays under the critical level for the whole grace period
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum CriticalAction {
+    Suspend,
+    Hibernate,
+    /// Shell command run through `sh -c`
+    Custom(String),
+    Disabled,
+}
+
+impl CriticalAction {
+    pub fn match_string(str_action: &str) -> Self {
+        match str_action {
+            "suspend" => Self::Suspend,
+            "hibernate" => Self::Hibernate,
+            "none" | "" => Self::Disabled,
+            custom => Self::Custom(custom.to_owned()),
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            CriticalAction::Suspend => "sospensione",
+            CriticalAction::Hibernate => "ibernazione",
+            CriticalAction::Custom(cmd) => cmd,
+            CriticalAction::Disabled => "nessuna azione",
+        }
+    }
+}
+
+/// Runs the critical action, real commands in the daemon and a fake one in tests
+pub trait CommandRunner {
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<ExitStatus>;
+}
+
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<ExitStatus> {
+        Command::new(program).args(args).status()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CriticalStatus {
+    /// Battery is above the critical level or the charger is connected
+    Normal,
+    /// Battery just went under the critical level, the grace period starts now
+    Entered,
+    /// Still waiting for the user to connect the charger
+    Waiting,
+    /// Grace period expired, the action has been run
+    Triggered,
+    /// The action has been run but failed
+    Failed(String),
+}
+
+#[derive(Debug)]
+pub struct CriticalPolicy {
+    level: f32,
+    grace: Duration,
+    action: CriticalAction,
+    below_since: Option<Instant>,
+}
+
+impl CriticalPolicy {
+    pub fn new(level: f32, grace: Duration, action: CriticalAction) -> Self {
+        CriticalPolicy {
+            level,
+            grace,
+            action,
+            below_since: None,
+        }
+    }
+
+    pub fn level(&self) -> f32 {
+        self.level
+    }
+
+    /// Message shown to the user when the battery enters the critical zone
+    pub fn warning_message(&self) -> String {
+        format!(
+            "Connetti il caricatore!! {} tra {} secondi",
+            self.action.name(),
+            self.grace.as_secs()
+        )
+    }
+
+    /// Check the current reading, if the battery has been critical and discharging
+    /// for longer than the grace period the configured action is run with `runner`.
+    pub fn check<R: CommandRunner>(
+        &mut self,
+        batt_perc: f32,
+        batt_state: &BatteryState,
+        now: Instant,
+        runner: &R,
+    ) -> CriticalStatus {
+        if self.action == CriticalAction::Disabled
+            || batt_perc >= self.level
+            || *batt_state != BatteryState::Discharging
+        {
+            self.below_since = None;
+            return CriticalStatus::Normal;
+        }
+
+        let Some(below_since) = self.below_since else {
+            self.below_since = Some(now);
+            return CriticalStatus::Entered;
+        };
+
+        if now.duration_since(below_since) < self.grace {
+            return CriticalStatus::Waiting;
+        }
+
+        // Re-arm so that after a resume the user gets a new grace period
+        self.below_since = None;
+        let result = match &self.action {
+            CriticalAction::Suspend => runner.run("systemctl", &["suspend"]),
+            CriticalAction::Hibernate => runner.run("systemctl", &["hibernate"]),
+            CriticalAction::Custom(cmd) => runner.run("sh", &["-c", cmd]),
+            CriticalAction::Disabled => unreachable!(),
+        };
+
+        match result {
+            Ok(status) if status.success() => CriticalStatus::Triggered,
+            Ok(status) => {
+                CriticalStatus::Failed(format!("{} exited with {status}", self.action.name()))
+            }
+            Err(err) => CriticalStatus::Failed(format!("{}: {err}", self.action.name())),
+        }
+    }
+}
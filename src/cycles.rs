@@ -0,0 +1,233 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use chrono::{DateTime, FixedOffset, SecondsFormat};
+
+use super::battery_health::{BatteryIdentity, BatterySnapshot};
+use super::utils::{read_file_as_string, MyError};
+
+/// The counter is saved every hundredth of a cycle, a crash loses less than that
+const SAVE_EVERY: f64 = 0.01;
+
+/// Cycles counted from the charge leaving the battery: a cycle is one design
+/// capacity discharged, in as many steps as it takes. Kept in a key=value file
+/// next to the history so that restarts don't lose it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CycleCounter {
+    pub started: DateTime<FixedOffset>,
+    /// µAh discharged since `started`
+    pub discharged: f64,
+    /// µAh of them discharged with the percentage between the charge limits
+    pub discharged_in_window: f64,
+    pub charge_full_design: u32,
+    /// Health when the counting started
+    pub health_start: f32,
+    /// Kernel cycle_count when the counting started, None if it doesn't report one
+    pub kernel_start: Option<u32>,
+    /// The battery being counted, None for counters started before the batteries were told apart
+    pub battery: Option<BatteryIdentity>,
+    last: Option<BatterySnapshot>,
+    saved_cycles: f64,
+}
+
+impl CycleCounter {
+    /// Start counting from `snapshot`
+    pub fn new(snapshot: &BatterySnapshot) -> Self {
+        CycleCounter {
+            started: snapshot.time.fixed_offset(),
+            discharged: 0.0,
+            discharged_in_window: 0.0,
+            charge_full_design: snapshot.charge_full_design,
+            health_start: snapshot.health(),
+            kernel_start: reported_cycles(snapshot),
+            battery: snapshot.identity.clone(),
+            last: None,
+            saved_cycles: 0.0,
+        }
+    }
+
+    /// Read a counter saved by `save`, None if there is no file yet
+    pub fn load(path: &Path) -> Result<Option<Self>, MyError> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = read_file_as_string(path)?;
+        let started = field(path, &content, "started")?;
+
+        let mut counter = CycleCounter {
+            started: DateTime::parse_from_rfc3339(started).map_err(|err| {
+                MyError::HealthStatsError(format!("Invalid started in {}: {err}", path.display()))
+            })?,
+            discharged: parse_field(path, &content, "discharged")?,
+            discharged_in_window: parse_field(path, &content, "discharged_in_window")?,
+            charge_full_design: parse_field(path, &content, "charge_full_design")?,
+            health_start: parse_field(path, &content, "health_start")?,
+            kernel_start: match field(path, &content, "kernel_start")? {
+                "N/A" => None,
+                _ => Some(parse_field(path, &content, "kernel_start")?),
+            },
+            battery: match field(path, &content, "battery") {
+                Ok("N/A") | Err(_) => None,
+                Ok(key) => Some(BatteryIdentity::from_key(key)?),
+            },
+            last: None,
+            saved_cycles: 0.0,
+        };
+        counter.saved_cycles = counter.cycles();
+        Ok(Some(counter))
+    }
+
+    /// Write the counter to `path`, replacing the previous one in a single rename
+    pub fn save(&mut self, path: &Path) -> Result<(), MyError> {
+        let temporary_path = PathBuf::from(format!("{}.tmp", path.display()));
+        let mut file = File::create(&temporary_path)?;
+        write!(
+            file,
+            "started={}\ndischarged={:.0}\ndischarged_in_window={:.0}\ncharge_full_design={}\n\
+             health_start={}\nkernel_start={}\nbattery={}\n",
+            self.started.to_rfc3339_opts(SecondsFormat::Secs, false),
+            self.discharged,
+            self.discharged_in_window,
+            self.charge_full_design,
+            self.health_start,
+            self.kernel_start
+                .map_or("N/A".to_owned(), |kernel_start| kernel_start.to_string()),
+            self.battery
+                .as_ref()
+                .map_or("N/A".to_owned(), BatteryIdentity::key),
+        )?;
+        file.sync_all()?;
+        fs::rename(&temporary_path, path)?;
+        self.saved_cycles = self.cycles();
+        Ok(())
+    }
+
+    /// Count the charge lost since the previous snapshot, returns whether the counter
+    /// moved enough to be saved. `window` is the (lower, upper) percentage range.
+    pub fn update(&mut self, snapshot: &BatterySnapshot, window: (f32, f32)) -> bool {
+        if let Some(last) = &self.last {
+            if snapshot.charge_now < last.charge_now {
+                let discharged = f64::from(last.charge_now - snapshot.charge_now);
+                self.discharged += discharged;
+                let in_window = |percentage: f32| window.0 <= percentage && percentage <= window.1;
+                if in_window(last.percentage()) && in_window(snapshot.percentage()) {
+                    self.discharged_in_window += discharged;
+                }
+            }
+        }
+        self.last = Some(snapshot.clone());
+        self.cycles() - self.saved_cycles >= SAVE_EVERY
+    }
+
+    /// Whether `snapshot` is of another battery than the counted one. A counter without
+    /// the battery is taken over by one with the same design capacity.
+    pub fn is_other_battery(&mut self, snapshot: &BatterySnapshot) -> bool {
+        let Some(identity) = &snapshot.identity else {
+            return false;
+        };
+        match &self.battery {
+            Some(battery) => battery != identity,
+            None if identity.charge_full_design == self.charge_full_design => {
+                self.battery = Some(identity.clone());
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Equivalent full cycles of the design capacity
+    pub fn cycles(&self) -> f64 {
+        self.discharged / f64::from(self.charge_full_design)
+    }
+
+    /// Compare the counter with the battery now
+    pub fn report(&self, snapshot: &BatterySnapshot) -> CycleReport {
+        let cycles = self.cycles();
+        let wear = f64::from(self.health_start - snapshot.health());
+        CycleReport {
+            started: self.started,
+            cycles,
+            window_share: (self.discharged > 0.0)
+                .then(|| self.discharged_in_window / self.discharged * 100.0),
+            kernel_now: reported_cycles(snapshot),
+            kernel_since_start: reported_cycles(snapshot)
+                .zip(self.kernel_start)
+                .map(|(now, start)| now.saturating_sub(start)),
+            health_start: self.health_start,
+            health_now: snapshot.health(),
+            wear_per_cycle: (cycles >= 1.0).then(|| wear / cycles),
+        }
+    }
+}
+
+fn field<'a>(path: &Path, content: &'a str, key: &str) -> Result<&'a str, MyError> {
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+        .ok_or_else(|| MyError::HealthStatsError(format!("{} has no {key}", path.display())))
+}
+
+fn parse_field<T>(path: &Path, content: &str, key: &str) -> Result<T, MyError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    field(path, content, key)?.parse::<T>().map_err(|err| {
+        MyError::HealthStatsError(format!("Invalid {key} in {}: {err}", path.display()))
+    })
+}
+
+/// The kernel cycle_count, None when missing or 0 as many firmwares never update it
+fn reported_cycles(snapshot: &BatterySnapshot) -> Option<u32> {
+    snapshot.cycle_count.filter(|cycle_count| *cycle_count > 0)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CycleReport {
+    pub started: DateTime<FixedOffset>,
+    pub cycles: f64,
+    /// Percent of the discharge between the charge limits
+    pub window_share: Option<f64>,
+    pub kernel_now: Option<u32>,
+    pub kernel_since_start: Option<u32>,
+    pub health_start: f32,
+    pub health_now: f32,
+    /// Health percentage points lost per counted cycle, None before the first cycle
+    pub wear_per_cycle: Option<f64>,
+}
+
+impl fmt::Display for CycleReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Counted cycles: {:.2} since {}",
+            self.cycles,
+            self.started.format("%Y-%m-%d")
+        )?;
+        match (self.kernel_now, self.kernel_since_start) {
+            (Some(kernel_now), Some(since_start)) => writeln!(
+                f,
+                "Kernel cycle_count: {kernel_now}, {since_start} since {}",
+                self.started.format("%Y-%m-%d")
+            )?,
+            (Some(kernel_now), None) => writeln!(f, "Kernel cycle_count: {kernel_now}")?,
+            (None, _) => writeln!(f, "Kernel cycle_count: not reported")?,
+        }
+        if let Some(window_share) = self.window_share {
+            writeln!(f, "Discharged inside the charge limits: {window_share:.1}%")?;
+        }
+        writeln!(
+            f,
+            "Health: {:.2}% then, {:.2}% now",
+            self.health_start, self.health_now
+        )?;
+        match self.wear_per_cycle {
+            Some(wear) => writeln!(f, "Wear: {wear:.4}% per cycle")?,
+            None => writeln!(f, "Wear: not a full cycle yet")?,
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+
+use super::battery_health::{BatterySnapshot, BatteryState};
+
+/// Time constant of the smoothing, the rate follows a new load in a few minutes
+const SMOOTHING: Duration = Duration::from_secs(5 * 60);
+/// Without current_now the rate comes from charge_now, which moves in steps,
+/// so there is no estimate until it has been followed for a while
+const WARM_UP: Duration = Duration::from_secs(2 * 60);
+
+/// Where the battery is going and how long it takes to get there
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Estimate {
+    /// Discharging until empty
+    Empty(Duration),
+    /// Charging until the upper limit, in percent
+    Limit(f32, Duration),
+}
+
+/// Smoothed charge or discharge rate of the battery, from current_now when the
+/// battery has it and from the changes of charge_now otherwise
+pub struct RuntimeEstimator {
+    upper_limit: f32,
+    /// µA, the unit of current_now, that is µAh of charge_now per hour
+    rate: Option<f64>,
+    /// When the battery started going in the current direction
+    since: Option<DateTime<Local>>,
+    last: Option<BatterySnapshot>,
+}
+
+impl RuntimeEstimator {
+    pub fn new(upper_limit: f32) -> Self {
+        RuntimeEstimator {
+            upper_limit,
+            rate: None,
+            since: None,
+            last: None,
+        }
+    }
+
+    /// Add a snapshot and estimate from it, None while the battery is full or
+    /// held, or the rate isn't known yet
+    pub fn update(&mut self, snapshot: &BatterySnapshot) -> Option<Estimate> {
+        let last = self
+            .last
+            .take()
+            .filter(|last| last.state == snapshot.state && last.time < snapshot.time);
+        if last.is_none() {
+            // A new direction, the old rate says nothing about it
+            self.rate = None;
+            self.since = Some(snapshot.time);
+        }
+
+        let elapsed = last
+            .as_ref()
+            .and_then(|last| (snapshot.time - last.time).to_std().ok());
+        let sample = match (snapshot.current_now, &last, elapsed) {
+            (Some(current_now), _, _) => Some(f64::from(current_now)),
+            (None, Some(last), Some(elapsed)) => {
+                let charged = f64::from(snapshot.charge_now) - f64::from(last.charge_now);
+                let charged = match snapshot.state {
+                    BatteryState::Discharging => -charged,
+                    _ => charged,
+                };
+                Some(charged / elapsed.as_secs_f64() * 3600.0)
+            }
+            (None, _, _) => None,
+        };
+        if let Some(sample) = sample {
+            self.rate = Some(match (self.rate, elapsed) {
+                (Some(rate), Some(elapsed)) => {
+                    let weight = 1.0 - (-elapsed.as_secs_f64() / SMOOTHING.as_secs_f64()).exp();
+                    rate + weight * (sample - rate)
+                }
+                _ => sample,
+            });
+        }
+
+        self.last = Some(snapshot.clone());
+        self.estimate(snapshot)
+    }
+
+    fn estimate(&self, snapshot: &BatterySnapshot) -> Option<Estimate> {
+        let rate = self.rate.filter(|rate| *rate > 0.0)?;
+        if snapshot.current_now.is_none() {
+            let followed = self
+                .since
+                .and_then(|since| (snapshot.time - since).to_std().ok())
+                .unwrap_or_default();
+            if followed < WARM_UP {
+                return None;
+            }
+        }
+        let time_for = |charge: f64| Duration::from_secs_f64(charge / rate * 3600.0);
+
+        match snapshot.state {
+            BatteryState::Discharging => {
+                Some(Estimate::Empty(time_for(f64::from(snapshot.charge_now))))
+            }
+            BatteryState::Charging => {
+                let target = f64::from(self.upper_limit) / 100.0 * f64::from(snapshot.charge_full);
+                let missing = target - f64::from(snapshot.charge_now);
+                (missing > 0.0).then(|| Estimate::Limit(self.upper_limit, time_for(missing)))
+            }
+            BatteryState::Full | BatteryState::NotCharging => None,
+        }
+    }
+}
+
+/// ~25 min or ~2 h 5 min
+pub fn format_remaining(remaining: Duration) -> String {
+    let minutes = (remaining.as_secs() + 30) / 60;
+    if minutes < 60 {
+        format!("~{minutes} min")
+    } else {
+        format!("~{} h {} min", minutes / 60, minutes % 60)
+    }
+}
@@ -0,0 +1,447 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{
+    DateTime, Duration, FixedOffset, Local, NaiveDate, NaiveDateTime, NaiveTime, SecondsFormat,
+    TimeZone,
+};
+use flate2::read::GzDecoder;
+
+use super::battery_health::{BatteryIdentity, BatterySnapshot, BatteryState};
+use super::utils::MyError;
+
+/// Bump this every time the columns change and teach `HistoryRecord::parse_row` the old layout
+pub const SCHEMA_VERSION: u32 = 3;
+const SCHEMA_LINE_PREFIX: &str = "# energy_monitor schema=";
+/// Starts a segment, the rows after it are of this battery
+const BATTERY_LINE_PREFIX: &str = "# energy_monitor battery=";
+pub const HEADER: &str = "Timestamp,Charge_Full,Charge_Full_Design,Battery_Health,Battery_Percentage,Battery_Status";
+/// Same header with the optional Unix epoch column
+pub const HEADER_WITH_EPOCH: &str = "Timestamp,Epoch,Charge_Full,Charge_Full_Design,Battery_Health,Battery_Percentage,Battery_Status";
+const MISSING: &str = "N/A";
+
+/// The layouts the history files have had over time
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Schema {
+    /// data/data_with_hour.csv: Date,Hour,Charge_Full,Charge_Full_Design,Battery_Health
+    HealthOnly,
+    /// data/battery_stats.csv: the same plus Battery_Percentage,Battery_Status, often N/A
+    HourStats,
+    /// data.csv before the schema line: Date,Hour_str,Hour_f32,...,Battery_Status
+    HourFloat,
+    /// Files starting with the schema line, 2 has the HourFloat columns,
+    /// 3 a single RFC 3339 Timestamp column and optionally Epoch
+    Versioned(u32),
+}
+
+impl Schema {
+    fn from_header(header: &str) -> Result<Self, MyError> {
+        match header.split(',').count() {
+            5 => Ok(Schema::HealthOnly),
+            7 => Ok(Schema::HourStats),
+            8 => Ok(Schema::HourFloat),
+            _ => Err(MyError::HealthStatsError(format!(
+                "Unknown history layout '{header}'"
+            ))),
+        }
+    }
+}
+
+/// When health_stats writes a row: at least every `max_interval`, and sooner when the
+/// battery changes so that charge bursts are recorded and idle periods stay sparse
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct WritePolicy {
+    pub max_interval: Duration,
+    /// Percentage points since the last row that trigger a new one
+    pub delta: Option<f32>,
+    pub on_status_change: bool,
+}
+
+impl WritePolicy {
+    /// A row only every `minutes`, like write_every always did
+    pub fn every(minutes: u64) -> Self {
+        WritePolicy {
+            max_interval: Duration::minutes(minutes as i64),
+            delta: None,
+            on_status_change: false,
+        }
+    }
+
+    pub fn should_write(&self, last_written: Option<&BatterySnapshot>, next: &BatterySnapshot) -> bool {
+        let Some(last_written) = last_written else {
+            return true;
+        };
+        next.time - last_written.time >= self.max_interval
+            || (self.on_status_change && next.state != last_written.state)
+            || self
+                .delta
+                .is_some_and(|delta| (next.percentage() - last_written.percentage()).abs() >= delta)
+    }
+}
+
+/// One row of the history, whatever the layout it was read from
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryRecord {
+    /// Time with the UTC offset it was recorded with
+    pub time: DateTime<FixedOffset>,
+    pub charge_full: u32,
+    pub charge_full_design: u32,
+    pub battery_health: f32,
+    /// Missing in the oldest files
+    pub battery_percentage: Option<f32>,
+    pub battery_status: Option<BatteryState>,
+    /// From the segment the row is in, None for rows logged before the batteries were told apart
+    pub battery: Option<BatteryIdentity>,
+}
+
+impl HistoryRecord {
+    pub fn from_snapshot(snapshot: &BatterySnapshot) -> Self {
+        HistoryRecord {
+            time: snapshot.time.fixed_offset(),
+            charge_full: snapshot.charge_full,
+            charge_full_design: snapshot.charge_full_design,
+            battery_health: snapshot.health(),
+            battery_percentage: Some(snapshot.percentage()),
+            battery_status: Some(snapshot.state),
+            battery: snapshot.identity.clone(),
+        }
+    }
+
+    /// Parse a data row of any schema. Rows starting with a RFC 3339 timestamp are in the
+    /// current layout, the older ones are recognized by the number of fields because some
+    /// legacy files have rows of different layouts appended together.
+    pub fn parse_row(line: &str) -> Result<Self, MyError> {
+        let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+        let (time, values) = match DateTime::parse_from_rfc3339(fields[0]) {
+            // Timestamp,[Epoch,]Charge_Full,...
+            Ok(time) => match fields.len() {
+                6 => (time, &fields[1..]),
+                7 => (time, &fields[2..]),
+                _ => return Err(row_error(line, "unexpected number of fields")),
+            },
+            Err(_) => match fields.len() {
+                // Date,Hour,Charge_Full,...
+                5 | 7 => (parse_legacy_time(line, fields[0], fields[1])?, &fields[2..]),
+                // Date,Hour_str,Hour_f32,Charge_Full,...
+                8 => (parse_legacy_time(line, fields[0], fields[1])?, &fields[3..]),
+                _ => return Err(row_error(line, "unexpected number of fields")),
+            },
+        };
+
+        let parse_u32 = |value: &str| value.parse::<u32>().map_err(|err| row_error(line, &err.to_string()));
+        let parse_f32 = |value: &str| value.parse::<f32>().map_err(|err| row_error(line, &err.to_string()));
+
+        let battery_percentage = match values.get(3) {
+            Some(&MISSING) | None => None,
+            Some(value) => Some(parse_f32(value)?),
+        };
+        let battery_status = match values.get(4) {
+            Some(&MISSING) | None => None,
+            Some(value) => Some(BatteryState::match_string(value)?),
+        };
+
+        Ok(HistoryRecord {
+            time,
+            charge_full: parse_u32(values[0])?,
+            charge_full_design: parse_u32(values[1])?,
+            battery_health: parse_f32(values[2])?,
+            battery_percentage,
+            battery_status,
+            battery: None,
+        })
+    }
+
+    /// The row in the current schema, the battery goes in the segment line
+    pub fn to_row(&self, with_epoch: bool) -> String {
+        let timestamp = self.time.to_rfc3339_opts(SecondsFormat::Secs, false);
+        let epoch = if with_epoch {
+            format!(",{}", self.time.timestamp())
+        } else {
+            String::new()
+        };
+        let battery_percentage = self
+            .battery_percentage
+            .map_or(MISSING.to_owned(), |percentage| percentage.to_string());
+        let battery_status = self
+            .battery_status
+            .map_or(MISSING.to_owned(), |status| status.to_string());
+
+        format!(
+            "{timestamp}{epoch},{},{},{},{battery_percentage},{battery_status}",
+            self.charge_full, self.charge_full_design, self.battery_health,
+        )
+    }
+}
+
+/// Legacy files have the local date and hour without any timezone, they are read
+/// in the timezone of this machine. During a DST change the earliest time is taken.
+fn parse_legacy_time(line: &str, date: &str, hour: &str) -> Result<DateTime<FixedOffset>, MyError> {
+    let date = NaiveDate::parse_from_str(date, "%d/%m/%Y")
+        .or_else(|_| NaiveDate::parse_from_str(date, "%Y/%m/%d"))
+        .map_err(|err| row_error(line, &err.to_string()))?;
+    let hour =
+        NaiveTime::parse_from_str(hour, "%H:%M").map_err(|err| row_error(line, &err.to_string()))?;
+
+    let time: NaiveDateTime = date.and_time(hour);
+    Local
+        .from_local_datetime(&time)
+        .earliest()
+        // Times skipped by the clock going forward
+        .or_else(|| Local.from_local_datetime(&(time + Duration::hours(1))).earliest())
+        .map(|time| time.fixed_offset())
+        .ok_or_else(|| row_error(line, "time doesn't exist in the local timezone"))
+}
+
+fn row_error(line: &str, reason: &str) -> MyError {
+    MyError::HealthStatsError(format!("Invalid history row '{line}': {reason}"))
+}
+
+/// Schema of the file from its first line, None if the file is empty or missing
+pub fn detect_schema(path: &Path) -> Result<Option<Schema>, MyError> {
+    let Ok(file) = File::open(path) else {
+        return Ok(None);
+    };
+    let Some(first_line) = BufReader::new(file).lines().next() else {
+        return Ok(None);
+    };
+    let first_line = first_line?;
+
+    match first_line.strip_prefix(SCHEMA_LINE_PREFIX) {
+        Some(version) => version.trim().parse().map(|version| Some(Schema::Versioned(version))).map_err(|err| {
+            MyError::HealthStatsError(format!("Invalid schema line '{first_line}': {err}"))
+        }),
+        None => Schema::from_header(&first_line).map(Some),
+    }
+}
+
+/// Whether a file of the current schema has the Epoch column
+pub fn has_epoch_column(path: &Path) -> Result<bool, MyError> {
+    let file = File::open(path)?;
+    let header = BufReader::new(file).lines().nth(1).transpose()?;
+    Ok(header.as_deref() == Some(HEADER_WITH_EPOCH))
+}
+
+/// Lines of a history file, rotated files compressed with gzip are read transparently
+fn open_lines(path: &Path) -> Result<impl Iterator<Item = std::io::Result<String>>, MyError> {
+    let file = File::open(path)?;
+    let reader: Box<dyn Read> = if path.extension().is_some_and(|extension| extension == "gz") {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    Ok(BufReader::new(reader).lines())
+}
+
+fn parse_battery_line(line: &str) -> Option<Result<BatteryIdentity, MyError>> {
+    line.strip_prefix(BATTERY_LINE_PREFIX).map(BatteryIdentity::from_key)
+}
+
+fn battery_line(battery: &BatteryIdentity) -> String {
+    format!("{BATTERY_LINE_PREFIX}{}\n", battery.key())
+}
+
+/// Schema line, segment lines, headers (also repeated ones) and blank lines
+fn is_data_row(line: &str) -> bool {
+    !(line.starts_with('#')
+        || line.starts_with("Date,")
+        || line.starts_with("Timestamp,")
+        || line.trim().is_empty())
+}
+
+/// Read every record of a history file of any schema, with the battery of its segment.
+/// A last row cut short by a crash is skipped, invalid rows anywhere else are an error.
+pub fn read_history(path: &Path) -> Result<Vec<HistoryRecord>, MyError> {
+    let mut records = Vec::new();
+    let mut battery = None;
+    let mut lines = open_lines(path)?.peekable();
+    while let Some(line) = lines.next() {
+        let line = line?;
+        if let Some(segment_battery) = parse_battery_line(&line) {
+            battery = Some(segment_battery?);
+            continue;
+        }
+        if !is_data_row(&line) {
+            continue;
+        }
+        match HistoryRecord::parse_row(&line) {
+            Ok(record) => records.push(HistoryRecord {
+                battery: battery.clone(),
+                ..record
+            }),
+            Err(err) if lines.peek().is_none() => {
+                log::warn!("Skipping the truncated last row of {}: {err}", path.display())
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(records)
+}
+
+/// The oldest record of a history file, without reading the whole file
+pub fn first_record(path: &Path) -> Result<Option<HistoryRecord>, MyError> {
+    for line in open_lines(path)? {
+        let line = line?;
+        if is_data_row(&line) {
+            return HistoryRecord::parse_row(&line).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// The battery of the last segment of a history file, None if it has no segments
+pub fn last_battery(path: &Path) -> Result<Option<BatteryIdentity>, MyError> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut battery = None;
+    for line in open_lines(path)? {
+        if let Some(segment_battery) = parse_battery_line(&line?) {
+            battery = Some(segment_battery?);
+        }
+    }
+    Ok(battery)
+}
+
+/// The schema line and the header
+fn preamble(with_epoch: bool) -> String {
+    let header = if with_epoch { HEADER_WITH_EPOCH } else { HEADER };
+    format!("{SCHEMA_LINE_PREFIX}{SCHEMA_VERSION}\n{header}\n")
+}
+
+/// Remove the last line of `path` if a crash cut it before its newline,
+/// returns what was removed. Missing files are left alone.
+pub fn repair_truncated_line(path: &Path) -> Result<Option<String>, MyError> {
+    let Ok(mut file) = OpenOptions::new().read(true).write(true).open(path) else {
+        return Ok(None);
+    };
+    let len = file.metadata()?.len();
+    if len == 0 {
+        return Ok(None);
+    }
+
+    // Read backwards until the newline before the cut line, usually a single chunk
+    let mut tail = Vec::new();
+    let mut line_start = len;
+    loop {
+        let chunk_start = line_start.saturating_sub(4096);
+        let mut chunk = vec![0; (line_start - chunk_start) as usize];
+        file.seek(SeekFrom::Start(chunk_start))?;
+        file.read_exact(&mut chunk)?;
+
+        if line_start == len && chunk.last() == Some(&b'\n') {
+            return Ok(None);
+        }
+        match chunk.iter().rposition(|&byte| byte == b'\n') {
+            Some(newline) => {
+                tail.splice(0..0, chunk[newline + 1..].iter().copied());
+                line_start = chunk_start + newline as u64 + 1;
+                break;
+            }
+            None => {
+                tail.splice(0..0, chunk);
+                line_start = chunk_start;
+                if line_start == 0 {
+                    break;
+                }
+            }
+        }
+    }
+
+    file.set_len(line_start)?;
+    file.sync_data()?;
+    let removed = String::from_utf8_lossy(&tail).into_owned();
+    log::warn!("Removed the truncated last line of {}: '{removed}'", path.display());
+    Ok(Some(removed))
+}
+
+/// Append `text` with a single write and wait for it to be on disk, so that a power
+/// loss leaves at most one cut line for `repair_truncated_line`
+pub fn append_durably(path: &Path, text: &str) -> Result<(), MyError> {
+    let created = !path.exists();
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(text.as_bytes())?;
+    file.sync_data()?;
+
+    // The new directory entry has to reach the disk too
+    if created {
+        if let Some(directory) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            File::open(directory)?.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+/// Append `record` to the history, a new file starts with the schema line and the header,
+/// with the Epoch column if `with_epoch`. An existing file keeps the columns it has.
+/// A new segment is started when the record has a battery other than `segment_battery`,
+/// the one of the last segment of the file. Files with another schema are never appended
+/// to, they have to be migrated first.
+pub fn append_record(
+    path: &Path,
+    record: &HistoryRecord,
+    with_epoch: bool,
+    segment_battery: Option<&BatteryIdentity>,
+) -> Result<(), MyError> {
+    repair_truncated_line(path)?;
+    match detect_schema(path)? {
+        None | Some(Schema::Versioned(SCHEMA_VERSION)) => (),
+        Some(schema) => {
+            return Err(MyError::HealthStatsError(format!(
+                "{} has layout {schema:?} instead of schema {SCHEMA_VERSION}, run `energy_monitor migrate`",
+                path.display()
+            )))
+        }
+    }
+
+    let is_new = fs::metadata(path).map_or(true, |metadata| metadata.len() == 0);
+    let segment = match &record.battery {
+        Some(battery) if is_new || segment_battery != Some(battery) => battery_line(battery),
+        _ => String::new(),
+    };
+    let text = if is_new {
+        format!("{}{segment}{}\n", preamble(with_epoch), record.to_row(with_epoch))
+    } else {
+        format!("{segment}{}\n", record.to_row(has_epoch_column(path)?))
+    };
+    append_durably(path, &text)
+}
+
+/// Rewrite a history file in the current schema, the original is kept as `<file>.bak`.
+/// Returns the backup path, None if the file was already up to date.
+pub fn migrate(path: &Path, with_epoch: bool) -> Result<Option<PathBuf>, MyError> {
+    match detect_schema(path)? {
+        Some(Schema::Versioned(SCHEMA_VERSION)) => return Ok(None),
+        Some(Schema::Versioned(version)) if version > SCHEMA_VERSION => {
+            return Err(MyError::HealthStatsError(format!(
+                "{} has schema {version}, newer than {SCHEMA_VERSION}",
+                path.display()
+            )))
+        }
+        None => return Ok(None),
+        Some(_) => (),
+    }
+
+    let records = read_history(path)?;
+
+    let migrated_path = append_extension(path, "migrating");
+    let mut migrated = File::create(&migrated_path)?;
+    write!(migrated, "{}", preamble(with_epoch))?;
+    for record in &records {
+        writeln!(migrated, "{}", record.to_row(with_epoch))?;
+    }
+    migrated.sync_all()?;
+
+    let backup_path = append_extension(path, "bak");
+    fs::rename(path, &backup_path)?;
+    fs::rename(&migrated_path, path)?;
+    Ok(Some(backup_path))
+}
+
+/// data.csv -> data.csv.bak
+fn append_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut file_name = path.as_os_str().to_owned();
+    file_name.push(".");
+    file_name.push(extension);
+    PathBuf::from(file_name)
+}
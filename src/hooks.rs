@@ -0,0 +1,261 @@
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::SecondsFormat;
+
+use super::battery_health::{BatterySnapshot, BatteryState};
+use super::store::{ActuatorEvent, EventObserver, NotificationEvent};
+use super::utils::MyError;
+
+pub const DEFAULT_HEALTH_BELOW: f32 = 80.0;
+pub const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often a running hook is checked
+const WAIT_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Shell commands run through `sh -c` on the daemon events, the details are in
+/// ENERGY_MONITOR_* environment variables
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hooks {
+    /// Every battery level notification: 80%, 20%, the critical level, the charge estimate
+    pub threshold: Option<String>,
+    pub charger_connected: Option<String>,
+    pub charger_disconnected: Option<String>,
+    /// When the health goes under `health_below`
+    pub health_low: Option<String>,
+    pub health_below: f32,
+    pub actuator_failure: Option<String>,
+    /// A hook still running after this is killed, the next ones would wait otherwise
+    pub timeout: Duration,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Hooks {
+            threshold: None,
+            charger_connected: None,
+            charger_disconnected: None,
+            health_low: None,
+            health_below: DEFAULT_HEALTH_BELOW,
+            actuator_failure: None,
+            timeout: DEFAULT_HOOK_TIMEOUT,
+        }
+    }
+}
+
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        [
+            &self.threshold,
+            &self.charger_connected,
+            &self.charger_disconnected,
+            &self.health_low,
+            &self.actuator_failure,
+        ]
+        .iter()
+        .all(|hook| hook.is_none())
+    }
+
+    fn command(&self, event: &HookEvent) -> Option<&str> {
+        match event {
+            HookEvent::Threshold(_) => self.threshold.as_deref(),
+            HookEvent::ChargerConnected(_) => self.charger_connected.as_deref(),
+            HookEvent::ChargerDisconnected(_) => self.charger_disconnected.as_deref(),
+            HookEvent::HealthLow(_) => self.health_low.as_deref(),
+            HookEvent::ActuatorFailure(_) => self.actuator_failure.as_deref(),
+        }
+    }
+
+    /// Run the hook of `event` and wait for it up to `timeout`, nothing if it isn't
+    /// configured
+    pub fn run(&self, event: &HookEvent) -> Result<(), MyError> {
+        let Some(command) = self.command(event) else {
+            return Ok(());
+        };
+        let failed =
+            |err: std::io::Error| MyError::HookError(format!("Failed to run '{command}': {err}"));
+        // In its own process group, so that a timeout kills what the script started too
+        let mut child = Command::new("sh")
+            .args(["-c", command])
+            .envs(event.env())
+            .process_group(0)
+            .spawn()
+            .map_err(failed)?;
+        let started = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait().map_err(failed)? {
+                break status;
+            }
+            if started.elapsed() > self.timeout {
+                // SAFETY: kill has no memory effects, the group is the one of the child
+                unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+                let _ = child.wait();
+                return Err(MyError::HookError(format!(
+                    "'{command}' killed on {} after {}s",
+                    event.name(),
+                    self.timeout.as_secs()
+                )));
+            }
+            thread::sleep(WAIT_INTERVAL);
+        };
+        match status.success() {
+            true => Ok(()),
+            false => Err(MyError::HookError(format!(
+                "'{command}' failed on {}: {status}",
+                event.name()
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum HookEvent {
+    Threshold(NotificationEvent),
+    ChargerConnected(BatterySnapshot),
+    ChargerDisconnected(BatterySnapshot),
+    HealthLow(BatterySnapshot),
+    ActuatorFailure(ActuatorEvent),
+}
+
+impl HookEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HookEvent::Threshold(_) => "threshold",
+            HookEvent::ChargerConnected(_) => "charger_connected",
+            HookEvent::ChargerDisconnected(_) => "charger_disconnected",
+            HookEvent::HealthLow(_) => "health_low",
+            HookEvent::ActuatorFailure(_) => "actuator_failure",
+        }
+    }
+
+    /// The environment variables given to the hook
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        let mut env = vec![("ENERGY_MONITOR_EVENT", self.name().to_owned())];
+        match self {
+            HookEvent::Threshold(notification) => env.extend([
+                ("ENERGY_MONITOR_TIME", rfc3339(&notification.time)),
+                ("ENERGY_MONITOR_LEVEL", notification.level.clone()),
+                ("ENERGY_MONITOR_MESSAGE", notification.message.clone()),
+            ]),
+            HookEvent::ChargerConnected(snapshot)
+            | HookEvent::ChargerDisconnected(snapshot)
+            | HookEvent::HealthLow(snapshot) => env.extend([
+                (
+                    "ENERGY_MONITOR_TIME",
+                    rfc3339(&snapshot.time.fixed_offset()),
+                ),
+                (
+                    "ENERGY_MONITOR_PERCENTAGE",
+                    format!("{:.1}", snapshot.percentage()),
+                ),
+                ("ENERGY_MONITOR_STATUS", snapshot.state.to_string()),
+                ("ENERGY_MONITOR_HEALTH", format!("{:.2}", snapshot.health())),
+            ]),
+            HookEvent::ActuatorFailure(event) => env.extend([
+                ("ENERGY_MONITOR_TIME", rfc3339(&event.time)),
+                ("ENERGY_MONITOR_COMMAND", event.command.to_string()),
+                (
+                    "ENERGY_MONITOR_ERROR",
+                    event.error.clone().unwrap_or_default(),
+                ),
+                (
+                    "ENERGY_MONITOR_PERCENTAGE",
+                    format!("{:.1}", event.percentage_before),
+                ),
+            ]),
+        }
+        env
+    }
+}
+
+fn rfc3339(time: &chrono::DateTime<chrono::FixedOffset>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, false)
+}
+
+/// Finds the charger and health events in the snapshots
+#[derive(Debug)]
+pub struct SnapshotWatcher {
+    health_below: f32,
+    /// None before the first snapshot, nothing is plugged or unplugged then
+    plugged: Option<bool>,
+    /// None before the first snapshot too, a restart with the health already low is silent
+    health_low: Option<bool>,
+}
+
+impl SnapshotWatcher {
+    pub fn new(health_below: f32) -> Self {
+        SnapshotWatcher {
+            health_below,
+            plugged: None,
+            health_low: None,
+        }
+    }
+
+    /// The health event comes once, and again only after the health went back up. The
+    /// first snapshot only says where the health is, like for the charger
+    pub fn observe(&mut self, snapshot: &BatterySnapshot) -> Vec<HookEvent> {
+        let mut events = Vec::new();
+        let plugged = snapshot.state != BatteryState::Discharging;
+        match (self.plugged, plugged) {
+            (Some(false), true) => events.push(HookEvent::ChargerConnected(snapshot.clone())),
+            (Some(true), false) => events.push(HookEvent::ChargerDisconnected(snapshot.clone())),
+            _ => (),
+        }
+        self.plugged = Some(plugged);
+
+        let health_low = snapshot.health() < self.health_below;
+        if health_low && self.health_low == Some(false) {
+            events.push(HookEvent::HealthLow(snapshot.clone()));
+        }
+        self.health_low = Some(health_low);
+        events
+    }
+}
+
+/// Sends the notifications and the failed commands recorded in the store to the hooks
+pub struct HookForwarder(pub Sender<HookEvent>);
+
+impl EventObserver for HookForwarder {
+    fn actuator_event(&self, event: &ActuatorEvent) {
+        if event.error.is_some() {
+            // The hooks are gone only when the daemon is stopping
+            let _ = self.0.send(HookEvent::ActuatorFailure(event.clone()));
+        }
+    }
+
+    fn notification(&self, event: &NotificationEvent) {
+        // Only the battery levels, a new battery isn't a threshold
+        if event.level.ends_with('%') {
+            let _ = self.0.send(HookEvent::Threshold(event.clone()));
+        }
+    }
+}
+
+/// Run the hooks one at a time on the `events` and on the changes in the `snapshots`,
+/// until the sampler closes the channel
+pub fn run_hooks(
+    hooks: &Hooks,
+    snapshots: &Receiver<BatterySnapshot>,
+    events: &Receiver<HookEvent>,
+) -> Result<(), MyError> {
+    let mut watcher = SnapshotWatcher::new(hooks.health_below);
+    // Every snapshot is received, a short plug in between two would be missed otherwise
+    for snapshot in snapshots {
+        let mut fired = events.try_iter().collect::<Vec<_>>();
+        fired.extend(watcher.observe(&snapshot));
+        for event in fired {
+            // A broken script doesn't stop the other hooks
+            if let Err(err) = hooks.run(&event) {
+                log::warn!("{err}");
+            }
+        }
+    }
+    for event in events.try_iter() {
+        if let Err(err) = hooks.run(&event) {
+            log::warn!("{err}");
+        }
+    }
+    Ok(())
+}
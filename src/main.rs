@@ -1,61 +1,635 @@
+pub mod actuator;
+pub mod analysis;
 pub mod ardu;
 pub mod battery_health;
+pub mod critical;
+pub mod cycles;
+pub mod estimate;
+pub mod history;
+pub mod hooks;
+pub mod metrics;
+pub mod mqtt;
+pub mod plot;
+pub mod power;
+pub mod rotation;
+pub mod sampler;
 pub mod secret_info;
+pub mod shutdown;
+pub mod store;
+pub mod supervisor;
+pub mod tui;
+pub mod uevent;
+pub mod usage;
 pub mod utils;
 
-use ardu::{ArduCommand, ArduSketch};
+use actuator::Actuator;
+use ardu::{ArduCommand, ArduSketch, ChargerMode, SharedMode, ShutdownState};
 use battery_health::*;
+use critical::{CriticalAction, CriticalPolicy, CriticalStatus, SystemRunner};
+use cycles::CycleCounter;
+use estimate::{format_remaining, Estimate, RuntimeEstimator};
+use history::{HistoryRecord, WritePolicy};
+use hooks::HookForwarder;
 use log::LevelFilter;
+use metrics::Metrics;
+use mqtt::{BrokerLink, EventForwarder, Topics};
+use power::SessionMeter;
 use secret_info::{CONFIG_FILE_PATH, DATA_FILE_PATH}; // config file and the csv file in which to store data
-use std::io::Write;
-use std::thread::sleep;
-use std::{error::Error, fs::OpenOptions, path::Path, thread, time::Duration};
+use std::sync::mpsc::{self, Receiver};
+use std::thread::JoinHandle;
+use std::time::Instant;
+use std::fs;
+use std::io::IsTerminal;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::{env, error::Error, path::Path, time::Duration};
+use sampler::{recv_latest, Sampler, SysfsSource};
+use shutdown::{PowerEvents, Shutdown};
+use store::{ActuatorEvent, NotificationEvent, SharedStore};
+use supervisor::Supervisor;
+use uevent::NetlinkSource;
 use utils::notify_percentage;
-use utils::Config;
+use utils::{Config, MyError};
 // use battery_health::BatteryState;
 
 const CHARGE_UPPER_LIMIT: f32 = 74.0;
 const DISCHARGE_LOWER_LIMIT: f32 = 20.0;
 //const WRITE_BATTERY_HEALTH_STATS_EVERY: u64 = 5; // minutes
-const BATTERY_CHECK_TIME: u64 = 20;
+/// Every consumer gets a new snapshot at least this often, sooner on power supply events
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(3);
+const RESTART_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(300);
+
+const USAGE: &str =
+    "Usage: energy_monitor [daemon | status | cycles | migrate [FILE]... | import [FILE]... \
+     | health [FILE]... | usage [--weekly] [--json] [FILE]... \
+     | plot [--output FILE.svg|FILE.png] [--days N] [FILE]... | tui]";
+/// Capacity levels forecast by the health report
+const HEALTH_TARGETS: [f64; 2] = [80.0, 70.0];
+/// The tui reads the history for the sparkline this often, the rest every SAMPLE_INTERVAL
+const TUI_HISTORY_REFRESH: Duration = Duration::from_secs(60);
+const TUI_EVENTS: usize = 20;
+/// The daemon rewrites its status this often, even when nothing changes
+const STATUS_REFRESH: Duration = Duration::from_secs(60);
+/// A status older than this was left by a daemon that didn't stop cleanly
+const STATUS_STALE: Duration = Duration::from_secs(180);
+/// Days of charge drawn by the plot command, the health is drawn for the whole history
+const PLOT_DAYS: i64 = 7;
 
 fn main() -> Result<(), Box<dyn Error>> {
     env_logger::Builder::new()
         .filter_level(LevelFilter::Info) // Set log level
         .init();
 
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    match args.first().map(String::as_str) {
+        None | Some("daemon") => run_daemon(),
+        Some("status") => status(),
+        Some("cycles") => cycles_report(),
+        Some("migrate") => migrate(&args[1..]),
+        Some("import") => import(&args[1..]),
+        Some("health") => health_report(&args[1..]),
+        Some("usage") => usage_report(&args[1..]),
+        Some("plot") => plot(&args[1..]),
+        Some("tui") => dashboard(),
+        Some(_) => {
+            eprintln!("{USAGE}");
+            Err(Box::new(MyError::ConfigError(format!("Unknown command '{}'", args.join(" ")))))
+        }
+    }
+}
+
+/// Print the battery level and how long until it is empty or at CHARGE_UPPER_LIMIT.
+/// A single reading, so the estimate needs current_now.
+fn status() -> Result<(), Box<dyn Error>> {
+    let snapshot = BatterySnapshot::read()?;
+    println!(
+        "Battery: {:.1}%, {}, health {:.2}%",
+        snapshot.percentage(),
+        snapshot.state,
+        snapshot.health()
+    );
+    if let Some(watts) = snapshot.power() {
+        println!("Power: {watts:.2} W");
+    }
+    match RuntimeEstimator::new(CHARGE_UPPER_LIMIT).update(&snapshot) {
+        Some(Estimate::Empty(remaining)) => println!("Empty in {}", format_remaining(remaining)),
+        Some(Estimate::Limit(limit, remaining)) => {
+            println!("{limit}% in {}", format_remaining(remaining))
+        }
+        None if snapshot.current_now.is_none()
+            && matches!(snapshot.state, BatteryState::Charging | BatteryState::Discharging) =>
+        {
+            println!("No estimate, the battery doesn't report current_now")
+        }
+        None => (),
+    }
+    print!("{}", daemon_status(supervisor::read_status(&status_path(), STATUS_STALE).as_deref()));
+    Ok(())
+}
+
+/// The summary written by the daemon, with the failing workers, and its details like the mode
+pub fn daemon_status(status: Option<&str>) -> String {
+    let Some(status) = status else {
+        return "Daemon: not running\n".to_owned();
+    };
+    let (summary, details) = supervisor::parse_status(status);
+    let mut output = format!("Daemon: {summary}\n");
+    for (name, value) in details {
+        output.push_str(&format!("  {name}: {value}\n"));
+    }
+    output
+}
+
+/// Print the cycles counted by the daemon next to the kernel cycle_count
+fn cycles_report() -> Result<(), Box<dyn Error>> {
+    let Some(counter) = CycleCounter::load(&cycles_path())? else {
+        return Err(Box::new(MyError::HealthStatsError(format!(
+            "No cycles counted yet, the daemon keeps them in {}",
+            cycles_path().display()
+        ))));
+    };
+    print!("{}", counter.report(&BatterySnapshot::read()?));
+    Ok(())
+}
+
+/// Rewrite the given history files, or DATA_FILE_PATH, in the current schema
+fn migrate(files: &[String]) -> Result<(), Box<dyn Error>> {
+    // Same columns the daemon would create
+    let epoch_column = match Config::get(CONFIG_FILE_PATH) {
+        Ok(config) => config.epoch_column(),
+        Err(err) => {
+            log::warn!("{err}, migrating without the epoch column");
+            false
+        }
+    };
+    let files = if files.is_empty() {
+        vec![DATA_FILE_PATH.to_owned()]
+    } else {
+        files.to_vec()
+    };
+
+    for file in files {
+        let path = Path::new(&file);
+        match history::migrate(path, epoch_column)? {
+            Some(backup_path) => println!(
+                "{file}: migrated to schema {}, original kept in {}",
+                history::SCHEMA_VERSION,
+                backup_path.display()
+            ),
+            None => println!("{file}: nothing to migrate"),
+        }
+    }
+    Ok(())
+}
+
+/// The records of the given history files with their rotated files, or else of the
+/// configured store, the one the daemon writes
+fn read_histories(files: &[String]) -> Result<Vec<HistoryRecord>, Box<dyn Error>> {
+    if files.is_empty() {
+        let history_store = open_history_store(&Config::get(CONFIG_FILE_PATH)?)?;
+        let from = chrono::DateTime::<chrono::Utc>::MIN_UTC.fixed_offset();
+        let to = chrono::DateTime::<chrono::Utc>::MAX_UTC.fixed_offset();
+        return Ok(history_store.snapshots_between(from, to)?);
+    }
+    let mut records = Vec::new();
+    for file in files {
+        records.extend(rotation::read_rotated_history(Path::new(file))?);
+    }
+    Ok(records)
+}
+
+/// Print the capacity fade of the given history files, or of the history store
+fn health_report(files: &[String]) -> Result<(), Box<dyn Error>> {
+    let records = read_histories(files)?;
+    let batteries = analysis::split_by_battery(&records);
+    if batteries.len() <= 1 {
+        if let Some((Some(battery), _)) = batteries.first() {
+            println!("Battery {battery}");
+        }
+        print!("{}", analysis::HealthReport::new(&records, &HEALTH_TARGETS)?);
+        return Ok(());
+    }
+    // One report per battery, a short history of an old one isn't an error
+    for (index, (battery, records)) in batteries.iter().enumerate() {
+        if index > 0 {
+            println!();
+        }
+        match battery {
+            Some(battery) => println!("Battery {battery}"),
+            None => println!("Unknown battery"),
+        }
+        match analysis::HealthReport::new(records, &HEALTH_TARGETS) {
+            Ok(report) => print!("{report}"),
+            Err(err) => println!("{err}"),
+        }
+    }
+    Ok(())
+}
+
+/// Print the daily, or weekly, usage of the given history files, or of the history store,
+/// as a table or as JSON
+fn usage_report(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut period = usage::UsagePeriod::Daily;
+    let mut json = false;
+    let mut files = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--weekly" => period = usage::UsagePeriod::Weekly,
+            "--daily" => period = usage::UsagePeriod::Daily,
+            "--json" => json = true,
+            _ if arg.starts_with("--") => {
+                eprintln!("{USAGE}");
+                return Err(Box::new(MyError::ConfigError(format!("Unknown option '{arg}'"))));
+            }
+            _ => files.push(arg.clone()),
+        }
+    }
+
+    let records = read_histories(&files)?;
+    let window = (DISCHARGE_LOWER_LIMIT, CHARGE_UPPER_LIMIT);
+    let summaries = usage::usage_report(&records, period, window)?;
+    if json {
+        println!("{}", usage::to_json(&summaries)?);
+    } else {
+        print!("{}", usage::format_table(&summaries, window));
+    }
+    Ok(())
+}
+
+/// Plot the charge and the health of the given history files, or of the history store,
+/// to a file or the terminal
+fn plot(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let invalid = |message: String| {
+        eprintln!("{USAGE}");
+        Box::new(MyError::ConfigError(message))
+    };
+    let mut output = None;
+    let mut days = PLOT_DAYS;
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output" => match args.next() {
+                Some(path) => output = Some(path.clone()),
+                None => return Err(invalid("--output needs a file".to_owned())),
+            },
+            "--days" => match args.next().map(|days| days.parse::<i64>()) {
+                Some(Ok(value)) if value > 0 => days = value,
+                _ => return Err(invalid("--days needs a positive number".to_owned())),
+            },
+            _ if arg.starts_with("--") => return Err(invalid(format!("Unknown option '{arg}'"))),
+            _ => files.push(arg.clone()),
+        }
+    }
+
+    let records = read_histories(&files)?;
+    let Some(span) = chrono::Duration::try_days(days) else {
+        return Err(invalid(format!("--days {days} is too many")));
+    };
+    let span = Some(span);
+    match output {
+        Some(output) => {
+            plot::plot_file(&records, Path::new(&output), span)?;
+            println!("Plotted {} records in {output}", records.len());
+        }
+        None => {
+            let width = ratatui::crossterm::terminal::size().map_or(80, |(columns, _)| columns);
+            let color = std::io::stdout().is_terminal();
+            print!("{}", plot::terminal_chart(&records, span, usize::from(width), color)?);
+        }
+    }
+    Ok(())
+}
+
+/// Live view of the battery, the controller and the last 24 h of history
+fn dashboard() -> Result<(), Box<dyn Error>> {
+    let config = Config::get(CONFIG_FILE_PATH)?;
+    let history_store = open_history_store(&config)?;
+    let limits = tui::Limits {
+        lower: DISCHARGE_LOWER_LIMIT,
+        upper: CHARGE_UPPER_LIMIT,
+        critical: config.critical_level(),
+    };
+    let mut estimator = RuntimeEstimator::new(CHARGE_UPPER_LIMIT);
+    let mut history = Vec::new();
+    let mut history_loaded: Option<Instant> = None;
+    tui::run(SAMPLE_INTERVAL, || {
+        let snapshot = BatterySnapshot::read()?;
+        if history_loaded.is_none_or(|loaded| loaded.elapsed() >= TUI_HISTORY_REFRESH) {
+            let now = snapshot.time.fixed_offset();
+            history = history_store.snapshots_between(now - tui::SPARKLINE_SPAN, now)?;
+            history_loaded = Some(Instant::now());
+        }
+        let status = supervisor::read_status(&status_path(), STATUS_STALE);
+        let (daemon_status, details) = status.as_deref().map(supervisor::parse_status).unzip();
+        let details = details.unwrap_or_default();
+        // The daemon knows the mode set over MQTT, auto when it isn't running
+        let mode = details
+            .get("mode")
+            .and_then(|mode| ChargerMode::match_string(mode).ok())
+            .unwrap_or_default();
+        let command = details
+            .get("command")
+            .and_then(|command| ArduSketch::match_string(command).ok())
+            .unwrap_or_else(|| controller_command(&snapshot, mode));
+        Ok(tui::Dashboard {
+            estimate: estimator.update(&snapshot),
+            limits,
+            mode,
+            command,
+            daemon_status,
+            events: history_store.last_actuator_events(TUI_EVENTS)?,
+            history: history.clone(),
+            snapshot,
+        })
+    })?;
+    Ok(())
+}
+
+/// The cycle counter lives next to the history
+fn cycles_path() -> std::path::PathBuf {
+    Path::new(DATA_FILE_PATH).with_extension("cycles")
+}
+
+/// The status of the daemon for the tui and the status command
+fn status_path() -> std::path::PathBuf {
+    Path::new(DATA_FILE_PATH).with_extension("status")
+}
+
+/// The SQLite history lives next to the csv one
+fn sqlite_path() -> std::path::PathBuf {
+    Path::new(DATA_FILE_PATH).with_extension("db")
+}
+
+/// The history store chosen by history_backend
+fn open_history_store(config: &Config) -> Result<Box<dyn store::HistoryStore>, MyError> {
+    store::open(
+        config.history_backend(),
+        Path::new(DATA_FILE_PATH),
+        &sqlite_path(),
+        config.epoch_column(),
+        config.history_rotation(),
+    )
+}
+
+/// Copy the given history files, or DATA_FILE_PATH, in the SQLite database
+#[cfg(feature = "sqlite")]
+fn import(files: &[String]) -> Result<(), Box<dyn Error>> {
+    let files = if files.is_empty() {
+        vec![DATA_FILE_PATH.to_owned()]
+    } else {
+        files.to_vec()
+    };
+
+    let mut database = store::sqlite::SqliteStore::open(&sqlite_path())?;
+    for file in files {
+        let report = database.import_csv(Path::new(&file))?;
+        println!(
+            "{file}: {} new rows in {}, {} skipped already there",
+            report.imported,
+            sqlite_path().display(),
+            report.skipped
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn import(_files: &[String]) -> Result<(), Box<dyn Error>> {
+    Err(Box::new(MyError::ConfigError(
+        "energy_monitor was built without the sqlite feature".to_owned(),
+    )))
+}
+
+/// Start the sampler, the notifier, the logger and the controller
+fn run_daemon() -> Result<(), Box<dyn Error>> {
     let mut has_been_notified_80 = false;
     let mut has_been_notified_20 = false;
 
-    let config = Config::get(CONFIG_FILE_PATH);
+    let config = Config::get(CONFIG_FILE_PATH)?;
 
     println!("{config:?}");
     let battery_notifier = config.battery_notifier();
+    // The thresholds are watched for the hook even without desktop notifications
+    let watch_thresholds = battery_notifier || config.hooks().threshold.is_some();
     let write_health_stats = config.health_stats();
-    let write_every = config.write_every();
-
-    //println!("{battery_notifier}{h_stats}");
-    let handle1 = thread::spawn(move || {
-        if battery_notifier {
-            thread::sleep(Duration::from_secs(1));
-            notifier(&mut has_been_notified_80, &mut has_been_notified_20);
-            //println!("notify")
+    let write_policy = config.write_policy();
+    // The events are logged even when health_stats is disabled
+    let history_store = SharedStore::new(open_history_store(&config)?);
+    // The daemon runs without the exporter if its address can't be used
+    let metrics_listener = config.metrics_address().and_then(|address| {
+        match TcpListener::bind(address) {
+            Ok(listener) => {
+                log::info!("Serving metrics on http://{address}/metrics");
+                Some(listener)
+            }
+            Err(err) => {
+                log::error!("Failed to listen for metrics on {address}: {err}");
+                None
+            }
         }
     });
-
-    let handle2 = thread::spawn(move || {
-        if write_health_stats {
-            std::thread::sleep(Duration::from_secs(4));
-            match health_stats(write_every) {
-                Ok(_) => (),
-                Err(err) => {
-                    println!("{err}")
-                }
-            };
-            //println!("write stats")
-        };
+    let metrics = Metrics::new();
+    let history_store = match metrics_listener {
+        Some(_) => history_store.with_observer(Arc::new(metrics.clone())),
+        None => history_store,
+    };
+    // The controller events reach the broker through the store, like the metrics
+    let mqtt = config.mqtt().cloned().map(|mqtt_config| {
+        let (sender, events) = mpsc::channel();
+        let topics = Topics::new(&mqtt::node_id(), &mqtt_config.discovery_prefix);
+        log::info!(
+            "Publishing on mqtt://{}:{}/{}",
+            mqtt_config.host,
+            mqtt_config.port,
+            topics.state()
+        );
+        (mqtt_config, topics, events, sender)
+    });
+    let history_store = match &mqtt {
+        Some((_, _, _, sender)) => {
+            history_store.with_observer(Arc::new(EventForwarder(sender.clone())))
+        }
+        None => history_store,
+    };
+    // The notifications and the failed commands reach the hooks through the store too
+    let hooks = (!config.hooks().is_empty()).then(|| {
+        let (sender, events) = mpsc::channel();
+        (config.hooks().clone(), events, sender)
     });
+    let history_store = match &hooks {
+        Some((_, _, sender)) => {
+            history_store.with_observer(Arc::new(HookForwarder(sender.clone())))
+        }
+        None => history_store,
+    };
+    // Home Assistant can take the charger from the hysteresis
+    let mode = SharedMode::default();
+    // With only the threshold hook the critical level is reported, the action stays off
+    let critical_action = match battery_notifier {
+        true => config.critical_action().clone(),
+        false => CriticalAction::Disabled,
+    };
+    let mut critical_policy = CriticalPolicy::new(
+        config.critical_level(),
+        Duration::from_secs(config.critical_grace()),
+        critical_action,
+    );
+
+    let shutdown_state = config.shutdown_state();
+    let actuator = config.actuator().clone();
+
+    let shutdown = Shutdown::new();
+    shutdown.listen_signals()?;
+    let power_events = PowerEvents::new(&shutdown);
+    match NetlinkSource::new() {
+        Ok(source) => {
+            uevent::listen(source, power_events.clone())?;
+        }
+        Err(err) => log::warn!("Failed to subscribe to power supply uevents, polling only: {err}"),
+    }
+
+    let supervisor = Supervisor::new(
+        Some(status_path()),
+        true,
+        RESTART_BACKOFF_MIN,
+        RESTART_BACKOFF_MAX,
+        shutdown.clone(),
+    );
+    let mut handles: Vec<JoinHandle<()>> = Vec::new();
+
+    // An old status is taken for a daemon that was killed
+    let status_supervisor = supervisor.clone();
+    let status_shutdown = shutdown.clone();
+    handles.push(supervisor.spawn("status", move || {
+        while !status_shutdown.sleep(STATUS_REFRESH) {
+            status_supervisor.refresh_status();
+        }
+        Ok(())
+    }));
+
+    // Before cycle_counter can move the cycles of a swapped battery aside
+    let previous_battery = previous_battery(&cycles_path());
+
+    // Subscribe every consumer before the first snapshot is sent
+    let mut sampler = Sampler::new().with_power_events(power_events);
+    let notifier_snapshots = watch_thresholds.then(|| sampler.subscribe());
+    let health_stats_snapshots = write_health_stats.then(|| sampler.subscribe());
+    let controller_snapshots = sampler.subscribe();
+    let power_snapshots = sampler.subscribe();
+    let cycle_snapshots = sampler.subscribe();
+    let battery_snapshots = sampler.subscribe();
+    let metrics_snapshots = metrics_listener.is_some().then(|| sampler.subscribe());
+    let mqtt_snapshots = mqtt.is_some().then(|| sampler.subscribe());
+    let hooks_snapshots = hooks.is_some().then(|| sampler.subscribe());
+
+    let sampler_shutdown = shutdown.clone();
+    let mut source = SysfsSource::default();
+    handles.push(supervisor.spawn("sampler", move || {
+        sampler.run(&mut source, SAMPLE_INTERVAL, &sampler_shutdown)
+    }));
+
+    //println!("{battery_notifier}{h_stats}");
+    if let Some(snapshots) = notifier_snapshots {
+        let notifier_supervisor = supervisor.clone();
+        let notifier_store = history_store.clone();
+        handles.push(supervisor.spawn("notifier", move || {
+            notifier(
+                &snapshots,
+                &mut has_been_notified_80,
+                &mut has_been_notified_20,
+                &mut critical_policy,
+                &notifier_supervisor,
+                &notifier_store,
+                battery_notifier,
+            )
+        }));
+        //println!("notify")
+    }
+
+    if let Some(snapshots) = health_stats_snapshots {
+        let health_stats_store = history_store.clone();
+        handles.push(supervisor.spawn("health_stats", move || {
+            health_stats(&health_stats_store, write_policy, &snapshots)
+        }));
+        //println!("write stats")
+    };
+
+    let power_store = history_store.clone();
+    handles.push(supervisor.spawn("power_meter", move || {
+        power_meter(&power_store, &power_snapshots)
+    }));
+
+    handles.push(supervisor.spawn("cycle_counter", move || {
+        cycle_counter(&cycles_path(), &cycle_snapshots)
+    }));
+
+    let battery_store = history_store.clone();
+    handles.push(supervisor.spawn("battery_watcher", move || {
+        battery_watcher(previous_battery.clone(), &battery_snapshots, &battery_store, battery_notifier)
+    }));
+
+    if let (Some(listener), Some(snapshots)) = (metrics_listener, metrics_snapshots) {
+        handles.push(supervisor.spawn("metrics", move || {
+            metrics::serve(&listener, &metrics, &snapshots)
+        }));
+    }
+
+    if let (Some((mqtt_config, topics, events, _)), Some(snapshots)) = (mqtt, mqtt_snapshots) {
+        let mqtt_mode = mode.clone();
+        handles.push(supervisor.spawn("mqtt", move || {
+            // A new connection after every failure, the supervisor waits in between
+            let mut link = BrokerLink::new(&mqtt_config, &topics);
+            mqtt::bridge(&mut link, &topics, &snapshots, &events, &mqtt_mode)
+        }));
+    }
+
+    if let (Some((hooks, events, _)), Some(snapshots)) = (hooks, hooks_snapshots) {
+        handles.push(supervisor.spawn("hooks", move || {
+            hooks::run_hooks(&hooks, &snapshots, &events)
+        }));
+    }
+
+    let controller_shutdown = shutdown.clone();
+    let controller_actuator = actuator.clone();
+    let controller_supervisor = supervisor.clone();
+    handles.push(supervisor.spawn("controller", move || {
+        controller(
+            &controller_snapshots,
+            &controller_shutdown,
+            &history_store,
+            &mode,
+            &controller_actuator,
+            &controller_supervisor,
+        )
+    }));
+
+    for handle in handles {
+        handle.join().expect("Supervisor thread panicked");
+    }
+    supervisor.remove_status();
 
+    // Every worker is stopped, nobody is switching the charger anymore
+    if shutdown_state == ShutdownState::Connected {
+        log::info!("Leaving the charger connected");
+        actuator.switch(ArduSketch::Connect)?;
+    }
+    log::info!("energy_monitor stopped");
+    Ok(())
+}
+
+/// Connect the charger under CHARGE_UPPER_LIMIT and disconnect it above, unless `mode`
+/// says otherwise, with `actuator`. Every command is logged in `history_store`, the
+/// current one and the mode are in the status of `supervisor`
+pub fn controller(
+    snapshots: &Receiver<BatterySnapshot>,
+    shutdown: &Shutdown,
+    history_store: &SharedStore,
+    mode: &SharedMode,
+    actuator: &Actuator,
+    supervisor: &Supervisor,
+) -> Result<(), MyError> {
     let do_nothing_cmd = ArduCommand {
         command_type: ArduSketch::DoNothing,
         state: ardu::CommandState::Stopped,
@@ -69,31 +643,68 @@ fn main() -> Result<(), Box<dyn Error>> {
         command_type: ArduSketch::Disconnect,
         state: ardu::CommandState::Stopped,
     };
-    let handle3 = thread::spawn(move || loop {
-        
-        let batt_perc = get_battery_percentage().expect("Failed getting batt percentage");
-        let batt_state = BatteryState::match_string(&get_battery_state().unwrap());
-        match (batt_perc, batt_state){
-            (0_f32..CHARGE_UPPER_LIMIT, BatteryState::Discharging) => {
-                connect_cmd.execute();
+    // The sampler closes the channel on shutdown
+    while let Some(snapshot) = recv_latest(snapshots) {
+        let current_mode = mode.get();
+        let command = controller_command(&snapshot, current_mode);
+        supervisor.set_detail("mode", current_mode.to_string());
+        supervisor.set_detail("command", command.to_string());
+        history_store.record_decision(command, current_mode);
+        match command {
+            ArduSketch::Connect => {
+                run_command(&connect_cmd, &snapshot, snapshots, shutdown, history_store, actuator, mode)?;
             },
-            (CHARGE_UPPER_LIMIT..100_f32, BatteryState::Charging) => {
-                disconnect_cmd.execute();
+            ArduSketch::Disconnect => {
+                run_command(&disconnect_cmd, &snapshot, snapshots, shutdown, history_store, actuator, mode)?;
             },
-            (_, _) =>{
-                do_nothing_cmd.execute();
+            ArduSketch::DoNothing =>{
+                run_command(&do_nothing_cmd, &snapshot, snapshots, shutdown, history_store, actuator, mode)?;
             }
         }
-        
-        sleep(Duration::from_secs(3));
-    });
-
-    handle1.join().expect("Thread 1 panicked");
-    handle2.join().expect("Thread 2 panicked");
-    handle3.join().expect("Thread 3 panicked");
+    }
     Ok(())
 }
 
+/// The sketch the controller flashes for `snapshot`
+pub fn controller_command(snapshot: &BatterySnapshot, mode: ChargerMode) -> ArduSketch {
+    match (mode, snapshot.percentage(), snapshot.state) {
+        (ChargerMode::Auto, 0_f32..CHARGE_UPPER_LIMIT, BatteryState::Discharging) => ArduSketch::Connect,
+        (ChargerMode::Auto, CHARGE_UPPER_LIMIT..100_f32, BatteryState::Charging) => ArduSketch::Disconnect,
+        (ChargerMode::Connect, _, BatteryState::Discharging) => ArduSketch::Connect,
+        (ChargerMode::Disconnect, _, BatteryState::Charging) => ArduSketch::Disconnect,
+        (_, _, _) => ArduSketch::DoNothing,
+    }
+}
+
+/// Execute `command` and log the battery level before and after, the latency and the outcome
+fn run_command(
+    command: &ArduCommand,
+    before: &BatterySnapshot,
+    snapshots: &Receiver<BatterySnapshot>,
+    shutdown: &Shutdown,
+    history_store: &SharedStore,
+    actuator: &Actuator,
+    mode: &SharedMode,
+) -> Result<(), MyError> {
+    let time = chrono::Local::now().fixed_offset();
+    let started = Instant::now();
+    let result = command.execute(actuator, mode, snapshots, shutdown);
+
+    let (latency, percentage_after) = match &result {
+        Ok(Some(reaction)) => (reaction.latency, Some(reaction.snapshot.percentage())),
+        Ok(None) | Err(_) => (started.elapsed(), None),
+    };
+    history_store.record_actuator_event(&ActuatorEvent {
+        time,
+        command: command.command_type,
+        error: result.as_ref().err().map(ToString::to_string),
+        latency,
+        percentage_before: before.percentage(),
+        percentage_after,
+    });
+    result.map(|_| ())
+}
+
 //const FACTORY_VALUE: u32 = 3620000;
 //const FIRST_REC_VALUE: u32 = 3189000; // 24/03/2024
 /*
@@ -105,70 +716,180 @@ cat /sys/class/power_supply/BAT1/charge_full_design                          03/
 2893000
 */
 
-#[allow(unreachable_code)]
-pub fn health_stats(write_timer: u64) -> Result<(), Box<dyn Error>> {
-    notify_percentage("N/A", "health_stats is running");
-    /*
-    Write to a csv file with columns today's date, charge_full, charge_full_design, battery health
-    this last one is calculated as charge_full/charge_full_design
-     */
-    loop {
-        let battery_stats = BatteryStatistics::new();
-        let charge_full: f32 = battery_stats.charge_full.parse()?;
-        let charge_full_design: f32 = battery_stats.charge_full_design.parse()?;
-
-        // Calculate battery health
-        let battery_health = charge_full / charge_full_design * 100.0;
-        let battery_percentage = get_battery_percentage().expect("Failed getting batt percentage");
-        let battery_status = get_battery_state().expect("Failed getting battery status");
-
-        // Get today's date
-        let today = chrono::Local::now();
-
-        let now_date = format!("{}", today.format("%d/%m/%Y"));
-        let now_hour = format!("{}", today.format("%H:%M"));
-        let now_hour_as_float = {
-            let hours: f32 = format!("{}", today.format("%H")).parse().unwrap();
-            let minutes: f32 = format!("{}", today.format("%M")).parse().unwrap();
-
-            let minutes_in_hours = minutes / 60.0;
-
-            hours + minutes_in_hours
-        };
+/// Write a row to `history_store` when `write_policy` asks for one
+pub fn health_stats(
+    history_store: &SharedStore,
+    write_policy: WritePolicy,
+    snapshots: &Receiver<BatterySnapshot>,
+) -> Result<(), MyError> {
+    // The logger doesn't need the desktop notifications to work
+    if let Err(err) = notify_percentage("N/A", "health_stats is running") {
+        log::warn!("{err}");
+    }
+    let mut last_written: Option<BatterySnapshot> = None;
 
-        // Open or create the CSV file
-        let file_path = Path::new(DATA_FILE_PATH);
-        let mut file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(file_path)?;
+    // Every snapshot is received, the ones in between two rows are skipped
+    for snapshot in snapshots {
+        if !write_policy.should_write(last_written.as_ref(), &snapshot) {
+            continue;
+        }
+        history_store.append_snapshot(&HistoryRecord::from_snapshot(&snapshot))?;
+        last_written = Some(snapshot);
+        println!("Battery health stats written to {}", history_store.path().display());
+    }
+    Ok(())
+}
 
-        // Write headers if the file is newly created
-        if file.metadata()?.len() == 0 {
-            writeln!(
-                file,
-                "Date,Hour_str,Hour_f32,Charge_Full,Charge_Full_Design,Battery_Health,Battery_Percentage,Battery_Status"
-            )?;
+/// Log the energy used in every session on battery in `history_store`
+pub fn power_meter(
+    history_store: &SharedStore,
+    snapshots: &Receiver<BatterySnapshot>,
+) -> Result<(), MyError> {
+    let mut meter = SessionMeter::new();
+    let mut warned = false;
+
+    // Every snapshot is received, the energy is integrated between each of them
+    for snapshot in snapshots {
+        match snapshot.power() {
+            Some(watts) => log::debug!("Power draw: {watts:.2} W"),
+            None if !warned => {
+                log::warn!("The battery reports neither power_now nor voltage_now and current_now");
+                warned = true;
+            }
+            None => (),
+        }
+        if let Some(session) = meter.update(&snapshot) {
+            record_session(history_store, &session);
         }
+    }
+    // Stopping the daemon ends the session on battery too
+    if let Some(session) = meter.finish() {
+        record_session(history_store, &session);
+    }
+    Ok(())
+}
+
+fn record_session(history_store: &SharedStore, session: &store::PowerSession) {
+    log::info!(
+        "Session on battery: {:.2} Wh in {} min, {:.2} W on average, {:.1}% to {:.1}%",
+        session.energy_wh,
+        session.active.as_secs() / 60,
+        session.average_w(),
+        session.percentage_start,
+        session.percentage_end
+    );
+    history_store.record_power_session(session);
+}
 
-        // Write data to the CSV file
-        writeln!(
-            file,
-            "{now_date},{now_hour},{now_hour_as_float},{charge_full},{charge_full_design},{battery_health},{battery_percentage},{battery_status}"
-        )?;
+/// Count the battery cycles from the charge discharged, the counter is kept in `path`.
+/// A swapped battery gets a new counter, the old one is kept aside.
+pub fn cycle_counter(path: &Path, snapshots: &Receiver<BatterySnapshot>) -> Result<(), MyError> {
+    let mut counter = CycleCounter::load(path)?;
 
-        println!("Battery health stats written to {}", DATA_FILE_PATH);
-        thread::sleep(Duration::from_secs(write_timer * 60));
+    // Every snapshot is received, each discharge step is counted
+    for snapshot in snapshots {
+        let other_battery = counter
+            .as_mut()
+            .is_some_and(|counter| counter.is_other_battery(&snapshot));
+        if let Some(old_counter) = counter.take_if(|_| other_battery) {
+            let started = old_counter.started.format("%Y-%m-%d");
+            // Two packs swapped out on the same day don't overwrite each other
+            let mut old_path = path.with_extension(format!("cycles.{started}"));
+            let mut copy = 1;
+            while old_path.exists() {
+                old_path = path.with_extension(format!("cycles.{started}.{copy}"));
+                copy += 1;
+            }
+            fs::rename(path, &old_path)?;
+            log::info!("The old battery cycles are kept in {}", old_path.display());
+        }
+        let counter = match &mut counter {
+            Some(counter) => counter,
+            None => {
+                log::info!("Counting the battery cycles in {}", path.display());
+                let mut new_counter = CycleCounter::new(&snapshot);
+                new_counter.save(path)?;
+                counter.insert(new_counter)
+            }
+        };
+        let whole_cycles = counter.cycles().floor();
+        if counter.update(&snapshot, (DISCHARGE_LOWER_LIMIT, CHARGE_UPPER_LIMIT)) {
+            counter.save(path)?;
+        }
+        if counter.cycles().floor() > whole_cycles {
+            log::info!(
+                "Battery cycle {} counted, kernel cycle_count {}",
+                counter.cycles().floor(),
+                snapshot.cycle_count.map_or("N/A".to_owned(), |count| count.to_string())
+            );
+        }
+    }
+    if let Some(counter) = &mut counter {
+        counter.save(path)?;
     }
     Ok(())
 }
 
-/// Will notify and if enabled will also flash a script to move the stepper to connect the charger
-fn notifier(has_been_notified_80: &mut bool, has_been_notified_20: &mut bool) {
-    notify_percentage("N/A", "notifier is running");
-    loop {
-        let battery_state = BatteryState::match_string(&get_battery_state().unwrap());
-        let batt_percentage = get_battery_percentage().unwrap();
+/// The battery counted in `cycles_path` before the start, read once so that the cycle
+/// counter renaming it for a new battery can't race with the battery watcher
+pub fn previous_battery(cycles_path: &Path) -> Option<BatteryIdentity> {
+    // A broken cycles file only loses the battery from before the start
+    match CycleCounter::load(cycles_path) {
+        Ok(counter) => counter.and_then(|counter| counter.battery),
+        Err(err) => {
+            log::warn!("{err}");
+            None
+        }
+    }
+}
+
+/// Notify when the battery in `snapshots` is another one than the last seen. The first one is
+/// compared with `previous_battery`, a swap while the daemon was stopped is noticed too.
+/// Without `desktop` the notification is only logged.
+pub fn battery_watcher(
+    previous_battery: Option<BatteryIdentity>,
+    snapshots: &Receiver<BatterySnapshot>,
+    history_store: &SharedStore,
+    desktop: bool,
+) -> Result<(), MyError> {
+    let mut battery = previous_battery;
+    for snapshot in snapshots {
+        let Some(identity) = snapshot.identity else {
+            continue;
+        };
+        if battery.as_ref().is_some_and(|battery| *battery != identity) {
+            log::info!("New battery {identity}");
+            notify(history_store, desktop, "nuova", &format!("Nuova batteria rilevata: {identity}"));
+        }
+        battery = Some(identity);
+    }
+    Ok(())
+}
+
+/// Will notify and if enabled will also flash a script to move the stepper to connect the charger.
+/// When the battery stays under the critical level the configured critical action is run.
+/// Without `desktop` the notifications are only logged, for the threshold hook.
+pub fn notifier(
+    snapshots: &Receiver<BatterySnapshot>,
+    has_been_notified_80: &mut bool,
+    has_been_notified_20: &mut bool,
+    critical_policy: &mut CriticalPolicy,
+    supervisor: &Supervisor,
+    history_store: &SharedStore,
+    desktop: bool,
+) -> Result<(), MyError> {
+    if desktop {
+        if let Err(err) = notify_percentage("N/A", "notifier is running") {
+            log::warn!("{err}");
+        }
+    }
+    let mut estimator = RuntimeEstimator::new(CHARGE_UPPER_LIMIT);
+    // After the low battery notification, tell when the charge will be back at the limit
+    let mut announce_charge = false;
+    while let Some(snapshot) = recv_latest(snapshots) {
+        let estimate = estimator.update(&snapshot);
+        let battery_state = snapshot.state;
+        let batt_percentage = snapshot.percentage();
         let to_notify_80 = batt_percentage >= CHARGE_UPPER_LIMIT
             && !*has_been_notified_80
             && battery_state == BatteryState::Charging;
@@ -176,15 +897,78 @@ fn notifier(has_been_notified_80: &mut bool, has_been_notified_20: &mut bool) {
             && !*has_been_notified_20
             && battery_state == BatteryState::Discharging;
 
+        // Remind the user that some worker, maybe the charge control, is not running
+        let degraded = if supervisor.is_degraded() {
+            format!("\n{}", supervisor.status())
+        } else {
+            String::new()
+        };
+
         if to_notify_80 {
-            notify_percentage("80%", "Sconnetti il caricatore!!");
+            notify(history_store, desktop, "80%", &format!("Sconnetti il caricatore!!{degraded}"));
             *has_been_notified_80 = true;
             *has_been_notified_20 = false;
         } else if to_notify_20 {
-            notify_percentage("20%", "Connetti il caricatore!!");
+            let runtime = match estimate {
+                Some(Estimate::Empty(remaining)) => {
+                    format!("\nScarica tra {}", format_remaining(remaining))
+                }
+                _ => String::new(),
+            };
+            notify(history_store, desktop, "20%", &format!("Connetti il caricatore!!{runtime}{degraded}"));
             *has_been_notified_20 = true;
             *has_been_notified_80 = false;
+            announce_charge = true;
+        }
+        if let (true, Some(Estimate::Limit(limit, remaining))) = (announce_charge, estimate) {
+            notify(
+                history_store,
+                desktop,
+                &format!("{batt_percentage:.0}%"),
+                &format!("{limit}% tra {}", format_remaining(remaining)),
+            );
+            announce_charge = false;
+        }
+
+        let critical_level = format!("{}%", critical_policy.level());
+        match critical_policy.check(
+            batt_percentage,
+            &battery_state,
+            Instant::now(),
+            &SystemRunner,
+        ) {
+            CriticalStatus::Entered => {
+                notify(history_store, desktop, &critical_level, &critical_policy.warning_message())
+            }
+            CriticalStatus::Triggered => {
+                log::info!("Critical action executed at {batt_percentage}%")
+            }
+            CriticalStatus::Failed(err) => {
+                log::error!("Critical action failed: {err}");
+                notify(
+                    history_store,
+                    desktop,
+                    &critical_level,
+                    "Azione critica fallita, connetti il caricatore!!",
+                );
+            }
+            CriticalStatus::Normal | CriticalStatus::Waiting => (),
+        }
+    }
+    Ok(())
+}
+
+/// Log the notification in `history_store`, which runs the threshold hook, and send it
+/// to the desktop if `desktop`. A headless box without notify-send still gets the hook
+fn notify(history_store: &SharedStore, desktop: bool, level: &str, message: &str) {
+    history_store.record_notification(&NotificationEvent {
+        time: chrono::Local::now().fixed_offset(),
+        level: level.to_owned(),
+        message: message.to_owned(),
+    });
+    if desktop {
+        if let Err(err) = notify_percentage(level, message) {
+            log::warn!("{err}");
         }
-        thread::sleep(Duration::from_secs(BATTERY_CHECK_TIME))
     }
 }
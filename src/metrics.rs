@@ -0,0 +1,322 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::ardu::{ArduSketch, ChargerMode};
+use super::battery_health::{BatterySnapshot, BatteryState};
+use super::store::{ActuatorEvent, EventObserver, NotificationEvent};
+use super::utils::MyError;
+
+/// How often the exporter looks for new snapshots and connections
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// A scraper that doesn't send its request and read the answer in time is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_REQUEST: usize = 8192;
+const PROMETHEUS_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+const OPENMETRICS_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+const STATES: [BatteryState; 4] = [
+    BatteryState::Charging,
+    BatteryState::Discharging,
+    BatteryState::Full,
+    BatteryState::NotCharging,
+];
+const COMMANDS: [ArduSketch; 3] = [
+    ArduSketch::Connect,
+    ArduSketch::Disconnect,
+    ArduSketch::DoNothing,
+];
+const MODES: [ChargerMode; 3] = [
+    ChargerMode::Auto,
+    ChargerMode::Connect,
+    ChargerMode::Disconnect,
+];
+
+#[derive(Default)]
+struct Values {
+    snapshot: Option<BatterySnapshot>,
+    /// By command name
+    commands: BTreeMap<String, u64>,
+    failures: BTreeMap<String, u64>,
+    /// By notification level
+    notifications: BTreeMap<String, u64>,
+    /// As decided by the controller, before the battery reacts
+    command: Option<ArduSketch>,
+    mode: Option<ChargerMode>,
+}
+
+/// The values served by the exporter, shared by the workers that update them
+#[derive(Clone, Default)]
+pub struct Metrics {
+    values: Arc<Mutex<Values>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Values> {
+        self.values.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub fn observe_snapshot(&self, snapshot: &BatterySnapshot) {
+        self.lock().snapshot = Some(snapshot.clone());
+    }
+
+    /// The metrics in the Prometheus text format, or in OpenMetrics if `openmetrics`
+    pub fn render(&self, openmetrics: bool) -> String {
+        let values = self.lock();
+        let mut text = String::new();
+
+        if let Some(snapshot) = &values.snapshot {
+            let gauges = [
+                (
+                    "battery_percent",
+                    "Charge of the battery in percent",
+                    Some(f64::from(snapshot.percentage())),
+                ),
+                (
+                    "battery_health_percent",
+                    "charge_full over charge_full_design in percent",
+                    Some(f64::from(snapshot.health())),
+                ),
+                (
+                    "battery_voltage_volts",
+                    "voltage_now of the battery",
+                    snapshot.voltage_now.map(|voltage| f64::from(voltage) / 1e6),
+                ),
+                (
+                    "battery_current_amperes",
+                    "current_now of the battery, charging or discharging",
+                    snapshot.current_now.map(|current| f64::from(current) / 1e6),
+                ),
+                (
+                    "battery_power_watts",
+                    "Power drawn from or into the battery",
+                    snapshot.power(),
+                ),
+            ];
+            for (name, help, value) in gauges {
+                if let Some(value) = value {
+                    family(&mut text, name, "gauge", help, openmetrics);
+                    let _ = writeln!(text, "energy_monitor_{name} {value}");
+                }
+            }
+
+            family(
+                &mut text,
+                "battery_state",
+                "gauge",
+                "1 for the status reported by the battery",
+                openmetrics,
+            );
+            for state in STATES {
+                let _ = writeln!(
+                    text,
+                    "energy_monitor_battery_state{{state=\"{}\"}} {}",
+                    escape(&state.to_string()),
+                    u8::from(state == snapshot.state)
+                );
+            }
+        }
+
+        family(
+            &mut text,
+            "controller_command",
+            "gauge",
+            "1 for the command the controller is running, also while it waits for the battery",
+            openmetrics,
+        );
+        for command in COMMANDS {
+            let _ = writeln!(
+                text,
+                "energy_monitor_controller_command{{command=\"{command}\"}} {}",
+                u8::from(values.command == Some(command))
+            );
+        }
+        family(
+            &mut text,
+            "controller_mode",
+            "gauge",
+            "1 for the charger mode of the controller, set over MQTT",
+            openmetrics,
+        );
+        for mode in MODES {
+            let _ = writeln!(
+                text,
+                "energy_monitor_controller_mode{{mode=\"{mode}\"}} {}",
+                u8::from(values.mode == Some(mode))
+            );
+        }
+
+        let counters = [
+            (
+                "actuator_commands",
+                "Sketches flashed by the controller",
+                "command",
+                &values.commands,
+            ),
+            (
+                "actuator_failures",
+                "Sketches that failed to flash or to change the battery state",
+                "command",
+                &values.failures,
+            ),
+            (
+                "notifications",
+                "Desktop notifications sent",
+                "level",
+                &values.notifications,
+            ),
+        ];
+        for (name, help, label, counts) in counters {
+            family(&mut text, name, "counter", help, openmetrics);
+            for (value, count) in counts {
+                let _ = writeln!(
+                    text,
+                    "energy_monitor_{name}_total{{{label}=\"{}\"}} {count}",
+                    escape(value)
+                );
+            }
+        }
+
+        if openmetrics {
+            text.push_str("# EOF\n");
+        }
+        text
+    }
+}
+
+impl EventObserver for Metrics {
+    fn actuator_event(&self, event: &ActuatorEvent) {
+        let mut values = self.lock();
+        let command = event.command.to_string();
+        *values.commands.entry(command.clone()).or_default() += 1;
+        if event.error.is_some() {
+            *values.failures.entry(command).or_default() += 1;
+        }
+    }
+
+    fn decision(&self, command: ArduSketch, mode: ChargerMode) {
+        let mut values = self.lock();
+        values.command = Some(command);
+        values.mode = Some(mode);
+    }
+
+    fn notification(&self, event: &NotificationEvent) {
+        *self
+            .lock()
+            .notifications
+            .entry(event.level.clone())
+            .or_default() += 1;
+    }
+}
+
+/// The HELP and TYPE lines of a metric, counters end in _total only in the Prometheus format
+fn family(text: &mut String, name: &str, kind: &str, help: &str, openmetrics: bool) {
+    let name = match kind {
+        "counter" if !openmetrics => format!("energy_monitor_{name}_total"),
+        _ => format!("energy_monitor_{name}"),
+    };
+    let _ = writeln!(text, "# HELP {name} {help}");
+    let _ = writeln!(text, "# TYPE {name} {kind}");
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve the metrics on `listener` at /metrics, updated from `snapshots`, until the
+/// sampler closes the channel
+pub fn serve(
+    listener: &TcpListener,
+    metrics: &Metrics,
+    snapshots: &Receiver<BatterySnapshot>,
+) -> Result<(), MyError> {
+    listener.set_nonblocking(true)?;
+    loop {
+        loop {
+            match snapshots.try_recv() {
+                Ok(snapshot) => metrics.observe_snapshot(&snapshot),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+
+        match listener.accept() {
+            Ok((stream, address)) => {
+                // One at a time, a slow scraper holds the others back for REQUEST_TIMEOUT at most
+                if let Err(err) = respond(stream, metrics) {
+                    log::warn!("Failed answering {address}: {err}");
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> Result<(), MyError> {
+    stream.set_nonblocking(false)?;
+    // The timeouts are for a single read or write, a byte at a time would go on forever
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    let remaining = || {
+        deadline
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero())
+            .ok_or_else(|| std::io::Error::from(ErrorKind::TimedOut))
+    };
+
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        stream.set_read_timeout(Some(remaining()?))?;
+        let read = stream.read(&mut buffer)?;
+        if read == 0 || request.len() > MAX_REQUEST {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+
+    let (status, content_type, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let openmetrics = request.lines().any(|line| {
+                let line = line.to_ascii_lowercase();
+                line.starts_with("accept:") && line.contains("application/openmetrics-text")
+            });
+            match openmetrics {
+                true => ("200 OK", OPENMETRICS_TYPE, metrics.render(true)),
+                false => ("200 OK", PROMETHEUS_TYPE, metrics.render(false)),
+            }
+        }
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "Try /metrics\n".to_owned()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Only GET is supported\n".to_owned(),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let mut response = response.as_bytes();
+    while !response.is_empty() {
+        stream.set_write_timeout(Some(remaining()?))?;
+        match stream.write(response)? {
+            0 => return Err(std::io::Error::from(ErrorKind::WriteZero).into()),
+            written => response = &response[written..],
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,423 @@
+use std::fmt;
+use std::fs;
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::time::Duration;
+
+use chrono::SecondsFormat;
+use rumqttc::{Client, Connection, Event, LastWill, MqttOptions, Packet, QoS, RecvTimeoutError};
+use serde_json::json;
+
+use super::ardu::{ArduSketch, ChargerMode, SharedMode};
+use super::battery_health::BatterySnapshot;
+use super::store::{ActuatorEvent, EventObserver};
+use super::utils::MyError;
+
+const DEFAULT_PORT: u16 = 1883;
+pub const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+/// How often the bridge looks for new snapshots and events between two packets
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+/// Publications waiting for the broker, the newer ones are dropped past this
+const QUEUE_CAPACITY: usize = 64;
+
+/// The broker and the Home Assistant discovery prefix
+#[derive(Clone, PartialEq)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub discovery_prefix: String,
+}
+
+impl MqttConfig {
+    /// `host` or `host:port`
+    pub fn new(broker: &str) -> Result<Self, MyError> {
+        let (host, port) = match broker.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse::<u16>().map_err(|err| {
+                    MyError::ConfigError(format!("Invalid port in mqtt_broker '{broker}': {err}"))
+                })?,
+            ),
+            None => (broker, DEFAULT_PORT),
+        };
+        if host.is_empty() {
+            return Err(MyError::ConfigError(format!(
+                "mqtt_broker '{broker}' has no host"
+            )));
+        }
+        Ok(MqttConfig {
+            host: host.to_owned(),
+            port,
+            username: None,
+            password: None,
+            discovery_prefix: DEFAULT_DISCOVERY_PREFIX.to_owned(),
+        })
+    }
+}
+
+// The config is printed when the daemon starts, the password isn't
+impl fmt::Debug for MqttConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MqttConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "***"))
+            .field("discovery_prefix", &self.discovery_prefix)
+            .finish()
+    }
+}
+
+/// The topics of one laptop, under energy_monitor/<node id>
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topics {
+    pub node_id: String,
+    base: String,
+    discovery_prefix: String,
+}
+
+impl Topics {
+    /// `node_id` is reduced to the characters allowed in discovery topics
+    pub fn new(node_id: &str, discovery_prefix: &str) -> Self {
+        let node_id = node_id
+            .trim()
+            .chars()
+            .map(|char| match char {
+                'a'..='z' | '0'..='9' | '_' | '-' => char,
+                'A'..='Z' => char.to_ascii_lowercase(),
+                _ => '_',
+            })
+            .collect::<String>();
+        Topics {
+            base: format!("energy_monitor/{node_id}"),
+            node_id,
+            discovery_prefix: discovery_prefix.to_owned(),
+        }
+    }
+
+    /// JSON of every snapshot
+    pub fn state(&self) -> String {
+        format!("{}/state", self.base)
+    }
+
+    /// JSON of every actuator event
+    pub fn event(&self) -> String {
+        format!("{}/event", self.base)
+    }
+
+    /// ON or OFF, where the controller wants the charger
+    pub fn charger(&self) -> String {
+        format!("{}/charger", self.base)
+    }
+
+    pub fn mode(&self) -> String {
+        format!("{}/mode", self.base)
+    }
+
+    /// auto, connect or disconnect, sent by Home Assistant
+    pub fn mode_set(&self) -> String {
+        format!("{}/mode/set", self.base)
+    }
+
+    /// online or offline, the broker publishes offline if the daemon disappears
+    pub fn availability(&self) -> String {
+        format!("{}/availability", self.base)
+    }
+
+    fn discovery(&self, component: &str, object_id: &str) -> String {
+        format!(
+            "{}/{component}/{}/{object_id}/config",
+            self.discovery_prefix, self.node_id
+        )
+    }
+}
+
+/// The hostname, so that every laptop on the broker has its own topics
+pub fn node_id() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .map(|hostname| hostname.trim().to_owned())
+        .filter(|hostname| !hostname.is_empty())
+        .unwrap_or_else(|| "laptop".to_owned())
+}
+
+/// A packet from the broker the bridge cares about
+#[derive(Debug, Clone, PartialEq)]
+pub enum Incoming {
+    /// Connected or reconnected, subscriptions and retained messages must be sent again
+    Connected,
+    Message {
+        topic: String,
+        payload: String,
+    },
+}
+
+/// The connection to the broker, a recorded one in the tests
+pub trait MqttLink {
+    fn publish(&mut self, topic: &str, payload: &str, retain: bool) -> Result<(), MyError>;
+
+    fn subscribe(&mut self, topic: &str) -> Result<(), MyError>;
+
+    /// The next packet, None if there is none within `timeout`
+    fn poll(&mut self, timeout: Duration) -> Result<Option<Incoming>, MyError>;
+}
+
+/// A broker reached with rumqttc, it reconnects on the next poll after an error
+pub struct BrokerLink {
+    client: Client,
+    connection: Connection,
+}
+
+impl BrokerLink {
+    /// Nothing is sent before the first poll
+    pub fn new(config: &MqttConfig, topics: &Topics) -> Self {
+        let mut options = MqttOptions::new(
+            format!("energy_monitor_{}", topics.node_id),
+            &config.host,
+            config.port,
+        );
+        options
+            .set_keep_alive(KEEP_ALIVE)
+            .set_last_will(LastWill::new(
+                topics.availability(),
+                "offline",
+                QoS::AtLeastOnce,
+                true,
+            ));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.clone().unwrap_or_default());
+        }
+        let (client, connection) = Client::new(options, QUEUE_CAPACITY);
+        BrokerLink { client, connection }
+    }
+}
+
+impl MqttLink for BrokerLink {
+    fn publish(&mut self, topic: &str, payload: &str, retain: bool) -> Result<(), MyError> {
+        // Never blocks, a broker that is away for long loses the newest states
+        self.client
+            .try_publish(topic, QoS::AtLeastOnce, retain, payload)
+            .map_err(|err| MyError::MqttError(format!("Failed to publish on {topic}: {err}")))
+    }
+
+    fn subscribe(&mut self, topic: &str) -> Result<(), MyError> {
+        self.client
+            .try_subscribe(topic, QoS::AtLeastOnce)
+            .map_err(|err| MyError::MqttError(format!("Failed to subscribe to {topic}: {err}")))
+    }
+
+    fn poll(&mut self, timeout: Duration) -> Result<Option<Incoming>, MyError> {
+        match self.connection.recv_timeout(timeout) {
+            Ok(Ok(Event::Incoming(Packet::ConnAck(_)))) => Ok(Some(Incoming::Connected)),
+            Ok(Ok(Event::Incoming(Packet::Publish(publish)))) => Ok(Some(Incoming::Message {
+                topic: publish.topic,
+                payload: String::from_utf8_lossy(&publish.payload).into_owned(),
+            })),
+            Ok(Ok(_)) | Err(RecvTimeoutError::Timeout) => Ok(None),
+            Ok(Err(err)) => Err(MyError::MqttError(format!(
+                "Connection to the broker: {err}"
+            ))),
+            Err(RecvTimeoutError::Disconnected) => Err(MyError::MqttError(
+                "The connection to the broker was closed".to_owned(),
+            )),
+        }
+    }
+}
+
+/// The Home Assistant discovery configs of the sensors, the charger and the mode
+/// select, as (topic, payload)
+pub fn discovery_messages(topics: &Topics) -> Vec<(String, String)> {
+    let device = json!({
+        "identifiers": [format!("energy_monitor_{}", topics.node_id)],
+        "name": format!("energy_monitor {}", topics.node_id),
+    });
+    let entity = |component: &str, object_id: &str, name: &str, mut config: serde_json::Value| {
+        config["name"] = json!(name);
+        config["unique_id"] = json!(format!("energy_monitor_{}_{object_id}", topics.node_id));
+        config["availability_topic"] = json!(topics.availability());
+        config["device"] = device.clone();
+        (topics.discovery(component, object_id), config.to_string())
+    };
+    let sensor = |object_id: &str, name: &str, field: &str, unit: &str, class: Option<&str>| {
+        let mut config = json!({
+            "state_topic": topics.state(),
+            "value_template": format!("{{{{ value_json.{field} }}}}"),
+            "unit_of_measurement": unit,
+            "state_class": "measurement",
+        });
+        if let Some(class) = class {
+            config["device_class"] = json!(class);
+        }
+        entity("sensor", object_id, name, config)
+    };
+
+    vec![
+        sensor("battery", "Battery", "percentage", "%", Some("battery")),
+        sensor("health", "Battery health", "health", "%", None),
+        sensor("power", "Battery power", "power", "W", Some("power")),
+        sensor(
+            "voltage",
+            "Battery voltage",
+            "voltage",
+            "V",
+            Some("voltage"),
+        ),
+        sensor(
+            "current",
+            "Battery current",
+            "current",
+            "A",
+            Some("current"),
+        ),
+        entity(
+            "sensor",
+            "status",
+            "Battery status",
+            json!({
+                "state_topic": topics.state(),
+                "value_template": "{{ value_json.status }}",
+            }),
+        ),
+        entity(
+            "binary_sensor",
+            "charger",
+            "Charger",
+            json!({
+                "state_topic": topics.charger(),
+                "payload_on": "ON",
+                "payload_off": "OFF",
+                "device_class": "plug",
+            }),
+        ),
+        entity(
+            "select",
+            "mode",
+            "Charger mode",
+            json!({
+                "state_topic": topics.mode(),
+                "command_topic": topics.mode_set(),
+                "options": ["auto", "connect", "disconnect"],
+            }),
+        ),
+    ]
+}
+
+pub fn state_payload(snapshot: &BatterySnapshot) -> String {
+    json!({
+        "time": snapshot.time.to_rfc3339_opts(SecondsFormat::Secs, false),
+        "percentage": snapshot.percentage(),
+        "status": snapshot.state.to_string(),
+        "health": snapshot.health(),
+        "power": snapshot.power(),
+        "voltage": snapshot.voltage_now.map(|voltage| f64::from(voltage) / 1e6),
+        "current": snapshot.current_now.map(|current| f64::from(current) / 1e6),
+    })
+    .to_string()
+}
+
+pub fn event_payload(event: &ActuatorEvent) -> String {
+    json!({
+        "time": event.time.to_rfc3339_opts(SecondsFormat::Secs, false),
+        "command": event.command.to_string(),
+        "error": event.error,
+        "latency_ms": event.latency.as_millis() as u64,
+        "percentage_before": event.percentage_before,
+        "percentage_after": event.percentage_after,
+    })
+    .to_string()
+}
+
+/// What the bridge hears from the controller
+#[derive(Debug, Clone)]
+pub enum ControllerEvent {
+    /// Before the command is run, a plug following the charger topic switches in time
+    Decision(ArduSketch),
+    Outcome(ActuatorEvent),
+}
+
+/// Sends the decisions and the events recorded in the store to the bridge
+pub struct EventForwarder(pub Sender<ControllerEvent>);
+
+impl EventObserver for EventForwarder {
+    fn actuator_event(&self, event: &ActuatorEvent) {
+        // The bridge is gone only when the daemon is stopping
+        let _ = self.0.send(ControllerEvent::Outcome(event.clone()));
+    }
+
+    fn decision(&self, command: ArduSketch, _mode: ChargerMode) {
+        let _ = self.0.send(ControllerEvent::Decision(command));
+    }
+}
+
+/// Publish the snapshots and the controller events on `link` and take the charger
+/// mode from it, until the sampler closes the channel
+pub fn bridge<L: MqttLink>(
+    link: &mut L,
+    topics: &Topics,
+    snapshots: &Receiver<BatterySnapshot>,
+    events: &Receiver<ControllerEvent>,
+    mode: &SharedMode,
+) -> Result<(), MyError> {
+    loop {
+        let mut latest = None;
+        loop {
+            match snapshots.try_recv() {
+                Ok(snapshot) => latest = Some(snapshot),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    return link.publish(&topics.availability(), "offline", true);
+                }
+            }
+        }
+        if let Some(snapshot) = latest {
+            publish(link, &topics.state(), &state_payload(&snapshot), false);
+        }
+        while let Ok(event) = events.try_recv() {
+            // The decision counts, not the flash: a plug following it works without the arduino
+            match event {
+                ControllerEvent::Decision(ArduSketch::Connect) => {
+                    publish(link, &topics.charger(), "ON", true)
+                }
+                ControllerEvent::Decision(ArduSketch::Disconnect) => {
+                    publish(link, &topics.charger(), "OFF", true)
+                }
+                ControllerEvent::Decision(ArduSketch::DoNothing) => (),
+                ControllerEvent::Outcome(event) => {
+                    publish(link, &topics.event(), &event_payload(&event), false)
+                }
+            }
+        }
+
+        match link.poll(POLL_INTERVAL)? {
+            Some(Incoming::Connected) => {
+                log::info!("Connected to the MQTT broker");
+                for (topic, payload) in discovery_messages(topics) {
+                    publish(link, &topic, &payload, true);
+                }
+                publish(link, &topics.availability(), "online", true);
+                publish(link, &topics.mode(), &mode.get().to_string(), true);
+                link.subscribe(&topics.mode_set())?;
+            }
+            Some(Incoming::Message { topic, payload }) if topic == topics.mode_set() => {
+                match ChargerMode::match_string(&payload.trim().to_lowercase()) {
+                    Ok(new_mode) => {
+                        log::info!("Charger mode set to {new_mode} over MQTT");
+                        mode.set(new_mode);
+                        publish(link, &topics.mode(), &new_mode.to_string(), true);
+                    }
+                    Err(err) => log::warn!("Ignoring {topic}: {err}"),
+                }
+            }
+            Some(Incoming::Message { .. }) | None => (),
+        }
+    }
+}
+
+/// A lost state or event isn't worth restarting the bridge
+fn publish<L: MqttLink>(link: &mut L, topic: &str, payload: &str, retain: bool) {
+    if let Err(err) = link.publish(topic, payload, retain) {
+        log::warn!("{err}");
+    }
+}
@@ -0,0 +1,482 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+
+use chrono::{DateTime, Duration, FixedOffset, NaiveDate};
+use plotters::coord::Shift;
+use plotters::prelude::*;
+
+use super::battery_health::BatteryState;
+use super::history::HistoryRecord;
+use super::utils::MyError;
+
+/// Longer gaps between two records break the charge line, the laptop was off
+const MAX_GAP: Duration = Duration::hours(1);
+const IMAGE_SIZE: (u32, u32) = (1200, 800);
+/// Every state in the legend order, None for the oldest rows without one
+const STATES: [Option<BatteryState>; 5] = [
+    Some(BatteryState::Charging),
+    Some(BatteryState::Discharging),
+    Some(BatteryState::Full),
+    Some(BatteryState::NotCharging),
+    None,
+];
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PlotFormat {
+    Svg,
+    Png,
+}
+
+impl PlotFormat {
+    /// From the extension of the output file
+    pub fn from_path(path: &Path) -> Result<Self, MyError> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("svg") => Ok(Self::Svg),
+            Some("png") => Ok(Self::Png),
+            _ => Err(MyError::ConfigError(format!(
+                "Can't plot to {}, the file must end in .svg or .png",
+                path.display()
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChargePoint {
+    pub time: DateTime<FixedOffset>,
+    pub percentage: f32,
+    pub state: Option<BatteryState>,
+}
+
+/// The history with a percentage, oldest first, limited to the `span` before the
+/// newest record
+pub fn charge_points(records: &[HistoryRecord], span: Option<Duration>) -> Vec<ChargePoint> {
+    let mut points = records
+        .iter()
+        .filter_map(|record| {
+            Some(ChargePoint {
+                time: record.time,
+                percentage: record.battery_percentage?,
+                state: record.battery_status,
+            })
+        })
+        .collect::<Vec<_>>();
+    points.sort_by_key(|point| point.time);
+    points.dedup_by_key(|point| point.time);
+    // A span going back before the calendar keeps everything
+    let from = span
+        .zip(points.last())
+        .and_then(|(span, newest)| newest.time.checked_sub_signed(span));
+    if let Some(from) = from {
+        points.retain(|point| point.time >= from);
+    }
+    points
+}
+
+/// Average health of every day in the history, oldest first, at the first record of the day
+pub fn daily_health(records: &[HistoryRecord]) -> Vec<(DateTime<FixedOffset>, f32)> {
+    let mut days: BTreeMap<NaiveDate, (DateTime<FixedOffset>, f64, u32)> = BTreeMap::new();
+    for record in records {
+        let day = days
+            .entry(record.time.date_naive())
+            .or_insert((record.time, 0.0, 0));
+        day.0 = day.0.min(record.time);
+        day.1 += f64::from(record.battery_health);
+        day.2 += 1;
+    }
+    days.into_values()
+        .map(|(time, sum, count)| (time, (sum / f64::from(count)) as f32))
+        .collect()
+}
+
+/// A line of the charge chart and the state it is colored by
+type StateRun = (Option<BatteryState>, Vec<(DateTime<FixedOffset>, f32)>);
+
+/// Consecutive points with the same state, each line ends at the first point of the
+/// next one. Points without a neighbour within MAX_GAP are a run of their own.
+fn state_runs(points: &[ChargePoint]) -> Vec<StateRun> {
+    let mut runs: Vec<StateRun> = Vec::new();
+    for pair in points.windows(2) {
+        let (current, next) = (&pair[0], &pair[1]);
+        if next.time - current.time > MAX_GAP {
+            continue;
+        }
+        match runs.last_mut() {
+            Some((state, line))
+                if *state == current.state
+                    && line.last() == Some(&(current.time, current.percentage)) =>
+            {
+                line.push((next.time, next.percentage))
+            }
+            _ => runs.push((
+                current.state,
+                vec![
+                    (current.time, current.percentage),
+                    (next.time, next.percentage),
+                ],
+            )),
+        }
+    }
+    for (index, point) in points.iter().enumerate() {
+        let near = |other: Option<&ChargePoint>| {
+            other.is_some_and(|other| (point.time - other.time).abs() <= MAX_GAP)
+        };
+        if !near(index.checked_sub(1).map(|previous| &points[previous]))
+            && !near(points.get(index + 1))
+        {
+            runs.push((point.state, vec![(point.time, point.percentage)]));
+        }
+    }
+    runs
+}
+
+fn state_name(state: Option<BatteryState>) -> String {
+    state.map_or("Unknown".to_owned(), |state| state.to_string())
+}
+
+fn state_color(state: Option<BatteryState>) -> RGBColor {
+    match state {
+        Some(BatteryState::Charging) => RGBColor(46, 160, 67),
+        Some(BatteryState::Discharging) => RGBColor(214, 39, 40),
+        Some(BatteryState::Full) => RGBColor(31, 119, 180),
+        Some(BatteryState::NotCharging) => RGBColor(230, 145, 30),
+        None => RGBColor(127, 127, 127),
+    }
+}
+
+/// ANSI foreground color of the terminal chart
+fn state_ansi(state: Option<BatteryState>) -> &'static str {
+    match state {
+        Some(BatteryState::Charging) => "\x1b[32m",
+        Some(BatteryState::Discharging) => "\x1b[31m",
+        Some(BatteryState::Full) => "\x1b[34m",
+        Some(BatteryState::NotCharging) => "\x1b[33m",
+        None => "\x1b[90m",
+    }
+}
+
+/// Plot the charge of the last `span` and the daily health of the whole history
+/// to an SVG or PNG file, depending on the extension of `path`. The old histories
+/// without a percentage only get the health
+pub fn plot_file(
+    records: &[HistoryRecord],
+    path: &Path,
+    span: Option<Duration>,
+) -> Result<(), MyError> {
+    let charge = charge_points(records, span);
+    let health = daily_health(records);
+    if charge.is_empty() && health.is_empty() {
+        return Err(MyError::HealthStatsError(
+            "The history has nothing to plot".to_owned(),
+        ));
+    }
+
+    let failed = |err: &dyn std::fmt::Display| {
+        MyError::HealthStatsError(format!("Failed to plot {}: {err}", path.display()))
+    };
+    match PlotFormat::from_path(path)? {
+        PlotFormat::Svg => draw(
+            SVGBackend::new(path, IMAGE_SIZE).into_drawing_area(),
+            &charge,
+            &health,
+        )
+        .map_err(|err| failed(&err)),
+        PlotFormat::Png => draw(
+            BitMapBackend::new(path, IMAGE_SIZE).into_drawing_area(),
+            &charge,
+            &health,
+        )
+        .map_err(|err| failed(&err)),
+    }
+}
+
+/// A time range plotters can draw, at least a minute long
+fn time_range(
+    from: DateTime<FixedOffset>,
+    to: DateTime<FixedOffset>,
+) -> std::ops::Range<DateTime<FixedOffset>> {
+    from..to.max(from + Duration::minutes(1))
+}
+
+fn draw<DB: DrawingBackend>(
+    root: DrawingArea<DB, Shift>,
+    charge: &[ChargePoint],
+    health: &[(DateTime<FixedOffset>, f32)],
+) -> Result<(), DrawingAreaErrorKind<DB::ErrorType>> {
+    root.fill(&WHITE)?;
+    if charge.is_empty() {
+        draw_health(&root, health)?;
+    } else {
+        let (upper, lower) = root.split_vertically(IMAGE_SIZE.1 * 3 / 5);
+        draw_charge(&upper, charge)?;
+        draw_health(&lower, health)?;
+    }
+    root.present()
+}
+
+fn draw_charge<DB: DrawingBackend>(
+    area: &DrawingArea<DB, Shift>,
+    charge: &[ChargePoint],
+) -> Result<(), DrawingAreaErrorKind<DB::ErrorType>> {
+    let mut chart = ChartBuilder::on(area)
+        .caption("Charge", ("sans-serif", 24))
+        .margin(10)
+        .x_label_area_size(30)
+        .y_label_area_size(50)
+        .build_cartesian_2d(
+            time_range(charge[0].time, charge[charge.len() - 1].time),
+            0f32..100f32,
+        )?;
+    chart
+        .configure_mesh()
+        .x_labels(8)
+        .x_label_formatter(&|time| time.format("%d/%m %H:%M").to_string())
+        .y_desc("%")
+        .draw()?;
+    let mut labelled = Vec::new();
+    for (state, line) in state_runs(charge) {
+        let color = state_color(state);
+        let series = match line[..] {
+            [point] => chart.draw_series([Circle::new(point, 3, color.filled())])?,
+            _ => chart.draw_series(LineSeries::new(line, color.stroke_width(2)))?,
+        };
+        if !labelled.contains(&state) {
+            labelled.push(state);
+            series.label(state_name(state)).legend(move |(x, y)| {
+                PathElement::new(vec![(x, y), (x + 20, y)], color.stroke_width(2))
+            });
+        }
+    }
+    chart
+        .configure_series_labels()
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .draw()?;
+    Ok(())
+}
+
+fn draw_health<DB: DrawingBackend>(
+    area: &DrawingArea<DB, Shift>,
+    health: &[(DateTime<FixedOffset>, f32)],
+) -> Result<(), DrawingAreaErrorKind<DB::ErrorType>> {
+    if let (Some(first), Some(last)) = (health.first(), health.last()) {
+        let lowest = health
+            .iter()
+            .map(|(_, health)| *health)
+            .fold(f32::INFINITY, f32::min);
+        let highest = health
+            .iter()
+            .map(|(_, health)| *health)
+            .fold(f32::NEG_INFINITY, f32::max);
+        let mut chart = ChartBuilder::on(area)
+            .caption("Battery health", ("sans-serif", 24))
+            .margin(10)
+            .x_label_area_size(30)
+            .y_label_area_size(50)
+            .build_cartesian_2d(time_range(first.0, last.0), (lowest - 1.0)..(highest + 1.0))?;
+        chart
+            .configure_mesh()
+            .x_labels(8)
+            .x_label_formatter(&|time| time.format("%d/%m/%Y").to_string())
+            .y_desc("%")
+            .draw()?;
+        chart.draw_series(LineSeries::new(
+            health.iter().copied(),
+            BLUE.stroke_width(2),
+        ))?;
+    }
+    Ok(())
+}
+
+/// A column of the terminal chart: the average value and the most frequent state
+type Column = Option<(f32, Option<BatteryState>)>;
+
+fn columns<I>(
+    points: I,
+    from: DateTime<FixedOffset>,
+    to: DateTime<FixedOffset>,
+    width: usize,
+) -> Vec<Column>
+where
+    I: IntoIterator<Item = (DateTime<FixedOffset>, f32, Option<BatteryState>)>,
+{
+    let span = (to - from).num_seconds().max(1) as f64;
+    let mut buckets = vec![(0.0, 0, [0; STATES.len()]); width];
+    for (time, value, state) in points {
+        if time < from || time > to || width == 0 {
+            continue;
+        }
+        let column =
+            (((time - from).num_seconds() as f64 / span * width as f64) as usize).min(width - 1);
+        let bucket = &mut buckets[column];
+        bucket.0 += f64::from(value);
+        bucket.1 += 1;
+        bucket.2[STATES
+            .iter()
+            .position(|known| *known == state)
+            .expect("every state is listed")] += 1;
+    }
+    buckets
+        .into_iter()
+        .map(|(sum, count, states)| {
+            let mut most_frequent = 0;
+            for (index, count) in states.iter().enumerate() {
+                if *count > states[most_frequent] {
+                    most_frequent = index;
+                }
+            }
+            (count > 0).then(|| ((sum / f64::from(count)) as f32, STATES[most_frequent]))
+        })
+        .collect()
+}
+
+/// Columns of eighth blocks from `range.0` to `range.1`, with the value on the left
+/// of the top, middle and bottom rows and the dates of both ends under the axis
+fn render_columns(
+    columns: &[Column],
+    range: (f32, f32),
+    height: usize,
+    dates: (String, String),
+    color: bool,
+) -> String {
+    const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let step = (range.1 - range.0) / height as f32;
+    let mut chart = String::new();
+    for row in (0..height).rev() {
+        let bottom = range.0 + step * row as f32;
+        if row == height - 1 || row == height / 2 || row == 0 {
+            let label = if row == height - 1 {
+                bottom + step
+            } else {
+                bottom
+            };
+            let _ = write!(chart, "{label:>6.1} ┤");
+        } else {
+            chart.push_str("       │");
+        }
+        for column in columns {
+            let cell = column.and_then(|(value, state)| {
+                let eighths = ((value - bottom) / step * 8.0).round();
+                let block = match eighths {
+                    eighths if eighths >= 8.0 => '█',
+                    eighths if eighths >= 1.0 => BLOCKS[eighths as usize - 1],
+                    // Keep the lowest values visible
+                    _ if row == 0 => BLOCKS[0],
+                    _ => return None,
+                };
+                Some((block, state))
+            });
+            match cell {
+                Some((block, state)) if color => {
+                    let _ = write!(chart, "{}{block}\x1b[0m", state_ansi(state));
+                }
+                Some((block, _)) => chart.push(block),
+                None => chart.push(' '),
+            }
+        }
+        chart.push('\n');
+    }
+    let _ = writeln!(chart, "       └{}", "─".repeat(columns.len()));
+    let padding = columns
+        .len()
+        .saturating_sub(dates.0.chars().count() + dates.1.chars().count());
+    let _ = writeln!(
+        chart,
+        "        {}{}{}",
+        dates.0,
+        " ".repeat(padding),
+        dates.1
+    );
+    chart
+}
+
+/// The charge of the last `span` colored by state, and the daily health of the whole
+/// history, as Unicode block charts `width` characters wide. ANSI colors if `color`.
+pub fn terminal_chart(
+    records: &[HistoryRecord],
+    span: Option<Duration>,
+    width: usize,
+    color: bool,
+) -> Result<String, MyError> {
+    let charge = charge_points(records, span);
+    let health = daily_health(records);
+    if charge.is_empty() && health.is_empty() {
+        return Err(MyError::HealthStatsError(
+            "The history has nothing to plot".to_owned(),
+        ));
+    }
+    // The axis and its labels take 8 characters
+    let width = width.saturating_sub(8).max(10);
+
+    let mut chart = String::new();
+    if let (Some(first), Some(last)) = (charge.first(), charge.last()) {
+        let date = |time: &DateTime<FixedOffset>| time.format("%d/%m %H:%M").to_string();
+        let _ = writeln!(
+            chart,
+            "Charge, {} to {}",
+            date(&first.time),
+            date(&last.time)
+        );
+        let charge_columns = columns(
+            charge
+                .iter()
+                .map(|point| (point.time, point.percentage, point.state)),
+            first.time,
+            last.time,
+            width,
+        );
+        chart.push_str(&render_columns(
+            &charge_columns,
+            (0.0, 100.0),
+            10,
+            (date(&first.time), date(&last.time)),
+            color,
+        ));
+        let legend = STATES
+            .iter()
+            .filter(|state| charge.iter().any(|point| point.state == **state))
+            .map(|state| match color {
+                true => format!("{}█\x1b[0m {}", state_ansi(*state), state_name(*state)),
+                false => state_name(*state),
+            })
+            .collect::<Vec<_>>();
+        let _ = writeln!(chart, "        {}", legend.join("  "));
+    }
+
+    if let (Some(first), Some(last)) = (health.first(), health.last()) {
+        let lowest = health
+            .iter()
+            .map(|(_, health)| *health)
+            .fold(f32::INFINITY, f32::min);
+        let highest = health
+            .iter()
+            .map(|(_, health)| *health)
+            .fold(f32::NEG_INFINITY, f32::max);
+        let date = |time: &DateTime<FixedOffset>| time.format("%d/%m/%Y").to_string();
+        if !chart.is_empty() {
+            chart.push('\n');
+        }
+        let _ = writeln!(
+            chart,
+            "Battery health, {} to {}",
+            date(&first.0),
+            date(&last.0)
+        );
+        let health_columns = columns(
+            health.iter().map(|(time, health)| (*time, *health, None)),
+            first.0,
+            last.0,
+            width,
+        );
+        // Without the state color the health is drawn in the terminal color
+        chart.push_str(&render_columns(
+            &health_columns,
+            ((lowest - 1.0).floor(), (highest + 1.0).ceil()),
+            6,
+            (date(&first.0), date(&last.0)),
+            false,
+        ));
+    }
+    Ok(chart)
+}
@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use super::battery_health::{BatterySnapshot, BatteryState};
+use super::store::PowerSession;
+
+/// Longer gaps between two snapshots mean the laptop was suspended, their energy
+/// isn't counted
+const MAX_GAP: Duration = Duration::from_secs(5 * 60);
+/// The controller unplugs the charger for a moment at the upper limit, those
+/// sessions aren't worth a row
+const MIN_SESSION: Duration = Duration::from_secs(5 * 60);
+
+/// Integrates the power drawn while the battery is discharging, one session from
+/// the charger being unplugged to it being plugged back
+#[derive(Default)]
+pub struct SessionMeter {
+    session: Option<PowerSession>,
+    last: Option<BatterySnapshot>,
+}
+
+impl SessionMeter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a snapshot, returns the session it ended
+    pub fn update(&mut self, snapshot: &BatterySnapshot) -> Option<PowerSession> {
+        if snapshot.state != BatteryState::Discharging {
+            return self.finish();
+        }
+
+        let time = snapshot.time.fixed_offset();
+        let power = snapshot.power();
+        match (&mut self.session, &self.last) {
+            (Some(session), Some(last)) => {
+                let elapsed = (snapshot.time - last.time).to_std().unwrap_or_default();
+                if let (Some(last_power), Some(power)) = (last.power(), power) {
+                    if elapsed <= MAX_GAP {
+                        let hours = elapsed.as_secs_f64() / 3600.0;
+                        session.energy_wh += (last_power + power) / 2.0 * hours;
+                        session.active += elapsed;
+                    }
+                }
+                session.end = time;
+                session.peak_w = session.peak_w.max(power.unwrap_or_default());
+                session.percentage_end = snapshot.percentage();
+            }
+            _ => {
+                self.session = Some(PowerSession {
+                    start: time,
+                    end: time,
+                    active: Duration::ZERO,
+                    energy_wh: 0.0,
+                    peak_w: power.unwrap_or_default(),
+                    percentage_start: snapshot.percentage(),
+                    percentage_end: snapshot.percentage(),
+                })
+            }
+        }
+        self.last = Some(snapshot.clone());
+        None
+    }
+
+    /// End the running session, None if there is none or it was too short
+    pub fn finish(&mut self) -> Option<PowerSession> {
+        self.last = None;
+        self.session
+            .take()
+            .filter(|session| session.duration().to_std().unwrap_or_default() >= MIN_SESSION)
+    }
+}
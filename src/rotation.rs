@@ -0,0 +1,246 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Datelike, FixedOffset};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use super::history::{self, HistoryRecord};
+use super::utils::MyError;
+
+/// When the history file is moved aside and a new one is started
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Rotation {
+    Never,
+    /// One file per month: data.2025-01.csv
+    Monthly,
+    /// When the file reaches this many bytes: data.2025-01-25T122200.csv
+    Size(u64),
+}
+
+impl Rotation {
+    /// never, monthly or a size like 512kb or 10mb
+    pub fn match_string(rotation: &str) -> Result<Self, MyError> {
+        let invalid = || {
+            MyError::ConfigError(format!(
+                "'{rotation}' is not a valid history_rotation, use never, monthly or a size like 10mb"
+            ))
+        };
+        match rotation {
+            "never" => Ok(Self::Never),
+            "monthly" => Ok(Self::Monthly),
+            _ => {
+                let (size, unit) = if let Some(size) = rotation.strip_suffix("kb") {
+                    (size, 1024)
+                } else if let Some(size) = rotation.strip_suffix("mb") {
+                    (size, 1024 * 1024)
+                } else {
+                    return Err(invalid());
+                };
+                let size = match size.parse::<u64>() {
+                    Ok(size) if size > 0 => size,
+                    _ => return Err(invalid()),
+                };
+                size.checked_mul(unit).map(Self::Size).ok_or_else(|| {
+                    MyError::ConfigError(format!("history_rotation={rotation} is too many bytes"))
+                })
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RotationPolicy {
+    pub rotation: Rotation,
+    /// Compress the rotated files with gzip
+    pub compress: bool,
+    /// Rotated files last written before this are deleted, None keeps them forever
+    pub retention: Option<Duration>,
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        RotationPolicy {
+            rotation: Rotation::Never,
+            compress: false,
+            retention: None,
+        }
+    }
+}
+
+impl RotationPolicy {
+    /// Move `path` aside if `next` doesn't belong to it anymore, then apply the retention.
+    /// Returns the rotated file, the next append starts a new file with the header.
+    pub fn rotate_if_needed(
+        &self,
+        path: &Path,
+        next: &HistoryRecord,
+    ) -> Result<Option<PathBuf>, MyError> {
+        let Some(first) = self.needs_rotation(path, next)? else {
+            return Ok(None);
+        };
+
+        let suffix = match self.rotation {
+            Rotation::Monthly => first.time.format("%Y-%m").to_string(),
+            Rotation::Never | Rotation::Size(_) => first.time.format("%Y-%m-%dT%H%M%S").to_string(),
+        };
+        let rotated_path = unused_path(path, &suffix);
+        fs::rename(path, &rotated_path)?;
+        log::info!("History rotated to {}", rotated_path.display());
+
+        let rotated_path = if self.compress {
+            compress(&rotated_path)?
+        } else {
+            rotated_path
+        };
+        self.apply_retention(path)?;
+        Ok(Some(rotated_path))
+    }
+
+    /// The first record of `path` if the file has to be rotated before appending `next`
+    fn needs_rotation(
+        &self,
+        path: &Path,
+        next: &HistoryRecord,
+    ) -> Result<Option<HistoryRecord>, MyError> {
+        let Ok(metadata) = fs::metadata(path) else {
+            return Ok(None);
+        };
+        let rotate = match self.rotation {
+            Rotation::Never => false,
+            Rotation::Size(max_size) => metadata.len() >= max_size,
+            // Decided on the first record
+            Rotation::Monthly => true,
+        };
+        if !rotate {
+            return Ok(None);
+        }
+
+        let Some(first) = history::first_record(path)? else {
+            return Ok(None);
+        };
+        if self.rotation == Rotation::Monthly && same_month(&first.time, &next.time) {
+            return Ok(None);
+        }
+        Ok(Some(first))
+    }
+
+    /// Delete the rotated files older than the retention
+    pub fn apply_retention(&self, path: &Path) -> Result<Vec<PathBuf>, MyError> {
+        let Some(retention) = self.retention else {
+            return Ok(Vec::new());
+        };
+        // A retention going back before the epoch keeps everything
+        let Some(oldest_kept) = SystemTime::now().checked_sub(retention) else {
+            return Ok(Vec::new());
+        };
+
+        let mut deleted = Vec::new();
+        for rotated_path in rotated_files(path)? {
+            if fs::metadata(&rotated_path)?.modified()? < oldest_kept {
+                fs::remove_file(&rotated_path)?;
+                log::info!(
+                    "Deleted {}, older than the history retention",
+                    rotated_path.display()
+                );
+                deleted.push(rotated_path);
+            }
+        }
+        Ok(deleted)
+    }
+}
+
+fn same_month(first: &DateTime<FixedOffset>, next: &DateTime<FixedOffset>) -> bool {
+    (first.year(), first.month()) == (next.year(), next.month())
+}
+
+/// data.csv -> data.<suffix>.csv
+fn rotated_name(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    match path.extension() {
+        Some(extension) => {
+            path.with_file_name(format!("{stem}.{suffix}.{}", extension.to_string_lossy()))
+        }
+        None => path.with_file_name(format!("{stem}.{suffix}")),
+    }
+}
+
+/// data.<suffix>.csv, or data.<suffix>.1.csv if two rotations get the same suffix
+fn unused_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut candidate = rotated_name(path, suffix);
+    let mut counter = 1;
+    while candidate.exists() || candidate.with_extension(gz_extension(&candidate)).exists() {
+        candidate = rotated_name(path, &format!("{suffix}.{counter}"));
+        counter += 1;
+    }
+    candidate
+}
+
+fn gz_extension(path: &Path) -> String {
+    match path.extension() {
+        Some(extension) => format!("{}.gz", extension.to_string_lossy()),
+        None => "gz".to_owned(),
+    }
+}
+
+/// Replace `path` with `path.gz`, keeping the modification time for the retention
+fn compress(path: &Path) -> Result<PathBuf, MyError> {
+    let compressed_path = path.with_extension(gz_extension(path));
+    let modified = fs::metadata(path)?.modified()?;
+
+    let mut encoder = GzEncoder::new(File::create(&compressed_path)?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    let compressed = encoder.finish()?;
+    compressed.set_modified(modified)?;
+    compressed.sync_all()?;
+
+    fs::remove_file(path)?;
+    Ok(compressed_path)
+}
+
+/// The rotated files of `path`, oldest first. Only the names made by the rotation
+/// are listed, not data.csv.bak or the event files next to it.
+pub fn rotated_files(path: &Path) -> Result<Vec<PathBuf>, MyError> {
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    let prefix = format!("{stem}.");
+
+    let mut rotated = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let file_name = entry?.file_name().to_string_lossy().into_owned();
+        let Some(rest) = file_name.strip_prefix(&prefix) else {
+            continue;
+        };
+        let rest = rest.strip_suffix(".gz").unwrap_or(rest);
+        let Some(suffix) = rest.strip_suffix(&extension) else {
+            continue;
+        };
+        if suffix.starts_with(|first: char| first.is_ascii_digit()) {
+            rotated.push(directory.join(file_name));
+        }
+    }
+    // The suffixes are dates, sorting the names sorts the files by time
+    rotated.sort();
+    Ok(rotated)
+}
+
+/// Every record of the history, the rotated files first and then `path`
+pub fn read_rotated_history(path: &Path) -> Result<Vec<HistoryRecord>, MyError> {
+    let mut records = Vec::new();
+    for rotated_path in rotated_files(path)? {
+        records.extend(history::read_history(&rotated_path)?);
+    }
+    if path.exists() {
+        records.extend(history::read_history(path)?);
+    }
+    Ok(records)
+}
@@ -0,0 +1,137 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::time::Duration;
+
+use super::battery_health::{BatterySnapshot, BATTERY_FILES_PATH};
+use super::shutdown::{PowerEvents, Shutdown, Wake};
+use super::utils::MyError;
+
+/// Where the sampler takes its readings from
+pub trait SnapshotSource {
+    /// Next reading, None when there is nothing left to read
+    fn sample(&mut self) -> Result<Option<BatterySnapshot>, MyError>;
+}
+
+/// The battery exposed by the kernel in sysfs
+pub struct SysfsSource {
+    battery_path: PathBuf,
+}
+
+impl SysfsSource {
+    pub fn new(battery_path: impl Into<PathBuf>) -> Self {
+        SysfsSource {
+            battery_path: battery_path.into(),
+        }
+    }
+}
+
+impl Default for SysfsSource {
+    fn default() -> Self {
+        Self::new(BATTERY_FILES_PATH)
+    }
+}
+
+impl SnapshotSource for SysfsSource {
+    fn sample(&mut self) -> Result<Option<BatterySnapshot>, MyError> {
+        BatterySnapshot::read_from(&self.battery_path).map(Some)
+    }
+}
+
+/// Readings recorded beforehand, replayed one per sample
+pub struct RecordedSource {
+    snapshots: VecDeque<BatterySnapshot>,
+}
+
+impl RecordedSource {
+    pub fn new(snapshots: Vec<BatterySnapshot>) -> Self {
+        RecordedSource {
+            snapshots: snapshots.into(),
+        }
+    }
+}
+
+impl SnapshotSource for RecordedSource {
+    fn sample(&mut self) -> Result<Option<BatterySnapshot>, MyError> {
+        Ok(self.snapshots.pop_front())
+    }
+}
+
+/// Reads the battery once per cycle and sends the same snapshot to every subscriber.
+/// When the sampler stops the channels are closed and the consumers stop too.
+#[derive(Default)]
+pub struct Sampler {
+    subscribers: Vec<Sender<BatterySnapshot>>,
+    power_events: Option<PowerEvents>,
+}
+
+impl Sampler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sample again as soon as the power supply changes
+    pub fn with_power_events(mut self, power_events: PowerEvents) -> Self {
+        self.power_events = Some(power_events);
+        self
+    }
+
+    pub fn subscribe(&mut self) -> Receiver<BatterySnapshot> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Sample every `interval`, or as soon as the power supply changes with `with_power_events`,
+    /// until the shutdown is triggered or the source is exhausted.
+    pub fn run<S: SnapshotSource>(
+        &mut self,
+        source: &mut S,
+        interval: Duration,
+        shutdown: &Shutdown,
+    ) -> Result<(), MyError> {
+        loop {
+            // An event while sampling is waited for too, the snapshot may predate it
+            let seen = self.power_events.as_ref().map(PowerEvents::count);
+            let Some(snapshot) = source.sample()? else {
+                self.close();
+                return Ok(());
+            };
+            self.broadcast(snapshot);
+
+            let stop = match (&self.power_events, seen) {
+                (Some(power_events), Some(seen)) => {
+                    power_events.wait(seen, interval) == Wake::Shutdown
+                }
+                _ => shutdown.sleep(interval),
+            };
+            if stop {
+                self.close();
+                return Ok(());
+            }
+        }
+    }
+
+    fn broadcast(&mut self, snapshot: BatterySnapshot) {
+        // Consumers that went away are forgotten
+        self.subscribers
+            .retain(|subscriber| subscriber.send(snapshot.clone()).is_ok());
+    }
+
+    /// Stop the consumers by closing their channels
+    fn close(&mut self) {
+        self.subscribers.clear();
+    }
+}
+
+/// Wait for the next snapshot and skip the ones that piled up meanwhile,
+/// None when the sampler has stopped.
+pub fn recv_latest(snapshots: &Receiver<BatterySnapshot>) -> Option<BatterySnapshot> {
+    let mut latest = snapshots.recv().ok()?;
+    loop {
+        match snapshots.try_recv() {
+            Ok(snapshot) => latest = snapshot,
+            Err(TryRecvError::Empty | TryRecvError::Disconnected) => return Some(latest),
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+/// Shared flag telling every loop of the daemon to stop at the next occasion.
+/// Waiting through `sleep` instead of `thread::sleep` makes a loop cancellable.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    state: Arc<(Mutex<State>, Condvar)>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    stop: bool,
+    /// Incremented on every power supply event
+    power_events: u64,
+}
+
+/// Why a wait ended
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Wake {
+    Shutdown,
+    PowerEvent,
+    Timeout,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trigger(&self) {
+        let (state, condvar) = &*self.state;
+        state.lock().unwrap_or_else(|err| err.into_inner()).stop = true;
+        condvar.notify_all();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        let (state, _) = &*self.state;
+        state.lock().unwrap_or_else(|err| err.into_inner()).stop
+    }
+
+    /// Sleep for `duration` or until the shutdown is triggered,
+    /// returns true if the caller has to stop.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        self.wait(duration, None) == Wake::Shutdown
+    }
+
+    /// Wait until the shutdown, the timeout or, with `seen`, a power event after it
+    fn wait(&self, duration: Duration, seen: Option<u64>) -> Wake {
+        let deadline = Instant::now() + duration;
+        let (state, condvar) = &*self.state;
+        let mut state = state.lock().unwrap_or_else(|err| err.into_inner());
+        loop {
+            if state.stop {
+                return Wake::Shutdown;
+            }
+            if seen.is_some_and(|seen| state.power_events != seen) {
+                return Wake::PowerEvent;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Wake::Timeout;
+            }
+            state = condvar
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|err| err.into_inner())
+                .0;
+        }
+    }
+
+    /// Trigger the shutdown on SIGTERM (systemctl stop) and SIGINT (ctrl-c)
+    pub fn listen_signals(&self) -> io::Result<()> {
+        let mut signals = Signals::new([SIGTERM, SIGINT])?;
+        let shutdown = self.clone();
+        thread::Builder::new()
+            .name("signals".to_owned())
+            .spawn(move || {
+                for signal in signals.forever() {
+                    // A second signal means the user doesn't want to wait
+                    if shutdown.is_triggered() {
+                        log::warn!("Received signal {signal} again, exiting now");
+                        std::process::exit(1);
+                    }
+                    log::info!("Received signal {signal}, shutting down");
+                    shutdown.trigger();
+                }
+            })?;
+        Ok(())
+    }
+}
+
+/// The power supply uevents, counted so that a loop can wait for the ones it didn't
+/// see yet. A wait also ends on the shutdown it was made from.
+#[derive(Debug, Clone)]
+pub struct PowerEvents {
+    shutdown: Shutdown,
+}
+
+impl PowerEvents {
+    pub fn new(shutdown: &Shutdown) -> Self {
+        PowerEvents {
+            shutdown: shutdown.clone(),
+        }
+    }
+
+    /// Wake up every loop waiting in `wait`
+    pub fn notify(&self) {
+        let (state, condvar) = &*self.shutdown.state;
+        state.lock().unwrap_or_else(|err| err.into_inner()).power_events += 1;
+        condvar.notify_all();
+    }
+
+    /// The events so far, taken before the work that a new event should redo
+    pub fn count(&self) -> u64 {
+        let (state, _) = &*self.shutdown.state;
+        state.lock().unwrap_or_else(|err| err.into_inner()).power_events
+    }
+
+    /// Like `Shutdown::sleep` but returns early when there were events after `seen`,
+    /// `duration` is the polling fallback when no uevents are received.
+    pub fn wait(&self, seen: u64, duration: Duration) -> Wake {
+        self.shutdown.wait(duration, Some(seen))
+    }
+}
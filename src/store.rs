@@ -0,0 +1,682 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, FixedOffset, SecondsFormat};
+
+use super::ardu::{ArduSketch, ChargerMode};
+use super::battery_health::BatteryIdentity;
+use super::history::{self, HistoryRecord};
+use super::rotation::{self, RotationPolicy};
+use super::utils::MyError;
+
+/// A sketch flashed on the arduino and how the battery reacted
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActuatorEvent {
+    pub time: DateTime<FixedOffset>,
+    pub command: ArduSketch,
+    /// None if the command succeeded
+    pub error: Option<String>,
+    /// From the flash to the battery reporting the new state
+    pub latency: Duration,
+    pub percentage_before: f32,
+    /// None if the sampler stopped before the battery reacted
+    pub percentage_after: Option<f32>,
+}
+
+/// A desktop notification sent to the user
+#[derive(Debug, Clone, PartialEq)]
+pub struct NotificationEvent {
+    pub time: DateTime<FixedOffset>,
+    pub level: String,
+    pub message: String,
+}
+
+/// Energy drawn from the battery between unplugging and plugging the charger back
+#[derive(Debug, Clone, PartialEq)]
+pub struct PowerSession {
+    pub start: DateTime<FixedOffset>,
+    pub end: DateTime<FixedOffset>,
+    /// The time the energy was measured over, without the suspended gaps
+    pub active: Duration,
+    pub energy_wh: f64,
+    pub peak_w: f64,
+    pub percentage_start: f32,
+    pub percentage_end: f32,
+}
+
+impl PowerSession {
+    pub fn duration(&self) -> chrono::Duration {
+        self.end - self.start
+    }
+
+    /// Mean power over the active time, 0 for an empty session
+    pub fn average_w(&self) -> f64 {
+        let hours = self.active.as_secs_f64() / 3600.0;
+        if hours > 0.0 {
+            self.energy_wh / hours
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Where the battery history is kept
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HistoryBackend {
+    Csv,
+    Sqlite,
+}
+
+impl HistoryBackend {
+    pub fn match_string(backend: &str) -> Result<Self, MyError> {
+        match backend {
+            "csv" => Ok(Self::Csv),
+            "sqlite" => Ok(Self::Sqlite),
+            _ => Err(MyError::ConfigError(format!(
+                "'{backend}' is not a valid history_backend, use csv or sqlite"
+            ))),
+        }
+    }
+}
+
+pub trait HistoryStore: Send {
+    fn path(&self) -> &Path;
+
+    fn append_snapshot(&mut self, record: &HistoryRecord) -> Result<(), MyError>;
+
+    fn append_actuator_event(&mut self, event: &ActuatorEvent) -> Result<(), MyError>;
+
+    fn append_notification(&mut self, event: &NotificationEvent) -> Result<(), MyError>;
+
+    fn append_power_session(&mut self, session: &PowerSession) -> Result<(), MyError>;
+
+    /// The last `count` actuator events, oldest first
+    fn last_actuator_events(&self, count: usize) -> Result<Vec<ActuatorEvent>, MyError>;
+
+    /// Snapshots recorded in `from..to`, oldest first
+    fn snapshots_between(
+        &self,
+        from: DateTime<FixedOffset>,
+        to: DateTime<FixedOffset>,
+    ) -> Result<Vec<HistoryRecord>, MyError>;
+}
+
+const ACTUATOR_EVENTS_HEADER: &str =
+    "Timestamp,Command,Outcome,Latency_ms,Percentage_Before,Percentage_After";
+const NOTIFICATIONS_HEADER: &str = "Timestamp,Level,Message";
+const POWER_SESSIONS_HEADER: &str =
+    "Start,End,Duration_min,Energy_Wh,Average_W,Peak_W,Percentage_Start,Percentage_End";
+
+/// The versioned CSV file written by health_stats, the events go to more files next
+/// to it: data.csv, data.actuator_events.csv, data.notifications.csv and
+/// data.power_sessions.csv.
+/// Only the history file is rotated, the snapshots are read across the rotated files.
+pub struct CsvStore {
+    path: PathBuf,
+    epoch_column: bool,
+    rotation: RotationPolicy,
+    /// Battery of the last segment of the file, None until the file has been read
+    segment_battery: Option<Option<BatteryIdentity>>,
+}
+
+impl CsvStore {
+    pub fn new(path: impl Into<PathBuf>, epoch_column: bool, rotation: RotationPolicy) -> Self {
+        CsvStore {
+            path: path.into(),
+            epoch_column,
+            rotation,
+            segment_battery: None,
+        }
+    }
+
+    pub fn actuator_events_path(&self) -> PathBuf {
+        self.path.with_extension("actuator_events.csv")
+    }
+
+    pub fn notifications_path(&self) -> PathBuf {
+        self.path.with_extension("notifications.csv")
+    }
+
+    pub fn power_sessions_path(&self) -> PathBuf {
+        self.path.with_extension("power_sessions.csv")
+    }
+}
+
+/// Append `row` to an event file, writing the header first if the file is new
+fn append_event(path: &Path, header: &str, row: &str) -> Result<(), MyError> {
+    history::repair_truncated_line(path)?;
+    let is_new = fs::metadata(path).map_or(true, |metadata| metadata.len() == 0);
+    if is_new {
+        history::append_durably(path, &format!("{header}\n{row}\n"))
+    } else {
+        history::append_durably(path, &format!("{row}\n"))
+    }
+}
+
+/// Error and notification texts can have commas, quotes and newlines
+fn csv_text(text: &str) -> String {
+    let text = text.replace('\n', " ");
+    if text.contains([',', '"']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+/// The fields of an event row, undoing `csv_text`
+fn split_csv(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(char) = chars.next() {
+        match (char, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            ('"', _) => quoted = !quoted,
+            (',', false) => fields.push(String::new()),
+            _ => fields.last_mut().unwrap().push(char),
+        }
+    }
+    fields
+}
+
+fn parse_actuator_event(line: &str) -> Result<ActuatorEvent, MyError> {
+    let invalid = |reason: &str| {
+        MyError::HealthStatsError(format!("Invalid actuator event '{line}': {reason}"))
+    };
+    let fields = split_csv(line);
+    let [time, command, outcome, latency, before, after] = &fields[..] else {
+        return Err(invalid("unexpected number of fields"));
+    };
+    let parse_f32 = |value: &str| value.parse::<f32>().map_err(|err| invalid(&err.to_string()));
+
+    Ok(ActuatorEvent {
+        time: DateTime::parse_from_rfc3339(time).map_err(|err| invalid(&err.to_string()))?,
+        command: ArduSketch::match_string(command)?,
+        error: (outcome != "ok").then(|| outcome.clone()),
+        latency: Duration::from_millis(
+            latency.parse().map_err(|err: std::num::ParseIntError| invalid(&err.to_string()))?,
+        ),
+        percentage_before: parse_f32(before)?,
+        percentage_after: match after.as_str() {
+            "N/A" => None,
+            after => Some(parse_f32(after)?),
+        },
+    })
+}
+
+impl HistoryStore for CsvStore {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn append_snapshot(&mut self, record: &HistoryRecord) -> Result<(), MyError> {
+        if self.rotation.rotate_if_needed(&self.path, record)?.is_some() {
+            self.segment_battery = Some(None);
+        }
+        let segment_battery = match &self.segment_battery {
+            Some(segment_battery) => segment_battery.clone(),
+            None => history::last_battery(&self.path)?,
+        };
+        history::append_record(&self.path, record, self.epoch_column, segment_battery.as_ref())?;
+        self.segment_battery = Some(record.battery.clone().or(segment_battery));
+        Ok(())
+    }
+
+    fn append_actuator_event(&mut self, event: &ActuatorEvent) -> Result<(), MyError> {
+        let row = format!(
+            "{},{},{},{},{},{}",
+            event.time.to_rfc3339_opts(SecondsFormat::Secs, false),
+            event.command,
+            event.error.as_deref().map_or("ok".to_owned(), csv_text),
+            event.latency.as_millis(),
+            event.percentage_before,
+            event.percentage_after.map_or("N/A".to_owned(), |percentage| percentage.to_string()),
+        );
+        append_event(&self.actuator_events_path(), ACTUATOR_EVENTS_HEADER, &row)
+    }
+
+    fn append_notification(&mut self, event: &NotificationEvent) -> Result<(), MyError> {
+        let row = format!(
+            "{},{},{}",
+            event.time.to_rfc3339_opts(SecondsFormat::Secs, false),
+            csv_text(&event.level),
+            csv_text(&event.message),
+        );
+        append_event(&self.notifications_path(), NOTIFICATIONS_HEADER, &row)
+    }
+
+    fn append_power_session(&mut self, session: &PowerSession) -> Result<(), MyError> {
+        let row = format!(
+            "{},{},{},{:.3},{:.2},{:.2},{},{}",
+            session.start.to_rfc3339_opts(SecondsFormat::Secs, false),
+            session.end.to_rfc3339_opts(SecondsFormat::Secs, false),
+            session.active.as_secs() / 60,
+            session.energy_wh,
+            session.average_w(),
+            session.peak_w,
+            session.percentage_start,
+            session.percentage_end,
+        );
+        append_event(&self.power_sessions_path(), POWER_SESSIONS_HEADER, &row)
+    }
+
+    fn last_actuator_events(&self, count: usize) -> Result<Vec<ActuatorEvent>, MyError> {
+        let path = self.actuator_events_path();
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let content = fs::read_to_string(&path)?;
+        let mut rows = content
+            .lines()
+            .filter(|line| !line.is_empty() && *line != ACTUATOR_EVENTS_HEADER)
+            .collect::<Vec<_>>();
+        // A last row cut short by a crash is skipped, invalid rows anywhere else are an error
+        if let Some(Err(err)) = rows.last().map(|row| parse_actuator_event(row)) {
+            log::warn!("Skipping the truncated last row of {}: {err}", path.display());
+            rows.pop();
+        }
+        rows[rows.len().saturating_sub(count)..]
+            .iter()
+            .map(|row| parse_actuator_event(row))
+            .collect()
+    }
+
+    fn snapshots_between(
+        &self,
+        from: DateTime<FixedOffset>,
+        to: DateTime<FixedOffset>,
+    ) -> Result<Vec<HistoryRecord>, MyError> {
+        let mut records = rotation::read_rotated_history(&self.path)?;
+        records.retain(|record| from <= record.time && record.time < to);
+        Ok(records)
+    }
+}
+
+/// Told about every event recorded through a SharedStore, like the metrics exporter
+pub trait EventObserver: Send + Sync {
+    fn actuator_event(&self, event: &ActuatorEvent);
+
+    fn notification(&self, _event: &NotificationEvent) {}
+
+    /// The controller decided `command` in `mode`, before running it
+    fn decision(&self, _command: ArduSketch, _mode: ChargerMode) {}
+}
+
+/// One store shared by the workers: health_stats writes the snapshots, the controller
+/// and the notifier their events. Failing to log an event never stops a worker.
+#[derive(Clone)]
+pub struct SharedStore {
+    store: Arc<Mutex<Box<dyn HistoryStore>>>,
+    observers: Vec<Arc<dyn EventObserver>>,
+}
+
+impl SharedStore {
+    pub fn new(store: Box<dyn HistoryStore>) -> Self {
+        SharedStore {
+            store: Arc::new(Mutex::new(store)),
+            observers: Vec::new(),
+        }
+    }
+
+    pub fn with_observer(mut self, observer: Arc<dyn EventObserver>) -> Self {
+        self.observers.push(observer);
+        self
+    }
+
+    /// A worker that panicked while writing doesn't make the store unusable
+    fn lock(&self) -> std::sync::MutexGuard<'_, Box<dyn HistoryStore>> {
+        self.store.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.lock().path().to_owned()
+    }
+
+    pub fn append_snapshot(&self, record: &HistoryRecord) -> Result<(), MyError> {
+        self.lock().append_snapshot(record)
+    }
+
+    pub fn record_actuator_event(&self, event: &ActuatorEvent) {
+        for observer in &self.observers {
+            observer.actuator_event(event);
+        }
+        if let Err(err) = self.lock().append_actuator_event(event) {
+            log::error!("Failed logging {} event: {err}", event.command);
+        }
+    }
+
+    pub fn record_notification(&self, event: &NotificationEvent) {
+        for observer in &self.observers {
+            observer.notification(event);
+        }
+        if let Err(err) = self.lock().append_notification(event) {
+            log::error!("Failed logging notification: {err}");
+        }
+    }
+
+    /// Only the observers are told, the outcome is what gets logged
+    pub fn record_decision(&self, command: ArduSketch, mode: ChargerMode) {
+        for observer in &self.observers {
+            observer.decision(command, mode);
+        }
+    }
+
+    pub fn record_power_session(&self, session: &PowerSession) {
+        if let Err(err) = self.lock().append_power_session(session) {
+            log::error!("Failed logging power session: {err}");
+        }
+    }
+
+    pub fn snapshots_between(
+        &self,
+        from: DateTime<FixedOffset>,
+        to: DateTime<FixedOffset>,
+    ) -> Result<Vec<HistoryRecord>, MyError> {
+        self.lock().snapshots_between(from, to)
+    }
+
+    pub fn last_actuator_events(&self, count: usize) -> Result<Vec<ActuatorEvent>, MyError> {
+        self.lock().last_actuator_events(count)
+    }
+}
+
+/// Open the store of `backend`, the CSV file or the SQLite database
+pub fn open(
+    backend: HistoryBackend,
+    csv_path: &Path,
+    sqlite_path: &Path,
+    epoch_column: bool,
+    rotation: RotationPolicy,
+) -> Result<Box<dyn HistoryStore>, MyError> {
+    match backend {
+        HistoryBackend::Csv => Ok(Box::new(CsvStore::new(csv_path, epoch_column, rotation))),
+        #[cfg(feature = "sqlite")]
+        HistoryBackend::Sqlite => Ok(Box::new(sqlite::SqliteStore::open(sqlite_path)?)),
+        #[cfg(not(feature = "sqlite"))]
+        HistoryBackend::Sqlite => Err(MyError::ConfigError(format!(
+            "Can't open {}, energy_monitor was built without the sqlite feature",
+            sqlite_path.display()
+        ))),
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub mod sqlite {
+    use std::path::{Path, PathBuf};
+
+    use chrono::{DateTime, FixedOffset, SecondsFormat};
+    use rusqlite::types::Type;
+    use rusqlite::{params, Connection, Row};
+
+    use super::super::ardu::ArduSketch;
+    use super::super::battery_health::{BatteryIdentity, BatteryState};
+    use super::super::history::{self, HistoryRecord};
+    use super::super::utils::MyError;
+    use super::{ActuatorEvent, HistoryStore, NotificationEvent, PowerSession};
+
+    // Rows are looked up by epoch, the RFC 3339 time keeps the offset for reading them back
+    const SCHEMA: &str = "
+        CREATE TABLE IF NOT EXISTS snapshots (
+            time TEXT NOT NULL,
+            epoch INTEGER NOT NULL,
+            charge_full INTEGER NOT NULL,
+            charge_full_design INTEGER NOT NULL,
+            battery_health REAL NOT NULL,
+            battery_percentage REAL,
+            battery_status TEXT,
+            battery TEXT
+        );
+
+        CREATE TABLE IF NOT EXISTS actuator_events (
+            time TEXT NOT NULL,
+            epoch INTEGER NOT NULL,
+            command TEXT NOT NULL,
+            error TEXT,
+            latency_ms INTEGER NOT NULL,
+            percentage_before REAL NOT NULL,
+            percentage_after REAL
+        );
+        CREATE INDEX IF NOT EXISTS actuator_events_epoch ON actuator_events (epoch);
+
+        CREATE TABLE IF NOT EXISTS notifications (
+            time TEXT NOT NULL,
+            epoch INTEGER NOT NULL,
+            level TEXT NOT NULL,
+            message TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS notifications_epoch ON notifications (epoch);
+
+        CREATE TABLE IF NOT EXISTS power_sessions (
+            start TEXT NOT NULL,
+            start_epoch INTEGER NOT NULL,
+            end TEXT NOT NULL,
+            active_s INTEGER NOT NULL,
+            energy_wh REAL NOT NULL,
+            peak_w REAL NOT NULL,
+            percentage_start REAL NOT NULL,
+            percentage_end REAL NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS power_sessions_epoch ON power_sessions (start_epoch);
+    ";
+
+    impl From<rusqlite::Error> for MyError {
+        fn from(err: rusqlite::Error) -> Self {
+            MyError::HealthStatsError(format!("SQLite error: {err}"))
+        }
+    }
+
+    fn rfc3339(time: &DateTime<FixedOffset>) -> String {
+        time.to_rfc3339_opts(SecondsFormat::Secs, false)
+    }
+
+    /// Snapshots, actuator events, notifications and power sessions in one database file
+    pub struct SqliteStore {
+        path: PathBuf,
+        connection: Connection,
+    }
+
+    impl SqliteStore {
+        /// Open the database, creating the tables the first time
+        pub fn open(path: &Path) -> Result<Self, MyError> {
+            let connection = Connection::open(path)?;
+            connection.execute_batch(SCHEMA)?;
+            // Databases made before the battery identity
+            let has_battery = connection
+                .prepare("SELECT 1 FROM pragma_table_info('snapshots') WHERE name = 'battery'")?
+                .exists([])?;
+            if !has_battery {
+                connection.execute("ALTER TABLE snapshots ADD COLUMN battery TEXT", [])?;
+            }
+            // The unique epoch index dropped the snapshots logged in the same second, even the
+            // ones of another battery. Only the import skips the rows already there.
+            connection.execute_batch(
+                "DROP INDEX IF EXISTS snapshots_epoch;
+                CREATE INDEX IF NOT EXISTS snapshots_epoch_battery ON snapshots (epoch, battery);",
+            )?;
+            Ok(SqliteStore {
+                path: path.to_owned(),
+                connection,
+            })
+        }
+
+        /// Copy the rows of a history file of any schema. Rows of the same second and
+        /// battery as one in the database are skipped, so importing twice is harmless.
+        pub fn import_csv(&mut self, csv_path: &Path) -> Result<ImportReport, MyError> {
+            let records = history::read_history(csv_path)?;
+            let transaction = self.connection.transaction()?;
+            let mut report = ImportReport::default();
+            for record in &records {
+                let exists = transaction
+                    .prepare_cached("SELECT 1 FROM snapshots WHERE epoch = ?1 AND battery IS ?2")?
+                    .exists(params![
+                        record.time.timestamp(),
+                        record.battery.as_ref().map(BatteryIdentity::key),
+                    ])?;
+                if exists {
+                    report.skipped += 1;
+                } else {
+                    insert_snapshot(&transaction, record)?;
+                    report.imported += 1;
+                }
+            }
+            transaction.commit()?;
+            Ok(report)
+        }
+    }
+
+    /// The rows of an import
+    #[derive(Debug, Default, PartialEq, Eq)]
+    pub struct ImportReport {
+        pub imported: usize,
+        /// Already in the database
+        pub skipped: usize,
+    }
+
+    fn insert_snapshot(connection: &Connection, record: &HistoryRecord) -> Result<(), MyError> {
+        connection.execute(
+            "INSERT INTO snapshots VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                rfc3339(&record.time),
+                record.time.timestamp(),
+                record.charge_full,
+                record.charge_full_design,
+                record.battery_health,
+                record.battery_percentage,
+                record.battery_status.map(|status| status.to_string()),
+                record.battery.as_ref().map(BatteryIdentity::key),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn conversion_error(column: usize, err: impl ToString) -> rusqlite::Error {
+        rusqlite::Error::FromSqlConversionFailure(column, Type::Text, err.to_string().into())
+    }
+
+    fn snapshot_from_row(row: &Row) -> rusqlite::Result<HistoryRecord> {
+        let time: String = row.get(0)?;
+        let battery_status: Option<String> = row.get(5)?;
+        let battery: Option<String> = row.get(6)?;
+        Ok(HistoryRecord {
+            time: DateTime::parse_from_rfc3339(&time).map_err(|err| conversion_error(0, err))?,
+            charge_full: row.get(1)?,
+            charge_full_design: row.get(2)?,
+            battery_health: row.get(3)?,
+            battery_percentage: row.get(4)?,
+            battery_status: battery_status
+                .as_deref()
+                .map(BatteryState::match_string)
+                .transpose()
+                .map_err(|err| conversion_error(5, err))?,
+            battery: battery
+                .as_deref()
+                .map(BatteryIdentity::from_key)
+                .transpose()
+                .map_err(|err| conversion_error(6, err))?,
+        })
+    }
+
+    fn actuator_event_from_row(row: &Row) -> rusqlite::Result<ActuatorEvent> {
+        let time: String = row.get(0)?;
+        let command: String = row.get(1)?;
+        let latency_ms: i64 = row.get(3)?;
+        Ok(ActuatorEvent {
+            time: DateTime::parse_from_rfc3339(&time).map_err(|err| conversion_error(0, err))?,
+            command: ArduSketch::match_string(&command).map_err(|err| conversion_error(1, err))?,
+            error: row.get(2)?,
+            latency: std::time::Duration::from_millis(latency_ms.max(0) as u64),
+            percentage_before: row.get(4)?,
+            percentage_after: row.get(5)?,
+        })
+    }
+
+    impl HistoryStore for SqliteStore {
+        fn path(&self) -> &Path {
+            &self.path
+        }
+
+        fn append_snapshot(&mut self, record: &HistoryRecord) -> Result<(), MyError> {
+            insert_snapshot(&self.connection, record)
+        }
+
+        fn append_actuator_event(&mut self, event: &ActuatorEvent) -> Result<(), MyError> {
+            self.connection.execute(
+                "INSERT INTO actuator_events VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    rfc3339(&event.time),
+                    event.time.timestamp(),
+                    event.command.to_string(),
+                    event.error,
+                    event.latency.as_millis() as i64,
+                    event.percentage_before,
+                    event.percentage_after,
+                ],
+            )?;
+            Ok(())
+        }
+
+        fn append_notification(&mut self, event: &NotificationEvent) -> Result<(), MyError> {
+            self.connection.execute(
+                "INSERT INTO notifications VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    rfc3339(&event.time),
+                    event.time.timestamp(),
+                    event.level,
+                    event.message
+                ],
+            )?;
+            Ok(())
+        }
+
+        fn append_power_session(&mut self, session: &PowerSession) -> Result<(), MyError> {
+            self.connection.execute(
+                "INSERT INTO power_sessions VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    rfc3339(&session.start),
+                    session.start.timestamp(),
+                    rfc3339(&session.end),
+                    session.active.as_secs(),
+                    session.energy_wh,
+                    session.peak_w,
+                    session.percentage_start,
+                    session.percentage_end,
+                ],
+            )?;
+            Ok(())
+        }
+
+        fn last_actuator_events(&self, count: usize) -> Result<Vec<ActuatorEvent>, MyError> {
+            let mut statement = self.connection.prepare(
+                "SELECT time, command, error, latency_ms, percentage_before, percentage_after
+                FROM actuator_events ORDER BY epoch DESC, rowid DESC LIMIT ?1",
+            )?;
+            let rows = statement.query_map(params![count as i64], actuator_event_from_row)?;
+
+            let mut events = rows.collect::<Result<Vec<_>, _>>()?;
+            events.reverse();
+            Ok(events)
+        }
+
+        fn snapshots_between(
+            &self,
+            from: DateTime<FixedOffset>,
+            to: DateTime<FixedOffset>,
+        ) -> Result<Vec<HistoryRecord>, MyError> {
+            let mut statement = self.connection.prepare(
+                "SELECT time, charge_full, charge_full_design, battery_health,
+                    battery_percentage, battery_status, battery
+                FROM snapshots WHERE epoch >= ?1 AND epoch < ?2 ORDER BY epoch, rowid",
+            )?;
+            let rows = statement
+                .query_map(params![from.timestamp(), to.timestamp()], snapshot_from_row)?;
+
+            let records = rows.collect::<Result<Vec<_>, _>>()?;
+            Ok(records)
+        }
+    }
+}
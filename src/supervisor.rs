@@ -0,0 +1,240 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::shutdown::Shutdown;
+use super::utils::{notify_percentage, MyError};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum WorkerHealth {
+    Running,
+    /// The worker failed and is waiting to be started again
+    Restarting { failures: u32, last_error: String },
+    /// The worker returned without errors
+    Stopped,
+}
+
+/// What goes in the status file
+#[derive(Default)]
+struct DaemonState {
+    workers: BTreeMap<&'static str, WorkerHealth>,
+    /// Written after the summary as "name: value", like the charger mode
+    details: BTreeMap<&'static str, String>,
+}
+
+/// Keeps the worker threads alive: errors and panics are logged, the worker is
+/// restarted with exponential backoff and the daemon is marked degraded meanwhile.
+#[derive(Clone)]
+pub struct Supervisor {
+    state: Arc<Mutex<DaemonState>>,
+    status_path: Option<PathBuf>,
+    notify: bool,
+    backoff_min: Duration,
+    backoff_max: Duration,
+    shutdown: Shutdown,
+}
+
+impl Supervisor {
+    pub fn new(
+        status_path: Option<PathBuf>,
+        notify: bool,
+        backoff_min: Duration,
+        backoff_max: Duration,
+        shutdown: Shutdown,
+    ) -> Self {
+        Supervisor {
+            state: Arc::new(Mutex::new(DaemonState::default())),
+            status_path,
+            notify,
+            backoff_min,
+            backoff_max,
+            shutdown,
+        }
+    }
+
+    /// Run `worker` on its own thread until it returns Ok or the shutdown is triggered
+    pub fn spawn<F>(&self, name: &'static str, worker: F) -> JoinHandle<()>
+    where
+        F: FnMut() -> Result<(), MyError> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || supervisor.supervise(name, worker))
+            .expect("Failed to spawn worker thread")
+    }
+
+    fn supervise<F>(&self, name: &'static str, mut worker: F)
+    where
+        F: FnMut() -> Result<(), MyError>,
+    {
+        let mut backoff = self.backoff_min;
+        let mut failures = 0;
+        loop {
+            self.set_health(name, WorkerHealth::Running);
+            let started = Instant::now();
+
+            let err = match panic::catch_unwind(AssertUnwindSafe(&mut worker)) {
+                Ok(Ok(())) => {
+                    self.set_health(name, WorkerHealth::Stopped);
+                    return;
+                }
+                Ok(Err(err)) => err.to_string(),
+                Err(panic) => format!("panicked: {}", panic_message(&*panic)),
+            };
+
+            // A worker that ran fine for a while starts again from the minimum wait
+            if started.elapsed() > self.backoff_max {
+                backoff = self.backoff_min;
+                failures = 0;
+            }
+            failures += 1;
+
+            log::error!("{name} failed: {err}, restarting in {}ms", backoff.as_millis());
+            if self.notify && failures == 1 {
+                let message = format!("{name} si è fermato, riavvio in corso: {err}");
+                if let Err(err) = notify_percentage("N/A", &message) {
+                    log::error!("{err}");
+                }
+            }
+            self.set_health(
+                name,
+                WorkerHealth::Restarting {
+                    failures,
+                    last_error: err,
+                },
+            );
+
+            if self.shutdown.sleep(backoff) {
+                self.set_health(name, WorkerHealth::Stopped);
+                return;
+            }
+            backoff = (backoff * 2).min(self.backoff_max);
+        }
+    }
+
+    fn set_health(&self, name: &'static str, health: WorkerHealth) {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        if state.workers.get(name) == Some(&health) {
+            return;
+        }
+        state.workers.insert(name, health);
+        log::info!("status: {}", summary(&state.workers));
+        self.write_status(&state);
+    }
+
+    /// Show `value` in the status file, for the tui
+    pub fn set_detail(&self, name: &'static str, value: String) {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        if state.details.get(name) == Some(&value) {
+            return;
+        }
+        state.details.insert(name, value);
+        self.write_status(&state);
+    }
+
+    /// Write the status again, its age tells the readers that the daemon is alive
+    pub fn refresh_status(&self) {
+        let state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        self.write_status(&state);
+    }
+
+    /// Once every worker is stopped, nobody is left to keep the status up to date
+    pub fn remove_status(&self) {
+        let _state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        let Some(path) = &self.status_path else {
+            return;
+        };
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                log::error!("Failed removing the status {}: {err}", path.display());
+            }
+            _ => {}
+        }
+    }
+
+    /// Called under the lock, or two workers could write their files out of order
+    fn write_status(&self, state: &DaemonState) {
+        let Some(path) = &self.status_path else {
+            return;
+        };
+        let mut content = format!("{}\n", summary(&state.workers));
+        for (name, value) in &state.details {
+            content.push_str(&format!("{name}: {value}\n"));
+        }
+        if let Err(err) = fs::write(path, content) {
+            log::error!("Failed writing status to {}: {err}", path.display());
+        }
+    }
+
+    pub fn health(&self, name: &str) -> Option<WorkerHealth> {
+        let state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        state.workers.get(name).cloned()
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        let state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        state
+            .workers
+            .values()
+            .any(|health| matches!(health, WorkerHealth::Restarting { .. }))
+    }
+
+    /// One line summary like "ok" or "degraded: controller restarting (2 failures): ..."
+    pub fn status(&self) -> String {
+        let state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        summary(&state.workers)
+    }
+}
+
+/// The status file of a running daemon. None without one or when it's older than `max_age`,
+/// the one left behind by a daemon that was killed.
+pub fn read_status(path: &Path, max_age: Duration) -> Option<String> {
+    let modified = fs::metadata(path).and_then(|metadata| metadata.modified()).ok()?;
+    if modified.elapsed().is_ok_and(|age| age > max_age) {
+        return None;
+    }
+    fs::read_to_string(path).ok()
+}
+
+/// The summary and the details of a status file
+pub fn parse_status(content: &str) -> (String, BTreeMap<&str, &str>) {
+    let mut lines = content.lines();
+    let summary = lines.next().unwrap_or_default().trim().to_owned();
+    let details = lines.filter_map(|line| line.split_once(": ")).collect();
+    (summary, details)
+}
+
+fn summary(workers: &BTreeMap<&'static str, WorkerHealth>) -> String {
+    let failing = workers
+        .iter()
+        .filter_map(|(name, health)| match health {
+            WorkerHealth::Restarting {
+                failures,
+                last_error,
+            } => Some(format!("{name} restarting ({failures} failures): {last_error}")),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    if failing.is_empty() {
+        "ok".to_owned()
+    } else {
+        format!("degraded: {}", failing.join(", "))
+    }
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    if let Some(msg) = panic.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = panic.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
@@ -0,0 +1,238 @@
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, FixedOffset};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Gauge, Paragraph, Sparkline};
+use ratatui::Frame;
+
+use super::ardu::{ArduSketch, ChargerMode};
+use super::battery_health::BatterySnapshot;
+use super::estimate::{format_remaining, Estimate};
+use super::history::HistoryRecord;
+use super::store::ActuatorEvent;
+use super::utils::MyError;
+
+/// Span of the charge sparkline
+pub const SPARKLINE_SPAN: chrono::Duration = chrono::Duration::hours(24);
+
+/// Percentages the controller works with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub lower: f32,
+    pub upper: f32,
+    pub critical: f32,
+}
+
+/// Everything shown by `energy_monitor tui`, read again on every refresh
+#[derive(Debug, Clone)]
+pub struct Dashboard {
+    pub snapshot: BatterySnapshot,
+    pub estimate: Option<Estimate>,
+    pub limits: Limits,
+    /// As set over MQTT
+    pub mode: ChargerMode,
+    /// The sketch the controller flashes for `snapshot`
+    pub command: ArduSketch,
+    /// The daemon status line, None when the daemon isn't running
+    pub daemon_status: Option<String>,
+    /// Oldest first
+    pub events: Vec<ActuatorEvent>,
+    /// The history of the last SPARKLINE_SPAN, any order
+    pub history: Vec<HistoryRecord>,
+}
+
+impl Dashboard {
+    pub fn render(&self, frame: &mut Frame) {
+        let [battery, controller, events, charge, help] = Layout::vertical([
+            Constraint::Length(5),
+            Constraint::Length(5),
+            Constraint::Min(4),
+            Constraint::Length(6),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        self.render_battery(frame, battery);
+        self.render_controller(frame, controller);
+        self.render_events(frame, events);
+        self.render_charge(frame, charge);
+        frame.render_widget(Paragraph::new("q: quit"), help);
+    }
+
+    fn render_battery(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title(" Battery ");
+        let [gauge, details] =
+            Layout::vertical([Constraint::Length(1), Constraint::Min(1)]).areas(block.inner(area));
+        frame.render_widget(block, area);
+
+        let percentage = self.snapshot.percentage();
+        let color = if percentage <= self.limits.critical {
+            Color::Red
+        } else if percentage <= self.limits.lower {
+            Color::Yellow
+        } else {
+            Color::Green
+        };
+        frame.render_widget(
+            Gauge::default()
+                .gauge_style(Style::new().fg(color))
+                .ratio((f64::from(percentage) / 100.0).clamp(0.0, 1.0))
+                .label(format!("{percentage:.1}%")),
+            gauge,
+        );
+
+        let power = self
+            .snapshot
+            .power()
+            .map_or("power not reported".to_owned(), |watts| {
+                format!("{watts:.2} W")
+            });
+        let estimate = match self.estimate {
+            Some(Estimate::Empty(remaining)) => format!("empty in {}", format_remaining(remaining)),
+            Some(Estimate::Limit(limit, remaining)) => {
+                format!("{limit}% in {}", format_remaining(remaining))
+            }
+            None => "no estimate".to_owned(),
+        };
+        let lines = vec![
+            Line::from(format!("{}, {power}", self.snapshot.state)),
+            Line::from(format!("Health {:.2}%, {estimate}", self.snapshot.health())),
+        ];
+        frame.render_widget(Paragraph::new(lines), details);
+    }
+
+    fn render_controller(&self, frame: &mut Frame, area: Rect) {
+        let reason = match (self.mode, self.command) {
+            (ChargerMode::Auto, ArduSketch::Connect) => format!("charging up to {}%", self.limits.upper),
+            (ChargerMode::Auto, ArduSketch::Disconnect) => {
+                format!("above {}%, stopping the charge", self.limits.upper)
+            }
+            (ChargerMode::Auto, ArduSketch::DoNothing) => "holding".to_owned(),
+            (mode, _) => format!("{mode} mode"),
+        };
+        let lines = vec![
+            Line::from(format!(
+                "Limits: {}% - {}%, critical {}%",
+                self.limits.lower, self.limits.upper, self.limits.critical
+            )),
+            Line::from(format!("Controller: {} ({reason})", self.command)),
+            Line::from(format!(
+                "Daemon: {}",
+                self.daemon_status.as_deref().unwrap_or("not running")
+            )),
+        ];
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(" Controller ")),
+            area,
+        );
+    }
+
+    fn render_events(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title(" Actuator events ");
+        // The newest at the top, as many as fit
+        let fit = usize::from(block.inner(area).height);
+        let mut lines = self
+            .events
+            .iter()
+            .rev()
+            .take(fit)
+            .map(|event| {
+                let outcome = event.error.as_deref().unwrap_or("ok");
+                let after = event
+                    .percentage_after
+                    .map_or("N/A".to_owned(), |after| format!("{after:.1}%"));
+                Line::from(format!(
+                    "{} {} {outcome} {:.1}s {:.1}% -> {after}",
+                    event.time.format("%m-%d %H:%M:%S"),
+                    event.command,
+                    event.latency.as_secs_f32(),
+                    event.percentage_before
+                ))
+            })
+            .collect::<Vec<_>>();
+        if lines.is_empty() {
+            lines.push(Line::from("No actuator events yet"));
+        }
+        frame.render_widget(Paragraph::new(lines).block(block), area);
+    }
+
+    fn render_charge(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title(" Charge, last 24 h ");
+        let buckets = charge_buckets(
+            &self.history,
+            self.snapshot.time.fixed_offset(),
+            SPARKLINE_SPAN,
+            usize::from(block.inner(area).width),
+        );
+        frame.render_widget(
+            Sparkline::default()
+                .block(block)
+                .data(&buckets)
+                .max(100)
+                .style(Style::new().fg(Color::Cyan)),
+            area,
+        );
+    }
+}
+
+/// Average percentage of `records` in `buckets` equal slices of the `span` before
+/// `end`, None for the slices without records
+pub fn charge_buckets(
+    records: &[HistoryRecord],
+    end: DateTime<FixedOffset>,
+    span: chrono::Duration,
+    buckets: usize,
+) -> Vec<Option<u64>> {
+    let mut sums = vec![(0.0, 0); buckets];
+    let start = end - span;
+    for record in records {
+        let Some(percentage) = record.battery_percentage else {
+            continue;
+        };
+        if record.time < start || record.time > end || buckets == 0 {
+            continue;
+        }
+        let offset = (record.time - start).num_seconds() as f64 / span.num_seconds() as f64;
+        let bucket = ((offset * buckets as f64) as usize).min(buckets - 1);
+        sums[bucket].0 += f64::from(percentage);
+        sums[bucket].1 += 1;
+    }
+    sums.into_iter()
+        .map(|(sum, count)| (count > 0).then(|| (sum / f64::from(count)).round() as u64))
+        .collect()
+}
+
+/// Draw the dashboard from `load` every `refresh` until q or Esc is pressed
+pub fn run<F>(refresh: Duration, mut load: F) -> Result<(), MyError>
+where
+    F: FnMut() -> Result<Dashboard, MyError>,
+{
+    let mut dashboard = load()?;
+    let mut terminal = ratatui::try_init()?;
+    let result = (|| {
+        let mut loaded = Instant::now();
+        loop {
+            terminal.draw(|frame| dashboard.render(frame))?;
+
+            let timeout = refresh.saturating_sub(loaded.elapsed());
+            if event::poll(timeout)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press
+                        && matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+                    {
+                        return Ok(());
+                    }
+                }
+            }
+            if loaded.elapsed() >= refresh {
+                dashboard = load()?;
+                loaded = Instant::now();
+            }
+        }
+    })();
+    ratatui::try_restore()?;
+    result
+}
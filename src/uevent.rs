@@ -0,0 +1,181 @@
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::mpsc::Receiver;
+use std::thread;
+
+use super::shutdown::PowerEvents;
+use super::utils::MyError;
+
+/// Kernel uevents are broadcast on the first netlink multicast group
+const KERNEL_UEVENT_GROUP: u32 = 1;
+const UEVENT_BUFFER_SIZE: usize = 8192;
+
+/// A change of a power supply (battery or AC adapter) reported by the kernel
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PowerEvent {
+    pub action: String,
+    /// POWER_SUPPLY_NAME, for example BAT1 or ACAD
+    pub supply_name: String,
+    /// POWER_SUPPLY_ONLINE, only sent by AC adapters
+    pub online: Option<bool>,
+    /// POWER_SUPPLY_STATUS, only sent by batteries
+    pub status: Option<String>,
+}
+
+impl PowerEvent {
+    /// Parse a raw kernel uevent: "action@devpath" followed by
+    /// NUL separated KEY=value pairs. Events of other subsystems give None.
+    pub fn parse(message: &[u8]) -> Option<Self> {
+        let message = String::from_utf8_lossy(message);
+        let mut fields = message.split('\0').filter(|field| !field.is_empty());
+        // The header repeats ACTION and DEVPATH
+        fields.next()?;
+
+        let mut action = None;
+        let mut subsystem = None;
+        let mut supply_name = None;
+        let mut online = None;
+        let mut status = None;
+        for field in fields {
+            let Some((key, value)) = field.split_once('=') else {
+                continue;
+            };
+            match key {
+                "ACTION" => action = Some(value.to_owned()),
+                "SUBSYSTEM" => subsystem = Some(value.to_owned()),
+                "POWER_SUPPLY_NAME" => supply_name = Some(value.to_owned()),
+                "POWER_SUPPLY_ONLINE" => online = Some(value == "1"),
+                "POWER_SUPPLY_STATUS" => status = Some(value.to_owned()),
+                _ => (),
+            }
+        }
+
+        if subsystem.as_deref() != Some("power_supply") {
+            return None;
+        }
+        Some(PowerEvent {
+            action: action?,
+            supply_name: supply_name.unwrap_or_default(),
+            online,
+            status,
+        })
+    }
+}
+
+/// Where power supply events come from, the kernel in the daemon and a channel in tests
+pub trait EventSource {
+    /// Block until the next power supply event, None when the source is closed
+    fn next_event(&mut self) -> Result<Option<PowerEvent>, MyError>;
+}
+
+/// Kernel uevents read from a NETLINK_KOBJECT_UEVENT socket
+pub struct NetlinkSource {
+    socket: OwnedFd,
+    buffer: Vec<u8>,
+}
+
+impl NetlinkSource {
+    pub fn new() -> Result<Self, MyError> {
+        // SAFETY: plain socket(2) call, the returned fd is checked before being owned
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                libc::NETLINK_KOBJECT_UEVENT,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        // SAFETY: fd is a valid socket that nobody else owns
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // SAFETY: sockaddr_nl is a plain C struct, all zeros is a valid value
+        let mut address: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        address.nl_groups = KERNEL_UEVENT_GROUP;
+        // SAFETY: address lives for the whole call and its size is passed along
+        let result = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok(NetlinkSource {
+            socket,
+            buffer: vec![0; UEVENT_BUFFER_SIZE],
+        })
+    }
+}
+
+impl EventSource for NetlinkSource {
+    fn next_event(&mut self) -> Result<Option<PowerEvent>, MyError> {
+        loop {
+            // SAFETY: the buffer is valid for writes of its whole length
+            let len = unsafe {
+                libc::recv(
+                    self.socket.as_raw_fd(),
+                    self.buffer.as_mut_ptr() as *mut libc::c_void,
+                    self.buffer.len(),
+                    0,
+                )
+            };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err.into());
+            }
+            if let Some(event) = PowerEvent::parse(&self.buffer[..len as usize]) {
+                return Ok(Some(event));
+            }
+        }
+    }
+}
+
+/// Events pushed by hand through a channel, used to replay plug/unplug in tests
+pub struct SyntheticSource {
+    events: Receiver<PowerEvent>,
+}
+
+impl SyntheticSource {
+    pub fn new(events: Receiver<PowerEvent>) -> Self {
+        SyntheticSource { events }
+    }
+}
+
+impl EventSource for SyntheticSource {
+    fn next_event(&mut self) -> Result<Option<PowerEvent>, MyError> {
+        Ok(self.events.recv().ok())
+    }
+}
+
+/// Forward every event of `source` to the loops waiting on `power_events`
+pub fn listen<S>(mut source: S, power_events: PowerEvents) -> io::Result<thread::JoinHandle<()>>
+where
+    S: EventSource + Send + 'static,
+{
+    thread::Builder::new()
+        .name("uevent".to_owned())
+        .spawn(move || loop {
+            match source.next_event() {
+                Ok(Some(event)) => {
+                    log::debug!("power supply event: {event:?}");
+                    power_events.notify();
+                }
+                Ok(None) => return,
+                Err(err) => {
+                    // The loops keep polling on their own
+                    log::error!("Stopped listening for uevents: {err}");
+                    return;
+                }
+            }
+        })
+}
@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use chrono::Datelike;
+use serde::Serialize;
+
+use super::analysis::{self, MAX_GAP};
+use super::battery_health::BatteryState;
+use super::history::HistoryRecord;
+use super::utils::MyError;
+
+const SECONDS_PER_HOUR: f64 = 60.0 * 60.0;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum UsagePeriod {
+    Daily,
+    /// ISO weeks, from Monday
+    Weekly,
+}
+
+/// How the battery was used in a day or a week
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct UsageSummary {
+    /// 2025-01-25 or 2025-W04
+    pub period: String,
+    /// Time covered by the history
+    pub logged_hours: f64,
+    pub charging_hours: f64,
+    pub discharging_hours: f64,
+    pub full_hours: f64,
+    /// On AC with the firmware holding the charge
+    pub not_charging_hours: f64,
+    /// Percentage points lost per hour on battery, None without discharging time
+    pub discharge_rate: Option<f64>,
+    /// Equivalent full cycles, 100 points of discharge each
+    pub cycles: f64,
+    /// Time with the percentage between the charge limits
+    pub window_hours: f64,
+}
+
+impl UsageSummary {
+    pub fn ac_hours(&self) -> f64 {
+        self.charging_hours + self.full_hours + self.not_charging_hours
+    }
+}
+
+fn period_name(record: &HistoryRecord, period: UsagePeriod) -> String {
+    match period {
+        UsagePeriod::Daily => record.time.format("%Y-%m-%d").to_string(),
+        UsagePeriod::Weekly => {
+            let week = record.time.iso_week();
+            format!("{}-W{:02}", week.year(), week.week())
+        }
+    }
+}
+
+/// Aggregate `records`, of any order, per `period`. The time between two records is
+/// given to the state and percentage of the first one, `window` is the (lower, upper)
+/// percentage range kept by the controller.
+pub fn usage_report(
+    records: &[HistoryRecord],
+    period: UsagePeriod,
+    window: (f32, f32),
+) -> Result<Vec<UsageSummary>, MyError> {
+    let mut records = records.to_vec();
+    records.sort_by_key(|record| record.time);
+    records.dedup_by_key(|record| record.time);
+    if records.is_empty() {
+        return Err(MyError::HealthStatsError("The history is empty".to_owned()));
+    }
+
+    let mut periods: BTreeMap<String, (UsageSummary, f64)> = BTreeMap::new();
+    for record in &records {
+        let name = period_name(record, period);
+        periods.entry(name.clone()).or_insert_with(|| {
+            (
+                UsageSummary {
+                    period: name,
+                    ..UsageSummary::default()
+                },
+                0.0,
+            )
+        });
+    }
+
+    for pair in records.windows(2) {
+        let (current, next) = (&pair[0], &pair[1]);
+        let (summary, discharged) = periods
+            .get_mut(&period_name(current, period))
+            .expect("every record has its period");
+
+        // The time over a gap isn't usage of the period
+        let elapsed = next.time - current.time;
+        if elapsed > MAX_GAP {
+            continue;
+        }
+
+        // Counted as in the health report
+        let dropped = analysis::discharged(current, next);
+        summary.cycles += dropped / 100.0;
+        let hours = elapsed.num_seconds() as f64 / SECONDS_PER_HOUR;
+        summary.logged_hours += hours;
+        match current.battery_status {
+            Some(BatteryState::Charging) => summary.charging_hours += hours,
+            Some(BatteryState::Discharging) => {
+                summary.discharging_hours += hours;
+                *discharged += dropped;
+            }
+            Some(BatteryState::Full) => summary.full_hours += hours,
+            Some(BatteryState::NotCharging) => summary.not_charging_hours += hours,
+            None => (),
+        }
+        if let Some(percentage) = current.battery_percentage {
+            if window.0 <= percentage && percentage <= window.1 {
+                summary.window_hours += hours;
+            }
+        }
+    }
+
+    Ok(periods
+        .into_values()
+        .map(|(mut summary, discharged)| {
+            if summary.discharging_hours > 0.0 {
+                summary.discharge_rate = Some(discharged / summary.discharging_hours);
+            }
+            summary
+        })
+        .collect())
+}
+
+/// The summaries as a table for the terminal
+pub fn format_table(summaries: &[UsageSummary], window: (f32, f32)) -> String {
+    let window_title = format!("{:.0}-{:.0}%", window.0, window.1);
+    let mut table = format!(
+        "{:<10} {:>7} {:>7} {:>7} {:>8} {:>7} {:>7} {:>8} {:>6} {:>7}\n",
+        "Period",
+        "Logged",
+        "On AC",
+        "Battery",
+        "Charging",
+        "Full",
+        "Holding",
+        "Rate %/h",
+        "Cycles",
+        window_title
+    );
+    for summary in summaries {
+        let rate = summary
+            .discharge_rate
+            .map_or("-".to_owned(), |rate| format!("{rate:.1}"));
+        let _ = writeln!(
+            table,
+            "{:<10} {:>6.1}h {:>6.1}h {:>6.1}h {:>7.1}h {:>6.1}h {:>6.1}h {:>8} {:>6.2} {:>6.1}h",
+            summary.period,
+            summary.logged_hours,
+            summary.ac_hours(),
+            summary.discharging_hours,
+            summary.charging_hours,
+            summary.full_hours,
+            summary.not_charging_hours,
+            rate,
+            summary.cycles,
+            summary.window_hours
+        );
+    }
+    table
+}
+
+pub fn to_json(summaries: &[UsageSummary]) -> Result<String, MyError> {
+    serde_json::to_string_pretty(summaries).map_err(|err| {
+        MyError::HealthStatsError(format!("Failed to write the usage as JSON: {err}"))
+    })
+}
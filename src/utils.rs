@@ -1,91 +1,311 @@
 use std::error::Error;
 use std::fs::File;
 use std::io::{self, Read};
+use std::net::SocketAddr;
 use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
 use std::{env, fmt};
 
+use super::actuator::Actuator;
+use super::ardu::ShutdownState;
+use super::critical::CriticalAction;
+use super::history::WritePolicy;
+use super::hooks::Hooks;
+use super::mqtt::MqttConfig;
+use super::rotation::{Rotation, RotationPolicy};
+use super::store::HistoryBackend;
+
 #[derive(Debug)]
 pub struct Config {
     battery_notifier: bool,
     health_stats: bool,
-    write_every: u64,
+    write_policy: WritePolicy,
+    critical_level: f32,
+    critical_grace: u64,
+    critical_action: CriticalAction,
+    shutdown_state: ShutdownState,
+    epoch_column: bool,
+    history_backend: HistoryBackend,
+    history_rotation: RotationPolicy,
+    metrics_address: Option<SocketAddr>,
+    mqtt: Option<MqttConfig>,
+    actuator: Actuator,
+    hooks: Hooks,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 enum ConfigOption {
     BatteryNotifier(bool),
     HealthStats(bool),
     WriteEvery(u64),
+    WriteDelta(f32),
+    WriteOnStatusChange(bool),
+    CriticalLevel(f32),
+    CriticalGrace(u64),
+    CriticalAction(CriticalAction),
+    ShutdownState(ShutdownState),
+    EpochColumn(bool),
+    HistoryBackend(HistoryBackend),
+    HistoryRotation(Rotation),
+    HistoryCompress(bool),
+    HistoryRetention(Option<Duration>),
+    MetricsAddress(SocketAddr),
+    MqttBroker(MqttConfig),
+    MqttUsername(String),
+    MqttPassword(String),
+    MqttDiscoveryPrefix(String),
+    Actuator(Actuator),
+    ActuatorConnectCommand(String),
+    ActuatorDisconnectCommand(String),
+    HookThreshold(String),
+    HookChargerConnected(String),
+    HookChargerDisconnected(String),
+    HookHealthLow(String),
+    HookHealthBelow(f32),
+    HookActuatorFailure(String),
+    HookTimeout(u64),
 }
 
+const DEFAULT_CRITICAL_LEVEL: f32 = 5.0;
+const DEFAULT_CRITICAL_GRACE: u64 = 120; // seconds
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
 impl Config {
-    fn validate_field(field: &str) {
+    fn validate_field(field: &str) -> Result<(), MyError> {
         match field {
-            "battery_notifier" | "health_stats" | "write_every" => (),
-            _ => {
-                log::error!("Provided field:'{field}' is not a valid config field");
-                panic!()
-            }
+            "battery_notifier" | "health_stats" | "write_every" | "write_delta"
+            | "write_on_status_change" | "critical_level"
+            | "critical_grace" | "critical_action" | "shutdown_state" | "epoch_column"
+            | "history_backend" | "history_rotation" | "history_compress"
+            | "history_retention_days" | "metrics_address" | "mqtt_broker" | "mqtt_username"
+            | "mqtt_password" | "mqtt_discovery_prefix" | "actuator"
+            | "actuator_connect_command" | "actuator_disconnect_command" | "hook_threshold"
+            | "hook_charger_connected" | "hook_charger_disconnected" | "hook_health_low"
+            | "hook_health_below" | "hook_actuator_failure" | "hook_timeout" => Ok(()),
+            _ => Err(MyError::ConfigError(format!(
+                "Provided field:'{field}' is not a valid config field"
+            ))),
+        }
+    }
+
+    fn parse_value<T>(option_value: &str, type_name: &str) -> Result<T, MyError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        option_value.parse::<T>().map_err(|err| {
+            MyError::ConfigError(format!(
+                "'{option_value}' is not parsable to {type_name} type:{err}"
+            ))
+        })
+    }
+
+    /// Days to keep the rotated files, 0 keeps them forever
+    fn parse_retention(option_value: &str) -> Result<Option<Duration>, MyError> {
+        let days: u64 = Self::parse_value(option_value, "u64")?;
+        if days == 0 {
+            return Ok(None);
         }
+        days.checked_mul(SECONDS_PER_DAY)
+            .map(|seconds| Some(Duration::from_secs(seconds)))
+            .ok_or_else(|| {
+                MyError::ConfigError(format!("history_retention_days={days} is too many days"))
+            })
     }
-    pub fn get(path: &str) -> Self {
-        let Ok(config) = read_file_as_string(Path::new(path)) else {
-            if let Ok(current_dir) = env::current_dir() {
-                println!("Current working directory: {}", current_dir.display());
-                println!("Tried to access {} from {}", path, current_dir.display());
-            } else {
-                eprintln!("Failed to get the current working directory");
-            };
-            eprintln!("Failed to find config file");
-            panic!()
+
+    fn parse_line(line: &str) -> Result<ConfigOption, MyError> {
+        // A password may contain '='
+        let mut splitted_line = line.splitn(2, '=');
+        let option_name = splitted_line.next().unwrap_or_default().trim();
+        Config::validate_field(option_name)?;
+
+        let Some(option_value) = splitted_line.next().map(str::trim) else {
+            return Err(MyError::ConfigError(format!(
+                "Missing field for '{option_name}'"
+            )));
+        };
+
+        let option = match option_name {
+            "battery_notifier" => ConfigOption::BatteryNotifier(Self::parse_value(option_value, "bool")?),
+            "health_stats" => ConfigOption::HealthStats(Self::parse_value(option_value, "bool")?),
+            "write_every" => ConfigOption::WriteEvery(Self::parse_value(option_value, "u64")?),
+            "write_delta" => ConfigOption::WriteDelta(Self::parse_value(option_value, "f32")?),
+            "write_on_status_change" => ConfigOption::WriteOnStatusChange(Self::parse_value(option_value, "bool")?),
+            "critical_level" => ConfigOption::CriticalLevel(Self::parse_value(option_value, "f32")?),
+            "critical_grace" => ConfigOption::CriticalGrace(Self::parse_value(option_value, "u64")?),
+            "critical_action" => ConfigOption::CriticalAction(CriticalAction::match_string(option_value)),
+            "shutdown_state" => ConfigOption::ShutdownState(ShutdownState::match_string(option_value)?),
+            "epoch_column" => ConfigOption::EpochColumn(Self::parse_value(option_value, "bool")?),
+            "history_backend" => ConfigOption::HistoryBackend(HistoryBackend::match_string(option_value)?),
+            "history_rotation" => ConfigOption::HistoryRotation(Rotation::match_string(option_value)?),
+            "history_compress" => ConfigOption::HistoryCompress(Self::parse_value(option_value, "bool")?),
+            "history_retention_days" => ConfigOption::HistoryRetention(Self::parse_retention(option_value)?),
+            "metrics_address" => ConfigOption::MetricsAddress(Self::parse_value(option_value, "socket address")?),
+            "mqtt_broker" => ConfigOption::MqttBroker(MqttConfig::new(option_value)?),
+            "mqtt_username" => ConfigOption::MqttUsername(option_value.to_owned()),
+            "mqtt_password" => ConfigOption::MqttPassword(option_value.to_owned()),
+            "mqtt_discovery_prefix" => ConfigOption::MqttDiscoveryPrefix(option_value.to_owned()),
+            "actuator" => ConfigOption::Actuator(Actuator::match_string(option_value)?),
+            "actuator_connect_command" => ConfigOption::ActuatorConnectCommand(option_value.to_owned()),
+            "actuator_disconnect_command" => ConfigOption::ActuatorDisconnectCommand(option_value.to_owned()),
+            "hook_threshold" => ConfigOption::HookThreshold(option_value.to_owned()),
+            "hook_charger_connected" => ConfigOption::HookChargerConnected(option_value.to_owned()),
+            "hook_charger_disconnected" => ConfigOption::HookChargerDisconnected(option_value.to_owned()),
+            "hook_health_low" => ConfigOption::HookHealthLow(option_value.to_owned()),
+            "hook_health_below" => ConfigOption::HookHealthBelow(Self::parse_value(option_value, "f32")?),
+            "hook_actuator_failure" => ConfigOption::HookActuatorFailure(option_value.to_owned()),
+            "hook_timeout" => ConfigOption::HookTimeout(Self::parse_value(option_value, "u64")?),
+            _ => unreachable!("validate_field accepted '{option_name}'"),
         };
+        Ok(option)
+    }
+
+    pub fn get(path: &str) -> Result<Self, MyError> {
+        let config = read_file_as_string(Path::new(path)).map_err(|err| {
+            match env::current_dir() {
+                Ok(current_dir) => MyError::ConfigError(format!(
+                    "Failed to find config file, tried to access {} from {}: {err}",
+                    path,
+                    current_dir.display()
+                )),
+                Err(_) => MyError::ConfigError(format!("Failed to find config file {path}: {err}")),
+            }
+        })?;
 
+        // Everything under the '____' separator line is free text
         let config = config
-            .split('\n')
-            .take(3)
-            .map(|line| {
-                let mut splitted_line = line.split('=');
-                let option_name = splitted_line.next().unwrap().trim();
-                Config::validate_field(option_name);
-
-                let option_value = match splitted_line.next(){
-                    Some(val) => val.trim(),
-                    None => {
-                        log::error!("Missing field for '{option_name}'");
-                        panic!()
-                    }
-                };
-
-                match option_name{
-                    "battery_notifier" => match option_value.parse::<bool>() {
-                        Ok(val) => ConfigOption::BatteryNotifier(val),
-                        Err(err) => panic!("Config file error: '{option_value}' is not parsable to bool type:{err}")
-                    },
-                    "health_stats" => match option_value.parse::<bool>() {
-                        Ok(val) => ConfigOption::HealthStats(val),
-                        Err(err) => panic!("Config file error: '{option_value}' is not parsable to bool type:{err}")
-                    },
-                    "write_every" => match option_value.parse::<u64>() {
-                        Ok(val) => ConfigOption::WriteEvery(val),
-                        Err(err) => panic!("Config file error: '{option_value}' is not parsable to u64 type:{err}")
-                    }
-                    _ => panic!("{option_name} is not a valid config option")
-                }})
-            .collect::<Vec<_>>();
-
-        if let (
-            ConfigOption::BatteryNotifier(battery_notifier),
-            ConfigOption::HealthStats(health_stats),
-            ConfigOption::WriteEvery(write_every),
-        ) = (config[0], config[1], config[2])
+            .lines()
+            .take_while(|line| !line.starts_with('_'))
+            .filter(|line| !line.trim().is_empty())
+            .map(Config::parse_line)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut battery_notifier = None;
+        let mut health_stats = None;
+        let mut write_every = None;
+        let mut write_delta = None;
+        let mut write_on_status_change = false;
+        let mut critical_level = DEFAULT_CRITICAL_LEVEL;
+        let mut critical_grace = DEFAULT_CRITICAL_GRACE;
+        let mut critical_action = CriticalAction::Disabled;
+        let mut shutdown_state = ShutdownState::Unchanged;
+        let mut epoch_column = false;
+        let mut history_backend = HistoryBackend::Csv;
+        let mut history_rotation = RotationPolicy::default();
+        let mut metrics_address = None;
+        let mut mqtt = None;
+        let mut mqtt_username = None;
+        let mut mqtt_password = None;
+        let mut mqtt_discovery_prefix = None;
+        let mut actuator = Actuator::Arduino;
+        let mut actuator_connect_command = None;
+        let mut actuator_disconnect_command = None;
+        let mut hooks = Hooks::default();
+
+        for option in config {
+            match option {
+                ConfigOption::BatteryNotifier(val) => battery_notifier = Some(val),
+                ConfigOption::HealthStats(val) => health_stats = Some(val),
+                ConfigOption::WriteEvery(val) => write_every = Some(val),
+                ConfigOption::WriteDelta(val) => write_delta = Some(val),
+                ConfigOption::WriteOnStatusChange(val) => write_on_status_change = val,
+                ConfigOption::CriticalLevel(val) => critical_level = val,
+                ConfigOption::CriticalGrace(val) => critical_grace = val,
+                ConfigOption::CriticalAction(val) => critical_action = val,
+                ConfigOption::ShutdownState(val) => shutdown_state = val,
+                ConfigOption::EpochColumn(val) => epoch_column = val,
+                ConfigOption::HistoryBackend(val) => history_backend = val,
+                ConfigOption::HistoryRotation(val) => history_rotation.rotation = val,
+                ConfigOption::HistoryCompress(val) => history_rotation.compress = val,
+                ConfigOption::HistoryRetention(val) => history_rotation.retention = val,
+                ConfigOption::MetricsAddress(val) => metrics_address = Some(val),
+                ConfigOption::MqttBroker(val) => mqtt = Some(val),
+                ConfigOption::MqttUsername(val) => mqtt_username = Some(val),
+                ConfigOption::MqttPassword(val) => mqtt_password = Some(val),
+                ConfigOption::MqttDiscoveryPrefix(val) => mqtt_discovery_prefix = Some(val),
+                ConfigOption::Actuator(val) => actuator = val,
+                ConfigOption::ActuatorConnectCommand(val) => actuator_connect_command = Some(val),
+                ConfigOption::ActuatorDisconnectCommand(val) => actuator_disconnect_command = Some(val),
+                ConfigOption::HookThreshold(val) => hooks.threshold = Some(val),
+                ConfigOption::HookChargerConnected(val) => hooks.charger_connected = Some(val),
+                ConfigOption::HookChargerDisconnected(val) => hooks.charger_disconnected = Some(val),
+                ConfigOption::HookHealthLow(val) => hooks.health_low = Some(val),
+                ConfigOption::HookHealthBelow(val) => hooks.health_below = val,
+                ConfigOption::HookActuatorFailure(val) => hooks.actuator_failure = Some(val),
+                ConfigOption::HookTimeout(val) => hooks.timeout = Duration::from_secs(val),
+            }
+        }
+
+        // The other mqtt options only make sense with a broker
+        let mqtt = match mqtt {
+            Some(mqtt) => Some(MqttConfig {
+                username: mqtt_username,
+                password: mqtt_password,
+                discovery_prefix: mqtt_discovery_prefix.unwrap_or(mqtt.discovery_prefix),
+                ..mqtt
+            }),
+            None if mqtt_username.is_some()
+                || mqtt_password.is_some()
+                || mqtt_discovery_prefix.is_some() =>
+            {
+                return Err(MyError::ConfigError(
+                    "mqtt_username, mqtt_password and mqtt_discovery_prefix need mqtt_broker"
+                        .to_owned(),
+                ))
+            }
+            None => None,
+        };
+
+        // The command actuator needs both commands, the others none
+        let actuator = match (actuator, actuator_connect_command, actuator_disconnect_command) {
+            (Actuator::Command { .. }, Some(connect), Some(disconnect)) => Actuator::Command {
+                connect,
+                disconnect,
+            },
+            (Actuator::Command { .. }, _, _) => {
+                return Err(MyError::ConfigError(
+                    "actuator = command needs actuator_connect_command and actuator_disconnect_command"
+                        .to_owned(),
+                ))
+            }
+            (actuator, None, None) => actuator,
+            (_, _, _) => {
+                return Err(MyError::ConfigError(
+                    "actuator_connect_command and actuator_disconnect_command need actuator = command"
+                        .to_owned(),
+                ))
+            }
+        };
+
+        // The first three options are mandatory, the others fall back to their defaults
+        if let (Some(battery_notifier), Some(health_stats), Some(write_every)) =
+            (battery_notifier, health_stats, write_every)
         {
-            Config {
+            Ok(Config {
                 battery_notifier,
                 health_stats,
-                write_every,
-            }
+                write_policy: WritePolicy {
+                    delta: write_delta,
+                    on_status_change: write_on_status_change,
+                    ..WritePolicy::every(write_every)
+                },
+                critical_level,
+                critical_grace,
+                critical_action,
+                shutdown_state,
+                epoch_column,
+                history_backend,
+                history_rotation,
+                metrics_address,
+                mqtt,
+                actuator,
+                hooks,
+            })
         } else {
-            panic!("Impossible to parse config")
+            Err(MyError::ConfigError(
+                "battery_notifier, health_stats and write_every are mandatory".to_owned(),
+            ))
         }
     }
 
@@ -97,24 +317,71 @@ impl Config {
         self.health_stats
     }
 
-    pub fn write_every(&self) -> u64 {
-        self.write_every
+    pub fn write_policy(&self) -> WritePolicy {
+        self.write_policy
+    }
+
+    pub fn critical_level(&self) -> f32 {
+        self.critical_level
     }
-}
 
-pub fn notify_percentage(level: &str, message: &str) {
-std::process::Command::new("paplay")
-    .arg("/usr/share/sounds/freedesktop/stereo/complete.oga")
-    .output()
-    .expect("errore con paplay");
+    pub fn critical_grace(&self) -> u64 {
+        self.critical_grace
+    }
+
+    pub fn critical_action(&self) -> &CriticalAction {
+        &self.critical_action
+    }
+
+    pub fn shutdown_state(&self) -> ShutdownState {
+        self.shutdown_state
+    }
+
+    pub fn epoch_column(&self) -> bool {
+        self.epoch_column
+    }
+
+    pub fn history_backend(&self) -> HistoryBackend {
+        self.history_backend
+    }
+
+    /// Where the exporter listens, None if it is disabled
+    pub fn metrics_address(&self) -> Option<SocketAddr> {
+        self.metrics_address
+    }
+
+    /// The broker to publish on, None if MQTT is disabled
+    pub fn mqtt(&self) -> Option<&MqttConfig> {
+        self.mqtt.as_ref()
+    }
+
+    /// What switches the charger, the arduino by default
+    pub fn actuator(&self) -> &Actuator {
+        &self.actuator
+    }
 
+    pub fn hooks(&self) -> &Hooks {
+        &self.hooks
+    }
+
+    pub fn history_rotation(&self) -> RotationPolicy {
+        self.history_rotation
+    }
+}
+
+pub fn notify_percentage(level: &str, message: &str) -> Result<(), MyError> {
+    std::process::Command::new("paplay")
+        .arg("/usr/share/sounds/freedesktop/stereo/complete.oga")
+        .output()
+        .map_err(|err| MyError::NotifierError(format!("errore con paplay: {err}")))?;
 
     let title = format!("Batteria {}!", level);
     std::process::Command::new("notify-send")
         .arg(title)
         .arg(message)
         .output()
-        .expect("Something went wrong in getting output from command");
+        .map_err(|err| MyError::NotifierError(format!("notify-send failed: {err}")))?;
+    Ok(())
 }
 
 pub fn read_file_as_string(file_path: &Path) -> io::Result<String> {
@@ -128,29 +395,51 @@ pub fn read_file_as_string(file_path: &Path) -> io::Result<String> {
 }
 
 // Define an enum for errors
-#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum MyError {
+    BatteryError(String),
+    ActuatorError(String),
+    ConfigError(String),
+    IoError(io::Error),
     NotifierError(String),
     HealthStatsError(String),
+    MqttError(String),
+    HookError(String),
 }
 
 impl fmt::Display for MyError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            MyError::BatteryError(msg) => write!(f, "Battery error: {}", msg),
+            MyError::ActuatorError(msg) => write!(f, "Actuator error: {}", msg),
+            MyError::ConfigError(msg) => write!(f, "Config error: {}", msg),
+            MyError::IoError(err) => write!(f, "IO error: {}", err),
             MyError::NotifierError(msg) => write!(f, "Notifier error: {}", msg),
             MyError::HealthStatsError(msg) => write!(f, "Health stats error: {}", msg),
+            MyError::MqttError(msg) => write!(f, "MQTT error: {}", msg),
+            MyError::HookError(msg) => write!(f, "Hook error: {}", msg),
         }
     }
 }
 
-impl Error for MyError {}
+// main prints the errors it returns with Debug, the commands show the message instead
+impl fmt::Debug for MyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
 
-// Function that may return a NotifierError
-/*
-fn notifier_function_example() -> Result<(), MyError> {
-    // Simulate an error in the notifier function
-    Err(MyError::NotifierError(String::from(
-        "Failed to send notification",
-    )))
+impl Error for MyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MyError::IoError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MyError {
+    fn from(err: io::Error) -> Self {
+        MyError::IoError(err)
+    }
 }
-*/
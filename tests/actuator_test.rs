@@ -0,0 +1,158 @@
+// Import the actuators from the main module
+#[path = "../src/main.rs"]
+#[allow(dead_code)]
+mod main;
+
+use main::actuator::Actuator;
+use main::ardu::{ArduCommand, ArduSketch, CommandState, SharedMode, REACTION_TIMEOUT};
+use main::battery_health::{BatterySnapshot, BatteryState};
+use main::sampler::{RecordedSource, Sampler};
+use main::shutdown::Shutdown;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    /// A plug answering one request with `body`, returns the request line
+    fn plug(body: &'static str) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // The whole request is read, closing with unread bytes resets the connection
+            let mut reader = BufReader::new(&stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut header = String::new();
+            while header != "\r\n" {
+                header.clear();
+                reader.read_line(&mut header).unwrap();
+            }
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+            request_line.trim().to_owned()
+        });
+        (host, handle)
+    }
+
+    fn snapshot(state: BatteryState) -> BatterySnapshot {
+        BatterySnapshot {
+            state,
+            ..BatterySnapshot::default()
+        }
+    }
+
+    #[test]
+    fn test_http_plugs() {
+        let (host, request) = plug(r#"{"POWER":"ON"}"#);
+        Actuator::Tasmota(host).switch(ArduSketch::Connect).unwrap();
+        assert_eq!(request.join().unwrap(), "GET /cm?cmnd=Power%20On HTTP/1.1");
+
+        // The plug answered but didn't switch
+        let (host, request) = plug(r#"{"POWER1":"ON"}"#);
+        assert!(Actuator::Tasmota(host).switch(ArduSketch::Disconnect).is_err());
+        assert_eq!(request.join().unwrap(), "GET /cm?cmnd=Power%20Off HTTP/1.1");
+
+        let (host, request) = plug(r#"{"was_on":true}"#);
+        let shelly = Actuator::Shelly { host, gen1: false };
+        shelly.switch(ArduSketch::Disconnect).unwrap();
+        assert_eq!(request.join().unwrap(), "GET /rpc/Switch.Set?id=0&on=false HTTP/1.1");
+
+        let (host, request) = plug(r#"{"ison":true,"has_timer":false}"#);
+        let shelly = Actuator::Shelly { host, gen1: true };
+        shelly.switch(ArduSketch::Connect).unwrap();
+        assert_eq!(request.join().unwrap(), "GET /relay/0?turn=on HTTP/1.1");
+
+        // Nothing listens there anymore
+        let host = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        assert!(Actuator::Tasmota(host.clone()).switch(ArduSketch::Connect).is_err());
+        // DoNothing leaves the plug alone
+        Actuator::Tasmota(host).switch(ArduSketch::DoNothing).unwrap();
+    }
+
+    #[test]
+    fn test_command_actuator_waits_for_the_battery() {
+        let actuator = Actuator::Command {
+            connect: "true".to_owned(),
+            disconnect: "exit 3".to_owned(),
+        };
+        let mut sampler = Sampler::new();
+        let receiver = sampler.subscribe();
+        sampler
+            .run(
+                &mut RecordedSource::new(vec![snapshot(BatteryState::Discharging), snapshot(BatteryState::Charging)]),
+                Duration::ZERO,
+                &Shutdown::new(),
+            )
+            .unwrap();
+        // Triggered, the pause after the reaction is skipped
+        let shutdown = Shutdown::new();
+        shutdown.trigger();
+
+        let connect = ArduCommand {
+            command_type: ArduSketch::Connect,
+            state: CommandState::ToExecute,
+        };
+        let reaction = connect.execute(&actuator, &SharedMode::default(), &receiver, &shutdown).unwrap().unwrap();
+        assert_eq!(reaction.snapshot.state, BatteryState::Charging);
+
+        let disconnect = ArduCommand {
+            command_type: ArduSketch::Disconnect,
+            state: CommandState::ToExecute,
+        };
+        let err = disconnect.execute(&actuator, &SharedMode::default(), &receiver, &shutdown).unwrap_err();
+        assert!(err.to_string().contains("'exit 3' failed"), "{err}");
+    }
+
+    #[test]
+    fn test_battery_ignoring_the_plug() {
+        // The plug answers but the battery never starts charging
+        let actuator = Actuator::Command {
+            connect: "true".to_owned(),
+            disconnect: "true".to_owned(),
+        };
+        let mut late = snapshot(BatteryState::Discharging);
+        late.time += REACTION_TIMEOUT + Duration::from_secs(1);
+        let (sampler, receiver) = mpsc::channel();
+        sampler.send(snapshot(BatteryState::Discharging)).unwrap();
+        sampler.send(late).unwrap();
+
+        let connect = ArduCommand {
+            command_type: ArduSketch::Connect,
+            state: CommandState::ToExecute,
+        };
+        let err = connect
+            .execute(&actuator, &SharedMode::default(), &receiver, &Shutdown::new())
+            .unwrap_err();
+        assert!(err.to_string().contains("The battery is still Discharging"), "{err}");
+        assert!(err.to_string().ends_with("s after connect_charger"), "{err}");
+    }
+
+    #[test]
+    fn test_match_string() {
+        assert_eq!(Actuator::match_string("arduino").unwrap(), Actuator::Arduino);
+        assert_eq!(
+            Actuator::match_string("tasmota  192.168.1.50").unwrap(),
+            Actuator::Tasmota("192.168.1.50".to_owned())
+        );
+        assert_eq!(
+            Actuator::match_string("shelly1 plug.lan:8080").unwrap(),
+            Actuator::Shelly {
+                host: "plug.lan:8080".to_owned(),
+                gen1: true
+            }
+        );
+        assert!(Actuator::match_string("tasmota").is_err());
+        assert!(Actuator::match_string("arduino uno").is_err());
+        assert!(Actuator::match_string("zigbee").is_err());
+    }
+}
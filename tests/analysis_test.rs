@@ -0,0 +1,165 @@
+// Import the health analysis from the main module
+#[path = "../src/main.rs"]
+#[allow(dead_code)]
+mod main;
+
+use main::analysis::{self, HealthReport, LinearFit};
+use main::battery_health::{BatteryIdentity, BatteryState};
+use main::history::{self, HistoryRecord};
+use main::usage::{self, UsagePeriod};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, Duration};
+    use std::path::Path;
+
+    /// Health going from 90% down 0.01 points a day, one full discharge a day logged every hour
+    fn linear_history(days: i64, noise: f32) -> Vec<HistoryRecord> {
+        let start = DateTime::parse_from_rfc3339("2024-01-01T08:00:00+01:00").unwrap();
+        (0..days)
+            .flat_map(|day| {
+                // Alternating noise keeps the trend but gives the fit some error
+                let health = 90.0 - 0.01 * day as f32 + if day % 2 == 0 { noise } else { -noise };
+                (0..=10).map(move |hour| HistoryRecord {
+                    time: start + Duration::days(day) + Duration::hours(hour),
+                    charge_full: 3000,
+                    charge_full_design: 3500,
+                    battery_health: health,
+                    battery_percentage: Some(100.0 - 10.0 * hour as f32),
+                    battery_status: Some(BatteryState::Discharging),
+                    battery: None,
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_linear_fit() {
+        let fit = LinearFit::fit(&[(0.0, 1.0), (1.0, 3.0), (2.0, 5.0)]).unwrap();
+        assert!((fit.slope - 2.0).abs() < 1e-9);
+        assert!((fit.intercept - 1.0).abs() < 1e-9);
+        assert!(fit.slope_stderr < 1e-9);
+        assert!((fit.at(3.0) - 7.0).abs() < 1e-9);
+        // Going up, it never goes down to 0
+        assert_eq!(fit.reaches(0.0), None);
+
+        assert!(LinearFit::fit(&[(0.0, 1.0), (1.0, 3.0)]).is_none());
+        assert!(LinearFit::fit(&[(1.0, 1.0), (1.0, 3.0), (1.0, 5.0)]).is_none());
+    }
+
+    #[test]
+    fn test_health_forecast() {
+        let report = HealthReport::new(&linear_history(200, 0.0), &[80.0, 70.0]).unwrap();
+        let start = DateTime::parse_from_rfc3339("2024-01-01T08:00:00+01:00").unwrap();
+
+        assert_eq!(report.samples, 2200);
+        assert!((report.per_day.slope + 0.01).abs() < 1e-6);
+        // 10 points at 0.01 a day
+        let expected = report.forecasts[0].expected.unwrap();
+        assert!((expected - start - Duration::days(1000)).num_hours().abs() <= 1);
+        let per_cycle = report.per_cycle.unwrap();
+        assert!((per_cycle.slope + 0.01).abs() < 1e-6);
+        assert!((report.total_cycles - 200.0).abs() < 1e-6);
+        // 80% after 1000 cycles from the start
+        assert!((report.cycle_forecasts[0].expected.unwrap() - 800.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_health_forecast_bounds() {
+        let report = HealthReport::new(&linear_history(200, 0.3), &[80.0]).unwrap();
+        let forecast = &report.forecasts[0];
+        let (earliest, expected, latest) = (
+            forecast.earliest.unwrap(),
+            forecast.expected.unwrap(),
+            forecast.latest.unwrap(),
+        );
+        assert!(earliest < expected && expected < latest);
+        assert!(report.to_string().contains("80%: expected "));
+    }
+
+    #[test]
+    fn test_flat_health_forecast() {
+        // The slope is a hair under 0, the targets are further than a date can go
+        let start = DateTime::parse_from_rfc3339("2024-01-01T08:00:00+01:00").unwrap();
+        let records = [85.0, 85.00001, 84.99999, 85.0]
+            .into_iter()
+            .enumerate()
+            .map(|(index, health)| HistoryRecord {
+                time: start + Duration::days(100 * index as i64),
+                charge_full: 2975,
+                charge_full_design: 3500,
+                battery_health: health,
+                battery_percentage: None,
+                battery_status: None,
+                battery: None,
+            })
+            .collect::<Vec<_>>();
+        let report = HealthReport::new(&records, &[80.0, 70.0]).unwrap();
+        assert!(report.per_day.slope < 0.0);
+        assert!(report.forecasts.iter().all(|forecast| forecast.expected.is_none()));
+        assert!(report.to_string().contains("80%: the health isn't going down, no forecast"), "{report}");
+    }
+
+    #[test]
+    fn test_cycles_counted_as_usage() {
+        let mut records = linear_history(4, 0.0);
+        // Suspended for the night at 40%, 30% in the morning
+        let night = records.len() - 4;
+        for record in &mut records[night..] {
+            record.time += Duration::hours(8);
+        }
+        let report = HealthReport::new(&records, &[80.0]).unwrap();
+        let usage = usage::usage_report(&records, UsagePeriod::Daily, (20.0, 80.0)).unwrap();
+        let usage_cycles = usage.iter().map(|summary| summary.cycles).sum::<f64>();
+
+        // The 10 points lost over the night aren't counted in either report
+        assert!((report.total_cycles - 3.9).abs() < 1e-6, "{}", report.total_cycles);
+        assert!((usage_cycles - report.total_cycles).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_health_report_legacy_files() {
+        // The oldest files mix two layouts and have no percentage in the first rows
+        let mut records = history::read_history(Path::new("data/battery_stats.csv")).unwrap();
+        records.extend(history::read_history(Path::new("data/data_with_hour.csv")).unwrap());
+        let report = HealthReport::new(&records, &[80.0, 70.0]).unwrap();
+        assert!(report.samples < records.len(), "duplicated rows are counted once");
+        assert!(report.current_health > 80.0 && report.current_health < 90.0);
+
+        assert!(HealthReport::new(&records[..3], &[80.0]).is_err());
+        assert!(HealthReport::new(&[], &[80.0]).is_err());
+    }
+
+    #[test]
+    fn test_split_by_battery() {
+        let battery = |serial_number: &str| BatteryIdentity {
+            manufacturer: "SMP".to_owned(),
+            model_name: "L19M3PF3".to_owned(),
+            serial_number: serial_number.to_owned(),
+            charge_full_design: 3500,
+        };
+        let mut records = linear_history(9, 0.0);
+        // Logged before the batteries were told apart, then the first pack, then a new one
+        for (index, record) in records.iter_mut().enumerate() {
+            record.battery = match index {
+                0..=32 => None,
+                33..=65 => Some(battery("123")),
+                _ => Some(battery("456")),
+            };
+        }
+        // A row of some other battery without identity
+        records.push(HistoryRecord {
+            charge_full_design: 5000,
+            ..records[0].clone()
+        });
+
+        let batteries = analysis::split_by_battery(&records);
+        let sizes = batteries
+            .iter()
+            .map(|(battery, records)| (battery.clone(), records.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, [(None, 1), (Some(battery("123")), 66), (Some(battery("456")), 33)]);
+        assert!(batteries[1].1.windows(2).all(|pair| pair[0].time <= pair[1].time));
+    }
+}
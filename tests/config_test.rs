@@ -0,0 +1,183 @@
+// Import the config from the main module
+#[path = "../src/main.rs"]
+#[allow(dead_code)]
+mod main;
+
+use main::actuator::Actuator;
+use main::ardu::ShutdownState;
+use main::critical::CriticalAction;
+use main::rotation::Rotation;
+use main::store::HistoryBackend;
+use main::utils::{Config, MyError};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    /// The mandatory options followed by `options`
+    fn config(options: &str) -> Result<Config, MyError> {
+        let temp_dir = tempdir::TempDir::new("config").expect("Failed to create temporary directory");
+        let path = temp_dir.path().join("config.txt");
+        fs::write(&path, format!("battery_notifier = true\nhealth_stats = false\nwrite_every = 5\n{options}\n____\n"))
+            .unwrap();
+        Config::get(path.to_str().unwrap())
+    }
+
+    #[test]
+    fn test_mandatory_options() {
+        let defaults = config("").unwrap();
+        assert!(defaults.battery_notifier());
+        assert!(!defaults.health_stats());
+        assert_eq!(defaults.write_policy().max_interval, chrono::Duration::minutes(5));
+
+        let temp_dir = tempdir::TempDir::new("config").expect("Failed to create temporary directory");
+        let path = temp_dir.path().join("config.txt");
+        fs::write(&path, "battery_notifier = true\nhealth_stats = false\n____\n").unwrap();
+        assert!(Config::get(path.to_str().unwrap()).is_err());
+        assert!(Config::get(temp_dir.path().join("missing.txt").to_str().unwrap()).is_err());
+
+        assert!(config("write_everything = 5").is_err());
+        assert!(config("epoch_column").is_err());
+        assert!(config("epoch_column = yes").is_err());
+    }
+
+    #[test]
+    fn test_write_policy() {
+        let policy = config("").unwrap().write_policy();
+        assert_eq!((policy.delta, policy.on_status_change), (None, false));
+        let policy = config("write_delta = 2.5\nwrite_on_status_change = true").unwrap().write_policy();
+        assert_eq!((policy.delta, policy.on_status_change), (Some(2.5), true));
+        assert!(config("write_delta = much").is_err());
+    }
+
+    #[test]
+    fn test_critical_options() {
+        let defaults = config("").unwrap();
+        assert_eq!(defaults.critical_level(), 5.0);
+        assert_eq!(defaults.critical_grace(), 120);
+        assert_eq!(defaults.critical_action(), &CriticalAction::Disabled);
+        assert_eq!(defaults.shutdown_state(), ShutdownState::Unchanged);
+
+        let custom = config(
+            "critical_level = 3.5\ncritical_grace = 60\ncritical_action = systemctl poweroff\nshutdown_state = connected",
+        )
+        .unwrap();
+        assert_eq!(custom.critical_level(), 3.5);
+        assert_eq!(custom.critical_grace(), 60);
+        assert_eq!(custom.critical_action(), &CriticalAction::Custom("systemctl poweroff".to_owned()));
+        assert_eq!(custom.shutdown_state(), ShutdownState::Connected);
+        assert_eq!(config("critical_action = hibernate").unwrap().critical_action(), &CriticalAction::Hibernate);
+        assert!(config("critical_grace = -1").is_err());
+        assert!(config("shutdown_state = disconnected").is_err());
+    }
+
+    #[test]
+    fn test_history_options() {
+        let defaults = config("").unwrap();
+        assert!(!defaults.epoch_column());
+        assert_eq!(defaults.history_backend(), HistoryBackend::Csv);
+        assert_eq!(defaults.history_rotation().rotation, Rotation::Never);
+        assert!(!defaults.history_rotation().compress);
+
+        let custom = config("epoch_column = true\nhistory_backend = sqlite\nhistory_rotation = 10mb\nhistory_compress = true")
+            .unwrap();
+        assert!(custom.epoch_column());
+        assert_eq!(custom.history_backend(), HistoryBackend::Sqlite);
+        assert_eq!(custom.history_rotation().rotation, Rotation::Size(10 * 1024 * 1024));
+        assert!(custom.history_rotation().compress);
+        assert_eq!(config("history_rotation = monthly").unwrap().history_rotation().rotation, Rotation::Monthly);
+        assert!(config("history_backend = postgres").is_err());
+        assert!(config("history_rotation = 10gb").is_err());
+        assert!(config("history_rotation = 5€").is_err());
+        // More bytes than a u64 holds
+        assert!(config("history_rotation = 99999999999999999mb").is_err());
+    }
+
+    #[test]
+    fn test_metrics_and_mqtt_options() {
+        assert_eq!(config("").unwrap().metrics_address(), None);
+        assert_eq!(
+            config("metrics_address = 127.0.0.1:9101").unwrap().metrics_address(),
+            Some("127.0.0.1:9101".parse::<SocketAddr>().unwrap())
+        );
+        assert!(config("metrics_address = localhost").is_err());
+
+        assert!(config("").unwrap().mqtt().is_none());
+        let mqtt = config(
+            "mqtt_broker = broker.lan\nmqtt_username = energy\nmqtt_password = a=b\nmqtt_discovery_prefix = ha",
+        )
+        .unwrap()
+        .mqtt()
+        .cloned()
+        .unwrap();
+        assert_eq!((mqtt.host.as_str(), mqtt.port), ("broker.lan", 1883));
+        assert_eq!(mqtt.username.as_deref(), Some("energy"));
+        // Split on the first '=' only
+        assert_eq!(mqtt.password.as_deref(), Some("a=b"));
+        assert_eq!(mqtt.discovery_prefix, "ha");
+        assert_eq!(config("mqtt_broker = broker.lan").unwrap().mqtt().unwrap().discovery_prefix, "homeassistant");
+        assert!(config("mqtt_username = energy").is_err());
+        assert!(config("mqtt_broker = broker.lan:mqtt").is_err());
+    }
+
+    #[test]
+    fn test_actuator_options() {
+        assert_eq!(config("").unwrap().actuator(), &Actuator::Arduino);
+        assert_eq!(
+            config("actuator = tasmota 192.168.1.50").unwrap().actuator(),
+            &Actuator::Tasmota("192.168.1.50".to_owned())
+        );
+        assert_eq!(
+            config("actuator = command\nactuator_connect_command = plug on\nactuator_disconnect_command = plug off")
+                .unwrap()
+                .actuator(),
+            &Actuator::Command {
+                connect: "plug on".to_owned(),
+                disconnect: "plug off".to_owned()
+            }
+        );
+        // Both commands, and only with the command actuator
+        assert!(config("actuator = command\nactuator_connect_command = plug on").is_err());
+        assert!(config("actuator_connect_command = plug on\nactuator_disconnect_command = plug off").is_err());
+        assert!(config("actuator = zigbee").is_err());
+    }
+
+    #[test]
+    fn test_hook_options() {
+        let hooks = config("").unwrap().hooks().clone();
+        assert!(hooks.is_empty());
+        assert_eq!(hooks.health_below, 80.0);
+        assert_eq!(hooks.timeout, Duration::from_secs(30));
+
+        let hooks = config(
+            "hook_threshold = echo threshold\nhook_charger_connected = echo connected\n\
+             hook_charger_disconnected = echo disconnected\nhook_health_low = echo low\n\
+             hook_health_below = 75\nhook_actuator_failure = echo failure\nhook_timeout = 5",
+        )
+        .unwrap()
+        .hooks()
+        .clone();
+        assert_eq!(hooks.threshold.as_deref(), Some("echo threshold"));
+        assert_eq!(hooks.charger_connected.as_deref(), Some("echo connected"));
+        assert_eq!(hooks.charger_disconnected.as_deref(), Some("echo disconnected"));
+        assert_eq!(hooks.health_low.as_deref(), Some("echo low"));
+        assert_eq!(hooks.health_below, 75.0);
+        assert_eq!(hooks.actuator_failure.as_deref(), Some("echo failure"));
+        assert_eq!(hooks.timeout, Duration::from_secs(5));
+        assert!(config("hook_timeout = 5s").is_err());
+    }
+
+    #[test]
+    fn test_history_retention_days() {
+        let retention = |options| config(options).unwrap().history_rotation().retention;
+        assert_eq!(retention(""), None);
+        assert_eq!(retention("history_retention_days = 0"), None);
+        assert_eq!(retention("history_retention_days = 30"), Some(Duration::from_secs(30 * 24 * 60 * 60)));
+        // More seconds than a u64 holds
+        assert!(config("history_retention_days = 300000000000000").is_err());
+        assert!(config("history_retention_days = -1").is_err());
+    }
+}
@@ -0,0 +1,109 @@
+// Import the critical policy from the main module
+#[path = "../src/main.rs"]
+#[allow(dead_code)]
+mod main;
+
+use main::battery_health::BatteryState;
+use main::critical::{CommandRunner, CriticalAction, CriticalPolicy, CriticalStatus};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io;
+    use std::process::ExitStatus;
+    use std::time::{Duration, Instant};
+
+    /// Records the commands instead of running them
+    #[derive(Default)]
+    struct FakeRunner {
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl CommandRunner for FakeRunner {
+        fn run(&self, program: &str, args: &[&str]) -> io::Result<ExitStatus> {
+            self.calls
+                .borrow_mut()
+                .push(format!("{program} {}", args.join(" ")));
+            Ok(ExitStatus::default())
+        }
+    }
+
+    #[test]
+    fn test_suspend_after_grace_period() {
+        let runner = FakeRunner::default();
+        let mut policy = CriticalPolicy::new(5.0, Duration::from_secs(60), CriticalAction::Suspend);
+        let start = Instant::now();
+
+        let status = policy.check(4.0, &BatteryState::Discharging, start, &runner);
+        assert_eq!(status, CriticalStatus::Entered);
+
+        let status = policy.check(
+            3.9,
+            &BatteryState::Discharging,
+            start + Duration::from_secs(30),
+            &runner,
+        );
+        assert_eq!(status, CriticalStatus::Waiting);
+        assert!(runner.calls.borrow().is_empty());
+
+        let status = policy.check(
+            3.8,
+            &BatteryState::Discharging,
+            start + Duration::from_secs(61),
+            &runner,
+        );
+        assert_eq!(status, CriticalStatus::Triggered);
+        assert_eq!(*runner.calls.borrow(), vec!["systemctl suspend".to_owned()]);
+    }
+
+    #[test]
+    fn test_charger_connected_during_grace_period() {
+        let runner = FakeRunner::default();
+        let mut policy =
+            CriticalPolicy::new(5.0, Duration::from_secs(60), CriticalAction::Hibernate);
+        let start = Instant::now();
+
+        policy.check(4.0, &BatteryState::Discharging, start, &runner);
+        let status = policy.check(
+            4.0,
+            &BatteryState::Charging,
+            start + Duration::from_secs(30),
+            &runner,
+        );
+        assert_eq!(status, CriticalStatus::Normal);
+
+        // The grace period starts again from scratch
+        let status = policy.check(
+            4.0,
+            &BatteryState::Discharging,
+            start + Duration::from_secs(90),
+            &runner,
+        );
+        assert_eq!(status, CriticalStatus::Entered);
+        assert!(runner.calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_custom_and_disabled_action() {
+        let runner = FakeRunner::default();
+        let start = Instant::now();
+
+        let mut policy = CriticalPolicy::new(
+            10.0,
+            Duration::ZERO,
+            CriticalAction::match_string("loginctl lock-session"),
+        );
+        policy.check(9.0, &BatteryState::Discharging, start, &runner);
+        policy.check(9.0, &BatteryState::Discharging, start, &runner);
+        assert_eq!(
+            *runner.calls.borrow(),
+            vec!["sh -c loginctl lock-session".to_owned()]
+        );
+
+        let mut policy =
+            CriticalPolicy::new(10.0, Duration::ZERO, CriticalAction::match_string("none"));
+        let status = policy.check(1.0, &BatteryState::Discharging, start, &runner);
+        assert_eq!(status, CriticalStatus::Normal);
+    }
+}
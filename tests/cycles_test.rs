@@ -0,0 +1,165 @@
+// Import the cycle counter from the main module
+#[path = "../src/main.rs"]
+#[allow(dead_code)]
+mod main;
+
+use main::battery_health::{BatteryIdentity, BatterySnapshot};
+use main::{battery_watcher, cycle_counter, previous_battery};
+use main::cycles::CycleCounter;
+use main::rotation::RotationPolicy;
+use main::sampler::{RecordedSource, Sampler};
+use main::shutdown::Shutdown;
+use main::store::{CsvStore, SharedStore};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Local, TimeZone};
+    use std::fs;
+    use std::time::Duration;
+
+    const WINDOW: (f32, f32) = (20.0, 74.0);
+
+    fn snapshot(
+        minutes: i64,
+        charge_now: u32,
+        charge_full: u32,
+        cycle_count: Option<u32>,
+    ) -> BatterySnapshot {
+        let start = Local.with_ymd_and_hms(2025, 1, 25, 12, 0, 0).unwrap();
+        BatterySnapshot {
+            time: start + chrono::Duration::minutes(minutes),
+            charge_now,
+            charge_full,
+            charge_full_design: 4000,
+            cycle_count,
+            ..BatterySnapshot::default()
+        }
+    }
+
+    fn identity(serial_number: &str) -> BatteryIdentity {
+        BatteryIdentity {
+            manufacturer: "SMP".to_owned(),
+            model_name: "L19M3PF3".to_owned(),
+            serial_number: serial_number.to_owned(),
+            charge_full_design: 4000,
+        }
+    }
+
+    /// Replay `snapshots` to the workers like a daemon run, returns the notifications
+    fn run_counter(path: &std::path::Path, snapshots: Vec<BatterySnapshot>) -> String {
+        let store = CsvStore::new(path.with_extension("csv"), false, RotationPolicy::default());
+        let notifications_path = store.notifications_path();
+        let mut sampler = Sampler::new();
+        let watcher_receiver = sampler.subscribe();
+        let receiver = sampler.subscribe();
+        sampler
+            .run(&mut RecordedSource::new(snapshots), Duration::ZERO, &Shutdown::new())
+            .expect("Failed replaying snapshots");
+        let store = SharedStore::new(Box::new(store));
+        battery_watcher(previous_battery(path), &watcher_receiver, &store, false).expect("battery_watcher failed");
+        cycle_counter(path, &receiver).expect("cycle_counter failed");
+        fs::read_to_string(notifications_path).unwrap_or_default()
+    }
+
+    /// From 100% to 0% of a 3000 capacity in steps of 100, and back up
+    fn discharge(counter: &mut CycleCounter, cycle_count: Option<u32>) {
+        for step in 0..=30 {
+            counter.update(&snapshot(step, 3000 - 100 * step as u32, 3000, cycle_count), WINDOW);
+        }
+        counter.update(&snapshot(31, 3000, 3000, cycle_count), WINDOW);
+    }
+
+    #[test]
+    fn test_cycle_counter() {
+        let mut counter = CycleCounter::new(&snapshot(0, 3200, 3200, Some(0)));
+        for _ in 0..4 {
+            discharge(&mut counter, Some(0));
+        }
+
+        // 4 × 3000 discharged of a 4000 design capacity
+        assert!((counter.cycles() - 3.0).abs() < 1e-9);
+        let report = counter.report(&snapshot(40, 3000, 3000, Some(0)));
+        // 1600 of every 3000, the steps from 73.3% down to 20%
+        assert!((report.window_share.unwrap() - 1600.0 / 3000.0 * 100.0).abs() < 1e-6);
+        // The firmware reporting 0 is the same as not reporting
+        assert_eq!(report.kernel_now, None);
+        // 80% health at the start, 75% now, over 3 cycles
+        assert!((report.wear_per_cycle.unwrap() - 5.0 / 3.0).abs() < 1e-4);
+        assert!(report.to_string().contains("Kernel cycle_count: not reported"));
+
+        let mut counter = CycleCounter::new(&snapshot(0, 3000, 3000, Some(120)));
+        discharge(&mut counter, Some(121));
+        let report = counter.report(&snapshot(40, 3000, 3000, Some(121)));
+        assert_eq!(report.kernel_since_start, Some(1));
+        assert_eq!(report.wear_per_cycle, None);
+        assert!(report.to_string().contains("Kernel cycle_count: 121, 1 since 2025-01-25"));
+    }
+
+    #[test]
+    fn test_cycle_counter_survives_restarts() {
+        let temp_dir = tempdir::TempDir::new("cycles").expect("Failed to create temporary directory");
+        let path = temp_dir.path().join("data.cycles");
+        assert_eq!(CycleCounter::load(&path).unwrap(), None);
+
+        // Two daemon runs, each discharging 1000
+        for run in 0..2 {
+            let snapshots = (0..=10)
+                .map(|step| snapshot(run * 60 + step, 3000 - 100 * step as u32, 3000, Some(7)))
+                .collect();
+            run_counter(&path, snapshots);
+        }
+
+        let counter = CycleCounter::load(&path).unwrap().unwrap();
+        assert!((counter.cycles() - 0.5).abs() < 1e-9);
+        assert_eq!(counter.kernel_start, Some(7));
+        assert_eq!(counter.started, snapshot(0, 0, 3000, None).time.fixed_offset());
+        assert!(fs::read_to_string(&path).unwrap().contains("discharged=2000\n"));
+
+        // A counter from before the battery identity is taken over by the battery in the laptop
+        let battery = |serial_number: &str, minutes: i64, charge_now: u32| BatterySnapshot {
+            identity: Some(identity(serial_number)),
+            ..snapshot(minutes, charge_now, 3000, Some(7))
+        };
+        assert_eq!(run_counter(&path, vec![battery("123", 200, 3000), battery("123", 201, 2000)]), "");
+        let counter = CycleCounter::load(&path).unwrap().unwrap();
+        assert!((counter.cycles() - 0.75).abs() < 1e-9);
+        assert_eq!(counter.battery, Some(identity("123")));
+
+        // The pack is swapped: the old counter is kept aside and the new one starts from 0
+        let notifications = run_counter(&path, vec![battery("456", 300, 3000), battery("456", 301, 2600)]);
+        assert!(notifications.contains("Nuova batteria rilevata: SMP L19M3PF3"), "{notifications}");
+        let counter = CycleCounter::load(&path).unwrap().unwrap();
+        assert!((counter.cycles() - 0.1).abs() < 1e-9);
+        assert_eq!(counter.battery, Some(identity("456")));
+        let old_path = temp_dir.path().join("data.cycles.2025-01-25");
+        let old_counter = CycleCounter::load(&old_path).unwrap().unwrap();
+        assert_eq!(old_counter.battery, Some(identity("123")));
+
+        // Swapped again on the same day, the first old counter is still there
+        run_counter(&path, vec![battery("789", 400, 3000)]);
+        let old_counter = CycleCounter::load(&old_path).unwrap().unwrap();
+        assert_eq!(old_counter.battery, Some(identity("123")));
+        let old_counter = CycleCounter::load(&temp_dir.path().join("data.cycles.2025-01-25.1")).unwrap().unwrap();
+        assert_eq!(old_counter.battery, Some(identity("456")));
+
+        // The swap is noticed even without the cycles
+        fs::write(&path, "started=2025-01-25T12:00:00+01:00\ndischarged=lots\n").unwrap();
+        assert!(CycleCounter::load(&path).is_err());
+        assert_eq!(previous_battery(&path), None);
+        let store = CsvStore::new(temp_dir.path().join("watcher.csv"), false, RotationPolicy::default());
+        let notifications_path = store.notifications_path();
+        let mut sampler = Sampler::new();
+        let receiver = sampler.subscribe();
+        sampler
+            .run(
+                &mut RecordedSource::new(vec![battery("789", 500, 3000), battery("123", 501, 3000)]),
+                Duration::ZERO,
+                &Shutdown::new(),
+            )
+            .unwrap();
+        battery_watcher(None, &receiver, &SharedStore::new(Box::new(store)), false).unwrap();
+        let notifications = fs::read_to_string(notifications_path).unwrap();
+        assert_eq!(notifications.matches("Nuova batteria rilevata").count(), 1, "{notifications}");
+    }
+}
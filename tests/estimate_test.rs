@@ -0,0 +1,91 @@
+// Import the runtime estimator from the main module
+#[path = "../src/main.rs"]
+#[allow(dead_code)]
+mod main;
+
+use main::battery_health::{BatterySnapshot, BatteryState};
+use main::estimate::{format_remaining, Estimate, RuntimeEstimator};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Local, TimeZone};
+    use std::time::Duration;
+
+    fn snapshot(
+        seconds: i64,
+        charge_now: u32,
+        state: BatteryState,
+        current_now: Option<u32>,
+    ) -> BatterySnapshot {
+        let start = Local.with_ymd_and_hms(2025, 1, 25, 12, 0, 0).unwrap();
+        BatterySnapshot {
+            time: start + chrono::Duration::seconds(seconds),
+            charge_now,
+            charge_full: 3000000,
+            charge_full_design: 3500000,
+            state,
+            current_now,
+            ..BatterySnapshot::default()
+        }
+    }
+
+    fn minutes(estimate: Option<Estimate>) -> (f32, f64) {
+        match estimate {
+            Some(Estimate::Empty(remaining)) => (0.0, remaining.as_secs_f64() / 60.0),
+            Some(Estimate::Limit(limit, remaining)) => (limit, remaining.as_secs_f64() / 60.0),
+            None => panic!("No estimate"),
+        }
+    }
+
+    #[test]
+    fn test_estimate_from_current_now() {
+        let mut estimator = RuntimeEstimator::new(74.0);
+
+        // 1.5 Ah left at 1 A
+        let estimate = estimator.update(&snapshot(0, 1500000, BatteryState::Discharging, Some(1000000)));
+        assert_eq!(minutes(estimate), (0.0, 90.0));
+
+        // A spike is smoothed, the estimate moves only a bit
+        let estimate = estimator.update(&snapshot(3, 1500000, BatteryState::Discharging, Some(3000000)));
+        let (_, remaining) = minutes(estimate);
+        assert!(remaining < 90.0 && remaining > 85.0, "{remaining}");
+
+        // Charging to 74% of 3 Ah, 0.72 Ah missing at 1.44 A
+        let estimate = estimator.update(&snapshot(6, 1500000, BatteryState::Charging, Some(1440000)));
+        assert_eq!(minutes(estimate), (74.0, 30.0));
+
+        // Nothing to estimate above the limit or with the charge held
+        assert_eq!(estimator.update(&snapshot(9, 2300000, BatteryState::Charging, Some(1440000))), None);
+        assert_eq!(estimator.update(&snapshot(12, 2300000, BatteryState::NotCharging, Some(0))), None);
+    }
+
+    #[test]
+    fn test_estimate_from_charge_now() {
+        let mut estimator = RuntimeEstimator::new(74.0);
+
+        // 1 mAh every 3 seconds is 1.2 A, after the warm up
+        let mut estimate = None;
+        for step in 0..=40 {
+            let charge_now = 1200000 - 1000 * step as u32;
+            estimate = estimator.update(&snapshot(step * 3, charge_now, BatteryState::Discharging, None));
+            if step * 3 < 120 {
+                assert_eq!(estimate, None, "step {step}");
+            }
+        }
+        // 1.16 Ah left
+        let (_, remaining) = minutes(estimate);
+        assert!((remaining - 58.0).abs() < 0.5, "{remaining}");
+
+        // Plugging in starts over
+        let estimate = estimator.update(&snapshot(123, 1160000, BatteryState::Charging, None));
+        assert_eq!(estimate, None);
+    }
+
+    #[test]
+    fn test_format_remaining() {
+        assert_eq!(format_remaining(Duration::from_secs(25 * 60 + 10)), "~25 min");
+        assert_eq!(format_remaining(Duration::from_secs(125 * 60)), "~2 h 5 min");
+        assert_eq!(format_remaining(Duration::from_secs(10)), "~0 min");
+    }
+}
@@ -0,0 +1,122 @@
+// Import the controller and the stores from the main module
+#[path = "../src/main.rs"]
+#[allow(dead_code)]
+mod main;
+
+use main::actuator::Actuator;
+use main::ardu::{ArduSketch, SharedMode};
+use main::battery_health::BatterySnapshot;
+use main::controller;
+use main::sampler::{RecordedSource, Sampler};
+use main::shutdown::Shutdown;
+use main::rotation::RotationPolicy;
+use main::store::{ActuatorEvent, CsvStore, NotificationEvent, SharedStore};
+use main::supervisor::{self, Supervisor};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+    use std::fs;
+    use std::time::Duration;
+
+    #[test]
+    fn test_controller_logs_failed_command() {
+        let temp_dir = tempdir::TempDir::new("events").expect("Failed to create temporary directory");
+        let csv_store = CsvStore::new(temp_dir.path().join("data.csv"), false, RotationPolicy::default());
+        let events_path = csv_store.actuator_events_path();
+        let store = SharedStore::new(Box::new(csv_store));
+
+        // Discharging at 50%, under the upper limit, the controller tries to connect the charger.
+        // The fake actuator fails and the failure is logged, the arduino is never flashed.
+        let snapshot = BatterySnapshot::default();
+        let mut sampler = Sampler::new();
+        let receiver = sampler.subscribe();
+        sampler
+            .run(&mut RecordedSource::new(vec![snapshot]), Duration::ZERO, &Shutdown::new())
+            .expect("Failed replaying snapshots");
+
+        let actuator = Actuator::Command {
+            connect: "false".to_owned(),
+            disconnect: "false".to_owned(),
+        };
+        let status_path = temp_dir.path().join("data.status");
+        let supervisor = Supervisor::new(
+            Some(status_path.clone()),
+            false,
+            Duration::from_millis(1),
+            Duration::from_millis(10),
+            Shutdown::new(),
+        );
+        assert!(controller(&receiver, &Shutdown::new(), &store, &SharedMode::default(), &actuator, &supervisor).is_err());
+
+        // The tui reads the mode and the command there
+        let status = fs::read_to_string(status_path).unwrap();
+        let (summary, details) = supervisor::parse_status(&status);
+        assert_eq!(summary, "ok");
+        assert_eq!(details["mode"], "auto");
+        assert_eq!(details["command"], "connect_charger");
+
+        let content = fs::read_to_string(events_path).expect("No actuator event was logged");
+        let lines = content.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "Timestamp,Command,Outcome,Latency_ms,Percentage_Before,Percentage_After");
+        assert_eq!(lines.len(), 2);
+        let fields = lines[1].split(',').collect::<Vec<_>>();
+        assert!(DateTime::parse_from_rfc3339(fields[0]).is_ok());
+        assert_eq!(fields[1], "connect_charger");
+        assert!(fields[2].contains("Actuator error"), "unexpected outcome {}", fields[2]);
+        assert_eq!(fields[4..], ["50", "N/A"]);
+    }
+
+    #[test]
+    fn test_csv_event_rows() {
+        let temp_dir = tempdir::TempDir::new("events").expect("Failed to create temporary directory");
+        let csv_store = CsvStore::new(temp_dir.path().join("data.csv"), false, RotationPolicy::default());
+        let events_path = csv_store.actuator_events_path();
+        let notifications_path = csv_store.notifications_path();
+        let store = SharedStore::new(Box::new(csv_store));
+        let time = DateTime::parse_from_rfc3339("2025-01-25T12:22:00+01:00").unwrap();
+
+        store.record_actuator_event(&ActuatorEvent {
+            time,
+            command: ArduSketch::Disconnect,
+            error: None,
+            latency: Duration::from_millis(4250),
+            percentage_before: 74.5,
+            percentage_after: Some(74.4),
+        });
+        store.record_notification(&NotificationEvent {
+            time,
+            level: "80%".to_owned(),
+            message: "Sconnetti il caricatore!!\ndegraded: controller restarting (1 failures), \"avrdude\"".to_owned(),
+        });
+
+        let events = fs::read_to_string(events_path).unwrap();
+        assert!(events.ends_with("\n2025-01-25T12:22:00+01:00,disconnect_charger,ok,4250,74.5,74.4\n"));
+        let notifications = fs::read_to_string(notifications_path).unwrap();
+        assert_eq!(
+            notifications,
+            "Timestamp,Level,Message\n\
+            2025-01-25T12:22:00+01:00,80%,\"Sconnetti il caricatore!! degraded: controller restarting (1 failures), \"\"avrdude\"\"\"\n"
+        );
+    }
+
+    #[test]
+    fn test_truncated_event_row() {
+        let temp_dir = tempdir::TempDir::new("events").expect("Failed to create temporary directory");
+        let csv_store = CsvStore::new(temp_dir.path().join("data.csv"), false, RotationPolicy::default());
+        let notifications_path = csv_store.notifications_path();
+        let store = SharedStore::new(Box::new(csv_store));
+        fs::write(&notifications_path, "Timestamp,Level,Message\n2025-01-25T12:22:00+01:00,80%,Sconn").unwrap();
+
+        store.record_notification(&NotificationEvent {
+            time: DateTime::parse_from_rfc3339("2025-01-25T12:40:00+01:00").unwrap(),
+            level: "20%".to_owned(),
+            message: "Connetti il caricatore!!".to_owned(),
+        });
+        assert_eq!(
+            fs::read_to_string(notifications_path).unwrap(),
+            "Timestamp,Level,Message\n2025-01-25T12:40:00+01:00,20%,Connetti il caricatore!!\n"
+        );
+    }
+}
@@ -1,22 +1,29 @@
-
 // Import the health_stats function from the main module
 #[path = "../src/main.rs"]
+#[allow(dead_code)]
 mod main;
 
 // Import the necessary modules
+use main::battery_health::{BatterySnapshot, BatteryState};
 use main::health_stats;
+use main::history::{self, WritePolicy};
+use main::sampler::{RecordedSource, Sampler};
+use main::shutdown::Shutdown;
+use main::rotation::RotationPolicy;
+use main::store::{CsvStore, SharedStore};
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use chrono::{Local, TimeZone};
     use std::fs;
-    
+    use std::time::Duration;
 
     #[test]
     fn test_health_stats() {
         // Set up mock battery statistics
-        let charge_full = "3000"; // Example values for charge_full and charge_full_design
-        let charge_full_design = "3500"; // Example values for charge_full and charge_full_design
+        let charge_full = 3000; // Example values for charge_full and charge_full_design
+        let charge_full_design = 3500; // Example values for charge_full and charge_full_design
 
         // Create a temporary directory for the test
         let temp_dir = tempdir::TempDir::new("test_data").expect("Failed to create temporary directory");
@@ -26,8 +33,25 @@ mod tests {
         // Set up the data file path
         let file_path = data_dir.join("battery_stats.csv");
 
-        // Call the health_stats function
-        let result = health_stats();
+        // Three snapshots two minutes apart, with write_every = 3 only the first and the last are written
+        let snapshots = (0..3)
+            .map(|i| BatterySnapshot {
+                time: Local.with_ymd_and_hms(2025, 1, 25, 12, 22 + 2 * i, 0).unwrap(),
+                charge_now: 2400 - 30 * i,
+                charge_full,
+                charge_full_design,
+                ..BatterySnapshot::default()
+            })
+            .collect();
+        let mut sampler = Sampler::new();
+        let receiver = sampler.subscribe();
+        sampler
+            .run(&mut RecordedSource::new(snapshots), Duration::ZERO, &Shutdown::new())
+            .expect("Failed replaying snapshots");
+
+        // Call the health_stats function, it returns once every snapshot is consumed
+        let store = CsvStore::new(&file_path, false, RotationPolicy::default());
+        let result = health_stats(&SharedStore::new(Box::new(store)), WritePolicy::every(3), &receiver);
 
         // Assert that the function executed without errors
         assert!(result.is_ok(), "health_stats function returned an error: {:?}", result);
@@ -39,10 +63,65 @@ mod tests {
         let content = fs::read_to_string(&file_path).expect("Failed to read CSV file");
 
         // Assert that the CSV file contains the expected data
-        assert!(content.contains("Date,Charge_Full,Charge_Full_Design,Battery_Health"));
-        assert!(content.contains("Charge_Full,3500,3000,0.857"));
+        let lines = content.lines().collect::<Vec<_>>();
+        // The timestamps carry the offset of the timezone the test runs in
+        let offset = Local.with_ymd_and_hms(2025, 1, 25, 12, 22, 0).unwrap().format("%:z");
+        assert_eq!(lines[0], "# energy_monitor schema=3");
+        assert_eq!(lines[1], "Timestamp,Charge_Full,Charge_Full_Design,Battery_Health,Battery_Percentage,Battery_Status");
+        assert_eq!(lines[2], format!("2025-01-25T12:22:00{offset},3000,3500,85.71429,80,Discharging"));
+        assert_eq!(lines[3], format!("2025-01-25T12:26:00{offset},3000,3500,85.71429,78,Discharging"));
+        assert_eq!(lines.len(), 4);
 
         // Clean up: delete the temporary directory
         temp_dir.close().expect("Failed to delete temporary directory");
     }
+
+    #[test]
+    fn test_health_stats_adaptive() {
+        let temp_dir = tempdir::TempDir::new("test_data").expect("Failed to create temporary directory");
+        let file_path = temp_dir.path().join("data.csv");
+
+        // One snapshot a minute: (charge_now out of 1000, status)
+        let readings = [
+            (800, BatteryState::Discharging), // first row
+            (797, BatteryState::Discharging), // 0.3 points, skipped
+            (790, BatteryState::Discharging), // 1 point since the last row
+            (789, BatteryState::Charging),    // status change
+            (790, BatteryState::Charging),    // skipped
+            (791, BatteryState::Charging),    // skipped
+            (792, BatteryState::Charging),    // 3 minutes since the last row
+        ];
+        let snapshots = readings
+            .iter()
+            .enumerate()
+            .map(|(i, &(charge_now, state))| BatterySnapshot {
+                time: Local.with_ymd_and_hms(2025, 1, 25, 12, i as u32, 0).unwrap(),
+                charge_now,
+                charge_full: 1000,
+                charge_full_design: 1200,
+                state,
+                ..BatterySnapshot::default()
+            })
+            .collect();
+        let mut sampler = Sampler::new();
+        let receiver = sampler.subscribe();
+        sampler
+            .run(&mut RecordedSource::new(snapshots), Duration::ZERO, &Shutdown::new())
+            .expect("Failed replaying snapshots");
+
+        let write_policy = WritePolicy {
+            delta: Some(1.0),
+            on_status_change: true,
+            ..WritePolicy::every(3)
+        };
+        let store = CsvStore::new(&file_path, false, RotationPolicy::default());
+        health_stats(&SharedStore::new(Box::new(store)), write_policy, &receiver).expect("health_stats failed");
+
+        let minutes = history::read_history(&file_path)
+            .unwrap()
+            .iter()
+            .map(|record| chrono::Timelike::minute(&record.time))
+            .collect::<Vec<_>>();
+        assert_eq!(minutes, [0, 2, 3, 6]);
+    }
 }
@@ -0,0 +1,211 @@
+// Import the history schema from the main module
+#[path = "../src/main.rs"]
+#[allow(dead_code)]
+mod main;
+
+use main::battery_health::{BatteryIdentity, BatteryState};
+use main::history::{self, HistoryRecord, Schema, HEADER_WITH_EPOCH, SCHEMA_VERSION};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Local, NaiveDate, TimeZone};
+    use std::fs;
+
+    // Head and tail of data/battery_stats.csv, the last row was appended in the data.csv layout
+    const BATTERY_STATS: &str = "\
+Date,Hour,Charge_Full,Charge_Full_Design,Battery_Health,Battery_Percentage,Battery_Status
+19/04/2024,08:00,3165000,3620000,87.43094,N/A,N/A
+25/04/2024,09:53,3190000,3620000,88.121544,54.670845,Charging
+2024/04/25,13:07,13.116667,3190000,3620000,88.121544,36.112854,Discharging
+";
+    const DATA_WITH_HOUR: &str = "\
+Date,Hour,Charge_Full,Charge_Full_Design,Battery_Health
+19/04/2024,21:31,3203000,3620000,88.48067
+";
+    // data.csv written by the daemon before the RFC 3339 timestamps
+    const SCHEMA_2: &str = "\
+# energy_monitor schema=2
+Date,Hour_str,Hour_f32,Charge_Full,Charge_Full_Design,Battery_Health,Battery_Percentage,Battery_Status
+25/01/2025,12:22,12.366667,3000,3500,85.71429,80,Discharging
+";
+
+    /// Legacy rows are in the timezone of the machine reading them
+    fn local_rfc3339(date: &str, hour: &str) -> String {
+        let time = NaiveDate::parse_from_str(date, "%d/%m/%Y")
+            .unwrap()
+            .and_time(chrono::NaiveTime::parse_from_str(hour, "%H:%M").unwrap());
+        Local.from_local_datetime(&time).earliest().unwrap().to_rfc3339()
+    }
+
+    #[test]
+    fn test_detect_legacy_layouts() {
+        let temp_dir = tempdir::TempDir::new("history").expect("Failed to create temporary directory");
+        let stats_path = temp_dir.path().join("battery_stats.csv");
+        let hour_path = temp_dir.path().join("data_with_hour.csv");
+        fs::write(&stats_path, BATTERY_STATS).unwrap();
+        fs::write(&hour_path, DATA_WITH_HOUR).unwrap();
+
+        assert_eq!(history::detect_schema(&stats_path).unwrap(), Some(Schema::HourStats));
+        assert_eq!(history::detect_schema(&hour_path).unwrap(), Some(Schema::HealthOnly));
+        assert_eq!(history::detect_schema(&temp_dir.path().join("missing.csv")).unwrap(), None);
+
+        let records = history::read_history(&stats_path).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].battery_percentage, None);
+        assert_eq!(records[1].battery_status, Some(BatteryState::Charging));
+        assert_eq!(
+            records[2].time.naive_local(),
+            records[1].time.date_naive().and_hms_opt(13, 7, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_migrate_legacy_file() {
+        let temp_dir = tempdir::TempDir::new("history").expect("Failed to create temporary directory");
+        let path = temp_dir.path().join("battery_stats.csv");
+        fs::write(&path, BATTERY_STATS).unwrap();
+        let legacy_records = history::read_history(&path).unwrap();
+
+        // A legacy file is never appended to
+        assert!(history::append_record(&path, &legacy_records[0], false, None).is_err());
+
+        let backup_path = history::migrate(&path, false).unwrap().expect("File was not migrated");
+        assert_eq!(fs::read_to_string(backup_path).unwrap(), BATTERY_STATS);
+        assert_eq!(history::detect_schema(&path).unwrap(), Some(Schema::Versioned(SCHEMA_VERSION)));
+        assert_eq!(history::read_history(&path).unwrap(), legacy_records);
+
+        let content = fs::read_to_string(&path).unwrap();
+        let first_row = format!("\n{},3165000,3620000,87.43094,N/A,N/A\n", local_rfc3339("19/04/2024", "08:00"));
+        let last_row = format!(
+            "\n{},3190000,3620000,88.121544,36.112854,Discharging\n",
+            local_rfc3339("25/04/2024", "13:07")
+        );
+        assert!(content.contains(&first_row));
+        assert!(content.ends_with(&last_row));
+
+        // Migrating twice does nothing, appending now works
+        assert_eq!(history::migrate(&path, false).unwrap(), None);
+        history::append_record(&path, &legacy_records[0], false, None).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_schema_2_with_epoch() {
+        let temp_dir = tempdir::TempDir::new("history").expect("Failed to create temporary directory");
+        let path = temp_dir.path().join("data.csv");
+        fs::write(&path, SCHEMA_2).unwrap();
+        assert_eq!(history::detect_schema(&path).unwrap(), Some(Schema::Versioned(2)));
+        let records = history::read_history(&path).unwrap();
+
+        history::migrate(&path, true).unwrap().expect("File was not migrated");
+        assert!(history::has_epoch_column(&path).unwrap());
+        assert_eq!(history::read_history(&path).unwrap(), records);
+
+        let time = records[0].time;
+        let lines = fs::read_to_string(&path).unwrap().lines().map(str::to_owned).collect::<Vec<_>>();
+        assert_eq!(lines[1], HEADER_WITH_EPOCH);
+        assert_eq!(
+            lines[2],
+            format!("{},{},3000,3500,85.71429,80,Discharging", time.to_rfc3339(), time.timestamp())
+        );
+
+        // The existing columns win over the argument
+        history::append_record(&path, &records[0], false, None).unwrap();
+        assert_eq!(history::read_history(&path).unwrap().len(), 2);
+        assert!(fs::read_to_string(&path).unwrap().ends_with(&format!("{}\n", lines[2])));
+    }
+
+    #[test]
+    fn test_parse_rfc3339_row() {
+        let record = HistoryRecord::parse_row("2025-01-25T12:22:00+01:00,3000,3500,85.71429,80,Discharging").unwrap();
+        assert_eq!(record.time.timestamp(), 1737804120);
+        assert_eq!(record.battery_status, Some(BatteryState::Discharging));
+        assert_eq!(record.to_row(false), "2025-01-25T12:22:00+01:00,3000,3500,85.71429,80,Discharging");
+        assert_eq!(record.to_row(true), "2025-01-25T12:22:00+01:00,1737804120,3000,3500,85.71429,80,Discharging");
+
+        let with_epoch = HistoryRecord::parse_row(&record.to_row(true)).unwrap();
+        assert_eq!(with_epoch, record);
+    }
+
+    #[test]
+    fn test_parse_row_errors() {
+        assert!(HistoryRecord::parse_row("19/04/2024,08:00,3165000").is_err());
+        assert!(HistoryRecord::parse_row("19/04/2024,25:00,3165000,3620000,87.43094").is_err());
+        assert!(HistoryRecord::parse_row("19/04/2024,08:00,3165000,3620000,87.43094,N/A,Sleeping").is_err());
+        assert!(HistoryRecord::parse_row("2025-01-25T12:22:00+01:00,3000,3500").is_err());
+    }
+
+    #[test]
+    fn test_truncated_last_row() {
+        let temp_dir = tempdir::TempDir::new("history").expect("Failed to create temporary directory");
+        let path = temp_dir.path().join("data.csv");
+        let good = "\
+# energy_monitor schema=3
+Timestamp,Charge_Full,Charge_Full_Design,Battery_Health,Battery_Percentage,Battery_Status
+2025-01-25T12:22:00+01:00,3000,3500,85.71429,80,Discharging
+";
+        // Power loss in the middle of the next append
+        fs::write(&path, format!("{good}2025-01-25T12:27:00+01:00,3000,35")).unwrap();
+
+        let records = history::read_history(&path).unwrap();
+        assert_eq!(records.len(), 1);
+
+        let next = HistoryRecord::parse_row("2025-01-25T12:32:00+01:00,3000,3500,85.71429,79,Discharging").unwrap();
+        history::append_record(&path, &next, false, None).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{good}2025-01-25T12:32:00+01:00,3000,3500,85.71429,79,Discharging\n")
+        );
+        assert_eq!(history::repair_truncated_line(&path).unwrap(), None);
+
+        // Only the last row may be broken
+        fs::write(&path, format!("{good}2025-01-25T12:27:00+01:00,3000,35\n{}\n", next.to_row(false))).unwrap();
+        assert!(history::read_history(&path).unwrap_err().to_string().contains("Invalid history row"));
+    }
+
+    #[test]
+    fn test_truncated_preamble() {
+        let temp_dir = tempdir::TempDir::new("history").expect("Failed to create temporary directory");
+        let path = temp_dir.path().join("data.csv");
+        // The very first append was cut, a long line makes the repair read several chunks
+        fs::write(&path, format!("# energy_monitor sch{}", "e".repeat(10_000))).unwrap();
+
+        let record = HistoryRecord::parse_row("2025-01-25T12:22:00+01:00,3000,3500,85.71429,80,Discharging").unwrap();
+        history::append_record(&path, &record, false, None).unwrap();
+        assert_eq!(history::detect_schema(&path).unwrap(), Some(Schema::Versioned(SCHEMA_VERSION)));
+        assert_eq!(history::read_history(&path).unwrap(), [record]);
+    }
+
+    #[test]
+    fn test_battery_segments() {
+        let temp_dir = tempdir::TempDir::new("history").expect("Failed to create temporary directory");
+        let path = temp_dir.path().join("data.csv");
+        let battery = |serial_number: &str| BatteryIdentity {
+            manufacturer: "SMP".to_owned(),
+            model_name: "L19M3PF3".to_owned(),
+            serial_number: serial_number.to_owned(),
+            charge_full_design: 3500,
+        };
+        let record = |minute: u32, serial_number: &str| HistoryRecord {
+            battery: Some(battery(serial_number)),
+            ..HistoryRecord::parse_row(&format!("2025-01-25T12:{minute:02}:00+01:00,3000,3500,85.71429,80,Discharging"))
+                .unwrap()
+        };
+        assert_eq!(history::last_battery(&path).unwrap(), None);
+
+        let records = [record(0, "123"), record(5, "123"), record(10, "456")];
+        let mut segment_battery = None;
+        for record in &records {
+            history::append_record(&path, record, false, segment_battery.as_ref()).unwrap();
+            segment_battery = record.battery.clone();
+        }
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(content.matches("# energy_monitor battery=").count(), 2);
+        assert!(content.contains("\n# energy_monitor battery=SMP;L19M3PF3;456;3500\n2025-01-25T12:10:00+01:00,"));
+        assert_eq!(history::read_history(&path).unwrap(), records);
+        assert_eq!(history::last_battery(&path).unwrap(), Some(battery("456")));
+        assert_eq!(BatteryIdentity::from_key(&battery("123").key()).unwrap(), battery("123"));
+        assert!(BatteryIdentity::from_key("SMP;L19M3PF3;123").is_err());
+    }
+}
@@ -0,0 +1,196 @@
+// Import the hooks from the main module
+#[path = "../src/main.rs"]
+#[allow(dead_code)]
+mod main;
+
+use main::ardu::ArduSketch;
+use main::battery_health::{BatterySnapshot, BatteryState};
+use main::critical::{CriticalAction, CriticalPolicy};
+use main::hooks::{self, HookEvent, HookForwarder, Hooks, SnapshotWatcher};
+use main::notifier;
+use main::rotation::RotationPolicy;
+use main::shutdown::Shutdown;
+use main::store::{ActuatorEvent, CsvStore, NotificationEvent, SharedStore};
+use main::supervisor::Supervisor;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Local;
+    use std::fs;
+    use std::sync::{mpsc, Arc};
+    use std::time::{Duration, Instant};
+
+    fn snapshot(state: BatteryState, charge_full: u32) -> BatterySnapshot {
+        BatterySnapshot {
+            charge_full,
+            state,
+            ..BatterySnapshot::default()
+        }
+    }
+
+    fn names(events: Vec<HookEvent>) -> Vec<&'static str> {
+        events.iter().map(HookEvent::name).collect()
+    }
+
+    #[test]
+    fn test_snapshot_watcher() {
+        let mut watcher = SnapshotWatcher::new(80.0);
+        // The first snapshot only says where the charger is
+        assert!(watcher.observe(&snapshot(BatteryState::Discharging, 3000)).is_empty());
+        assert_eq!(names(watcher.observe(&snapshot(BatteryState::Charging, 3000))), ["charger_connected"]);
+        assert!(watcher.observe(&snapshot(BatteryState::NotCharging, 3000)).is_empty());
+        assert_eq!(
+            names(watcher.observe(&snapshot(BatteryState::Discharging, 2700))),
+            ["charger_disconnected", "health_low"]
+        );
+        // Once, until the health is back over the limit
+        assert!(watcher.observe(&snapshot(BatteryState::Discharging, 2700)).is_empty());
+        assert!(watcher.observe(&snapshot(BatteryState::Discharging, 3000)).is_empty());
+        assert_eq!(names(watcher.observe(&snapshot(BatteryState::Discharging, 2700))), ["health_low"]);
+
+        // Restarted with the health already low, the hook already ran
+        let mut watcher = SnapshotWatcher::new(80.0);
+        assert!(watcher.observe(&snapshot(BatteryState::Discharging, 2700)).is_empty());
+        assert!(watcher.observe(&snapshot(BatteryState::Discharging, 2700)).is_empty());
+    }
+
+    #[test]
+    fn test_run_hooks() {
+        let temp_dir = tempdir::TempDir::new("hooks").expect("Failed to create temporary directory");
+        let log = temp_dir.path().join("hooks.log");
+        let log = log.display();
+        let hooks = Hooks {
+            threshold: Some(format!("echo \"$ENERGY_MONITOR_EVENT $ENERGY_MONITOR_LEVEL $ENERGY_MONITOR_MESSAGE\" >> {log}")),
+            charger_connected: Some(format!(
+                "echo \"$ENERGY_MONITOR_EVENT $ENERGY_MONITOR_PERCENTAGE $ENERGY_MONITOR_STATUS\" >> {log}"
+            )),
+            charger_disconnected: Some("exit 1".to_owned()),
+            health_low: Some(format!("echo \"$ENERGY_MONITOR_EVENT $ENERGY_MONITOR_HEALTH\" >> {log}")),
+            health_below: 80.0,
+            actuator_failure: Some(format!(
+                "echo \"$ENERGY_MONITOR_EVENT $ENERGY_MONITOR_COMMAND $ENERGY_MONITOR_ERROR\" >> {log}"
+            )),
+            ..Hooks::default()
+        };
+
+        let (sender, events) = mpsc::channel();
+        let store = SharedStore::new(Box::new(CsvStore::new(
+            temp_dir.path().join("data.csv"),
+            false,
+            RotationPolicy::default(),
+        )))
+        .with_observer(Arc::new(HookForwarder(sender)));
+        store.record_notification(&NotificationEvent {
+            time: Local::now().fixed_offset(),
+            level: "20%".to_owned(),
+            message: "Connetti il caricatore!!".to_owned(),
+        });
+        // Not a threshold
+        store.record_notification(&NotificationEvent {
+            time: Local::now().fixed_offset(),
+            level: "nuova".to_owned(),
+            message: "Nuova batteria rilevata".to_owned(),
+        });
+        let mut event = ActuatorEvent {
+            time: Local::now().fixed_offset(),
+            command: ArduSketch::Connect,
+            error: None,
+            latency: Duration::from_secs(5),
+            percentage_before: 20.0,
+            percentage_after: Some(20.5),
+        };
+        // Only the failures run a hook
+        store.record_actuator_event(&event);
+        event.error = Some("no plug".to_owned());
+        store.record_actuator_event(&event);
+
+        let (sampler, snapshots) = mpsc::channel();
+        for state in [BatteryState::Charging, BatteryState::Discharging, BatteryState::Charging] {
+            sampler.send(snapshot(state, 3000)).unwrap();
+        }
+        drop(sampler);
+        // The failing charger_disconnected hook doesn't stop the others
+        hooks::run_hooks(&hooks, &snapshots, &events).unwrap();
+
+        let lines = fs::read_to_string(temp_dir.path().join("hooks.log")).unwrap();
+        let lines = lines.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "threshold 20% Connetti il caricatore!!",
+                "actuator_failure connect_charger no plug",
+                "charger_connected 50.0 Charging",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hook_env() {
+        let event = HookEvent::HealthLow(snapshot(BatteryState::Full, 2700));
+        let env = event.env();
+        assert!(env.contains(&("ENERGY_MONITOR_EVENT", "health_low".to_owned())));
+        assert!(env.contains(&("ENERGY_MONITOR_HEALTH", "77.14".to_owned())));
+        assert!(env.contains(&("ENERGY_MONITOR_STATUS", "Full".to_owned())));
+        assert!(env.iter().any(|(name, _)| *name == "ENERGY_MONITOR_TIME"));
+
+        assert!(Hooks::default().is_empty());
+        assert!(Hooks::default().run(&event).is_ok());
+        let failing = Hooks {
+            health_low: Some("exit 2".to_owned()),
+            ..Hooks::default()
+        };
+        assert!(!failing.is_empty());
+        assert!(failing.run(&event).is_err());
+    }
+
+    #[test]
+    fn test_hook_timeout() {
+        let event = HookEvent::HealthLow(snapshot(BatteryState::Full, 2700));
+        let hanging = Hooks {
+            health_low: Some("sleep 10".to_owned()),
+            timeout: Duration::from_millis(200),
+            ..Hooks::default()
+        };
+        let started = Instant::now();
+        let err = hanging.run(&event).unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(err.to_string().contains("'sleep 10' killed on health_low"), "{err}");
+    }
+
+    #[test]
+    fn test_threshold_without_desktop() {
+        let temp_dir = tempdir::TempDir::new("threshold").expect("Failed to create temporary directory");
+        let (sender, events) = mpsc::channel();
+        let store = SharedStore::new(Box::new(CsvStore::new(
+            temp_dir.path().join("data.csv"),
+            false,
+            RotationPolicy::default(),
+        )))
+        .with_observer(Arc::new(HookForwarder(sender)));
+        let supervisor = Supervisor::new(
+            None,
+            false,
+            Duration::from_millis(1),
+            Duration::from_millis(10),
+            Shutdown::new(),
+        );
+        let mut critical_policy = CriticalPolicy::new(5.0, Duration::from_secs(60), CriticalAction::Disabled);
+
+        let (sampler, snapshots) = mpsc::channel();
+        let mut low = snapshot(BatteryState::Discharging, 3000);
+        low.charge_now = 300;
+        sampler.send(low).unwrap();
+        drop(sampler);
+        // No notify-send here, the hook gets the notification anyway
+        notifier(&snapshots, &mut false, &mut false, &mut critical_policy, &supervisor, &store, false).unwrap();
+
+        let events = events.try_iter().collect::<Vec<_>>();
+        assert_eq!(events.len(), 1);
+        let HookEvent::Threshold(notification) = &events[0] else {
+            panic!("{events:?}");
+        };
+        assert_eq!(notification.level, "20%");
+        assert!(notification.message.starts_with("Connetti il caricatore!!"));
+    }
+}
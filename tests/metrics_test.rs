@@ -0,0 +1,200 @@
+// Import the exporter from the main module
+#[path = "../src/main.rs"]
+#[allow(dead_code)]
+mod main;
+
+use main::actuator::Actuator;
+use main::ardu::{ChargerMode, SharedMode};
+use main::battery_health::{BatterySnapshot, BatteryState};
+use main::controller;
+use main::metrics::{self, Metrics};
+use main::rotation::RotationPolicy;
+use main::sampler::{RecordedSource, Sampler};
+use main::shutdown::Shutdown;
+use main::store::{CsvStore, NotificationEvent, SharedStore};
+use main::supervisor::Supervisor;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Local;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn snapshot() -> BatterySnapshot {
+        BatterySnapshot {
+            current_now: Some(1250000),
+            voltage_now: Some(11800000),
+            ..BatterySnapshot::default()
+        }
+    }
+
+    /// A plain HTTP/1.1 request, returns the whole response
+    fn get(address: std::net::SocketAddr, path: &str, accept: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\nAccept: {accept}\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_metrics_endpoint() {
+        let temp_dir = tempdir::TempDir::new("metrics").expect("Failed to create temporary directory");
+        let metrics = Metrics::new();
+        let store = SharedStore::new(Box::new(CsvStore::new(
+            temp_dir.path().join("data.csv"),
+            false,
+            RotationPolicy::default(),
+        )))
+        .with_observer(Arc::new(metrics.clone()));
+
+        // The fake actuator fails connecting the charger, the failure is counted
+        let mut sampler = Sampler::new();
+        let receiver = sampler.subscribe();
+        sampler
+            .run(&mut RecordedSource::new(vec![snapshot()]), Duration::ZERO, &Shutdown::new())
+            .unwrap();
+        let actuator = Actuator::Command {
+            connect: "false".to_owned(),
+            disconnect: "false".to_owned(),
+        };
+        let supervisor = Supervisor::new(
+            None,
+            false,
+            Duration::from_millis(1),
+            Duration::from_millis(10),
+            Shutdown::new(),
+        );
+        assert!(controller(&receiver, &Shutdown::new(), &store, &SharedMode::default(), &actuator, &supervisor).is_err());
+        store.record_notification(&NotificationEvent {
+            time: Local::now().fixed_offset(),
+            level: "20%".to_owned(),
+            message: "Connetti il caricatore!!".to_owned(),
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, snapshots) = mpsc::channel();
+        sender.send(snapshot()).unwrap();
+        let server_metrics = metrics.clone();
+        let server = thread::spawn(move || metrics::serve(&listener, &server_metrics, &snapshots));
+
+        // The snapshot is read before the first connection is accepted
+        let response = get(address, "/metrics", "text/plain");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4"));
+        assert!(response.contains("\nenergy_monitor_battery_percent 50\n"), "{response}");
+        assert!(response.contains("\nenergy_monitor_battery_voltage_volts 11.8\n"));
+        assert!(response.contains("\nenergy_monitor_battery_current_amperes 1.25\n"));
+        assert!(response.contains("\nenergy_monitor_battery_power_watts 14.75\n"));
+        assert!(response.contains("\nenergy_monitor_battery_state{state=\"Discharging\"} 1\n"));
+        assert!(response.contains("\nenergy_monitor_controller_command{command=\"connect_charger\"} 1\n"));
+        assert!(response.contains("\nenergy_monitor_controller_mode{mode=\"auto\"} 1\n"));
+        assert!(response.contains("# TYPE energy_monitor_actuator_commands_total counter\n"));
+        assert!(response.contains("\nenergy_monitor_actuator_commands_total{command=\"connect_charger\"} 1\n"));
+        assert!(response.contains("\nenergy_monitor_actuator_failures_total{command=\"connect_charger\"} 1\n"));
+        assert!(response.contains("\nenergy_monitor_notifications_total{level=\"20%\"} 1\n"));
+        assert!(!response.contains("# EOF"));
+
+        let response = get(address, "/metrics", "application/openmetrics-text;version=1.0.0,text/plain;q=0.5");
+        assert!(response.contains("Content-Type: application/openmetrics-text"));
+        assert!(response.contains("# TYPE energy_monitor_actuator_commands counter\n"));
+        assert!(response.ends_with("# EOF\n"));
+
+        assert!(get(address, "/", "*/*").starts_with("HTTP/1.1 404 Not Found"));
+
+        // The exporter stops with the sampler
+        drop(sender);
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_command_in_progress() {
+        let temp_dir = tempdir::TempDir::new("metrics").expect("Failed to create temporary directory");
+        let metrics = Metrics::new();
+        let store = SharedStore::new(Box::new(CsvStore::new(
+            temp_dir.path().join("data.csv"),
+            false,
+            RotationPolicy::default(),
+        )))
+        .with_observer(Arc::new(metrics.clone()));
+        let actuator = Actuator::Command {
+            connect: "true".to_owned(),
+            disconnect: "true".to_owned(),
+        };
+        let shutdown = Shutdown::new();
+        shutdown.trigger();
+        let supervisor = Supervisor::new(
+            None,
+            false,
+            Duration::from_millis(1),
+            Duration::from_millis(10),
+            shutdown.clone(),
+        );
+        let mode = SharedMode::default();
+        mode.set(ChargerMode::Connect);
+
+        let (sampler, snapshots) = mpsc::channel();
+        let handle = thread::spawn(move || controller(&snapshots, &shutdown, &store, &mode, &actuator, &supervisor));
+        // Discharging, the controller connects the charger and waits for the battery to charge
+        sampler.send(snapshot()).unwrap();
+        let started = Instant::now();
+        while !metrics.render(false).contains("energy_monitor_controller_command{command=\"connect_charger\"} 1\n") {
+            assert!(started.elapsed() < Duration::from_secs(5), "{}", metrics.render(false));
+            thread::sleep(Duration::from_millis(1));
+        }
+        let text = metrics.render(false);
+        assert!(text.contains("energy_monitor_controller_mode{mode=\"connect\"} 1\n"), "{text}");
+        assert!(!text.contains("energy_monitor_actuator_commands_total{"), "{text}");
+
+        sampler
+            .send(BatterySnapshot {
+                state: BatteryState::Charging,
+                ..snapshot()
+            })
+            .unwrap();
+        drop(sampler);
+        handle.join().unwrap().unwrap();
+        let text = metrics.render(false);
+        assert!(text.contains("energy_monitor_actuator_commands_total{command=\"connect_charger\"} 1\n"), "{text}");
+    }
+
+    #[test]
+    fn test_slow_scraper() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, snapshots) = mpsc::channel();
+        let server = thread::spawn(move || metrics::serve(&listener, &Metrics::new(), &snapshots));
+
+        // A byte at a time, each read is in time but the request never ends
+        let mut slow = TcpStream::connect(address).unwrap();
+        let trickle = thread::spawn(move || {
+            for byte in b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n".iter().cycle().take(30) {
+                if slow.write_all(&[*byte]).is_err() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(200));
+            }
+        });
+
+        let started = Instant::now();
+        assert!(get(address, "/metrics", "text/plain").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+
+        drop(sender);
+        server.join().unwrap().unwrap();
+        trickle.join().unwrap();
+    }
+
+    #[test]
+    fn test_metrics_before_snapshots() {
+        let text = Metrics::new().render(false);
+        assert!(!text.contains("energy_monitor_battery_percent"));
+        assert!(text.contains("energy_monitor_controller_command{command=\"do_nothing\"} 0\n"));
+        assert!(text.contains("energy_monitor_controller_mode{mode=\"auto\"} 0\n"));
+    }
+}
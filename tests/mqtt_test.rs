@@ -0,0 +1,353 @@
+// Import the MQTT bridge from the main module
+#[path = "../src/main.rs"]
+#[allow(dead_code)]
+mod main;
+
+use main::actuator::Actuator;
+use main::ardu::{ArduSketch, ChargerMode, SharedMode};
+use main::battery_health::{BatterySnapshot, BatteryState};
+use main::{controller, controller_command};
+use main::mqtt::{self, ControllerEvent, EventForwarder, Incoming, MqttConfig, MqttLink, Topics};
+use main::rotation::RotationPolicy;
+use main::shutdown::Shutdown;
+use main::store::{ActuatorEvent, CsvStore, SharedStore};
+use main::supervisor::Supervisor;
+use main::utils::MyError;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Local;
+    use std::collections::VecDeque;
+    use std::fs;
+    use std::sync::mpsc::{self, Sender};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Replays `incoming` and records what the bridge sends, then hangs up the sampler
+    struct RecordedLink {
+        incoming: VecDeque<Incoming>,
+        published: Vec<(String, String, bool)>,
+        subscribed: Vec<String>,
+        sampler: Option<Sender<BatterySnapshot>>,
+    }
+
+    impl MqttLink for RecordedLink {
+        fn publish(&mut self, topic: &str, payload: &str, retain: bool) -> Result<(), MyError> {
+            self.published.push((topic.to_owned(), payload.to_owned(), retain));
+            Ok(())
+        }
+
+        fn subscribe(&mut self, topic: &str) -> Result<(), MyError> {
+            self.subscribed.push(topic.to_owned());
+            Ok(())
+        }
+
+        fn poll(&mut self, _timeout: Duration) -> Result<Option<Incoming>, MyError> {
+            let incoming = self.incoming.pop_front();
+            if incoming.is_none() {
+                self.sampler = None;
+            }
+            Ok(incoming)
+        }
+    }
+
+    impl RecordedLink {
+        fn payloads(&self, topic: &str) -> Vec<&str> {
+            self.published
+                .iter()
+                .filter(|(published, _, _)| published == topic)
+                .map(|(_, payload, _)| payload.as_str())
+                .collect()
+        }
+    }
+
+    fn snapshot(charge_now: u32, state: BatteryState) -> BatterySnapshot {
+        BatterySnapshot {
+            charge_now,
+            state,
+            current_now: Some(1250000),
+            voltage_now: Some(11800000),
+            ..BatterySnapshot::default()
+        }
+    }
+
+    /// Sends what the bridge publishes to the test, like a broker to a smart plug
+    struct PlugLink(Sender<(String, String)>);
+
+    impl MqttLink for PlugLink {
+        fn publish(&mut self, topic: &str, payload: &str, _retain: bool) -> Result<(), MyError> {
+            let _ = self.0.send((topic.to_owned(), payload.to_owned()));
+            Ok(())
+        }
+
+        fn subscribe(&mut self, _topic: &str) -> Result<(), MyError> {
+            Ok(())
+        }
+
+        fn poll(&mut self, _timeout: Duration) -> Result<Option<Incoming>, MyError> {
+            thread::sleep(Duration::from_millis(5));
+            Ok(None)
+        }
+    }
+
+    fn event(command: ArduSketch) -> ActuatorEvent {
+        ActuatorEvent {
+            time: Local::now().fixed_offset(),
+            command,
+            error: Some("Actuator error: no arduino".to_owned()),
+            latency: Duration::from_millis(40),
+            percentage_before: 50.0,
+            percentage_after: None,
+        }
+    }
+
+    #[test]
+    fn test_bridge() {
+        let temp_dir = tempdir::TempDir::new("mqtt").expect("Failed to create temporary directory");
+        let topics = Topics::new("Giulio-Laptop.local", "homeassistant");
+        assert_eq!(topics.state(), "energy_monitor/giulio-laptop_local/state");
+
+        let (sampler, snapshots) = mpsc::channel();
+        sampler.send(snapshot(900, BatteryState::Discharging)).unwrap();
+        sampler.send(snapshot(1500, BatteryState::Discharging)).unwrap();
+        // The controller events come from the store, like in the daemon
+        let (sender, events) = mpsc::channel();
+        let store = SharedStore::new(Box::new(CsvStore::new(
+            temp_dir.path().join("data.csv"),
+            false,
+            RotationPolicy::default(),
+        )))
+        .with_observer(Arc::new(EventForwarder(sender)));
+        store.record_decision(ArduSketch::Connect, ChargerMode::Auto);
+        store.record_actuator_event(&event(ArduSketch::Connect));
+        store.record_decision(ArduSketch::Disconnect, ChargerMode::Auto);
+        store.record_actuator_event(&event(ArduSketch::Disconnect));
+        store.record_decision(ArduSketch::DoNothing, ChargerMode::Auto);
+
+        let mode = SharedMode::default();
+        let mut link = RecordedLink {
+            incoming: VecDeque::from([
+                Incoming::Connected,
+                Incoming::Message {
+                    topic: topics.mode_set(),
+                    payload: "Disconnect\n".to_owned(),
+                },
+                Incoming::Message {
+                    topic: topics.mode_set(),
+                    payload: "off".to_owned(),
+                },
+            ]),
+            published: Vec::new(),
+            subscribed: Vec::new(),
+            sampler: Some(sampler),
+        };
+        mqtt::bridge(&mut link, &topics, &snapshots, &events, &mode).unwrap();
+
+        // Only the latest snapshot is published
+        let states = link.payloads(&topics.state());
+        assert_eq!(states.len(), 1);
+        let state: serde_json::Value = serde_json::from_str(states[0]).unwrap();
+        assert_eq!(state["percentage"], 50.0);
+        assert_eq!(state["status"], "Discharging");
+        assert_eq!(state["voltage"], 11.8);
+        assert_eq!(state["power"], 14.75);
+
+        let events = link.payloads(&topics.event());
+        assert_eq!(events.len(), 2);
+        let first: serde_json::Value = serde_json::from_str(events[0]).unwrap();
+        assert_eq!(first["command"], "connect_charger");
+        assert_eq!(first["latency_ms"], 40);
+        // The decisions move the charger topic, the events don't
+        assert_eq!(link.payloads(&topics.charger()), ["ON", "OFF"]);
+
+        let discovery = link
+            .published
+            .iter()
+            .filter(|(topic, _, _)| topic.starts_with("homeassistant/"))
+            .collect::<Vec<_>>();
+        assert_eq!(discovery.len(), 8);
+        assert!(discovery.iter().all(|(_, _, retain)| *retain));
+        let (_, select, _) = discovery
+            .iter()
+            .find(|(topic, _, _)| topic == "homeassistant/select/giulio-laptop_local/mode/config")
+            .unwrap();
+        let select: serde_json::Value = serde_json::from_str(select).unwrap();
+        assert_eq!(select["command_topic"], topics.mode_set());
+        assert_eq!(select["unique_id"], "energy_monitor_giulio-laptop_local_mode");
+        assert_eq!(select["availability_topic"], topics.availability());
+        assert_eq!(link.subscribed, [topics.mode_set()]);
+
+        // The invalid command is ignored
+        assert_eq!(mode.get(), ChargerMode::Disconnect);
+        assert_eq!(link.payloads(&topics.mode()), ["auto", "disconnect"]);
+        assert_eq!(link.payloads(&topics.availability()), ["online", "offline"]);
+    }
+
+    #[test]
+    fn test_plug_following_the_charger_topic() {
+        let temp_dir = tempdir::TempDir::new("plug").expect("Failed to create temporary directory");
+        let topics = Topics::new("laptop", "homeassistant");
+        let (sender, events) = mpsc::channel::<ControllerEvent>();
+        let store = SharedStore::new(Box::new(CsvStore::new(
+            temp_dir.path().join("data.csv"),
+            false,
+            RotationPolicy::default(),
+        )))
+        .with_observer(Arc::new(EventForwarder(sender)));
+        let mode = SharedMode::default();
+        // The plug is switched by Home Assistant, the actuator does nothing
+        let actuator = Actuator::Command {
+            connect: "true".to_owned(),
+            disconnect: "true".to_owned(),
+        };
+        let shutdown = Shutdown::new();
+        shutdown.trigger();
+        let supervisor = Supervisor::new(
+            None,
+            false,
+            Duration::from_millis(1),
+            Duration::from_millis(10),
+            shutdown.clone(),
+        );
+
+        let (controller_sampler, controller_snapshots) = mpsc::channel();
+        let controller_mode = mode.clone();
+        let controller_handle = thread::spawn(move || {
+            controller(&controller_snapshots, &shutdown, &store, &controller_mode, &actuator, &supervisor)
+        });
+        let (bridge_sampler, bridge_snapshots) = mpsc::channel();
+        let (published, plug) = mpsc::channel();
+        let bridge_topics = topics.clone();
+        let bridge_handle = thread::spawn(move || {
+            mqtt::bridge(&mut PlugLink(published), &bridge_topics, &bridge_snapshots, &events, &mode)
+        });
+        let wait_for = |topic: String| loop {
+            let (published_topic, payload) = plug
+                .recv_timeout(Duration::from_secs(5))
+                .unwrap_or_else(|_| panic!("Nothing published on {topic}"));
+            if published_topic == topic {
+                break payload;
+            }
+        };
+
+        controller_sampler.send(snapshot(600, BatteryState::Discharging)).unwrap();
+        // The controller waits for the battery, the plug switches on the topic meanwhile
+        assert_eq!(wait_for(topics.charger()), "ON");
+        controller_sampler.send(snapshot(600, BatteryState::Charging)).unwrap();
+        let event: serde_json::Value = serde_json::from_str(&wait_for(topics.event())).unwrap();
+        assert_eq!(event["command"], "connect_charger");
+        assert_eq!(event["error"], serde_json::Value::Null);
+
+        drop(controller_sampler);
+        controller_handle.join().unwrap().unwrap();
+        drop(bridge_sampler);
+        bridge_handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_controller_modes() {
+        let low = snapshot(600, BatteryState::Discharging);
+        let high = snapshot(2700, BatteryState::Charging);
+        assert_eq!(controller_command(&low, ChargerMode::Auto), ArduSketch::Connect);
+        assert_eq!(controller_command(&high, ChargerMode::Auto), ArduSketch::Disconnect);
+        assert_eq!(controller_command(&low, ChargerMode::Disconnect), ArduSketch::DoNothing);
+        assert_eq!(controller_command(&high, ChargerMode::Connect), ArduSketch::DoNothing);
+        assert_eq!(
+            controller_command(&snapshot(2700, BatteryState::Discharging), ChargerMode::Connect),
+            ArduSketch::Connect
+        );
+        assert_eq!(
+            controller_command(&snapshot(600, BatteryState::Charging), ChargerMode::Disconnect),
+            ArduSketch::Disconnect
+        );
+    }
+
+    #[test]
+    fn test_mode_set_during_do_nothing() {
+        let temp_dir = tempdir::TempDir::new("mode").expect("Failed to create temporary directory");
+        let (sender, events) = mpsc::channel();
+        let store = SharedStore::new(Box::new(CsvStore::new(
+            temp_dir.path().join("data.csv"),
+            false,
+            RotationPolicy::default(),
+        )))
+        .with_observer(Arc::new(EventForwarder(sender)));
+        let mode = SharedMode::default();
+        let actuator = Actuator::Command {
+            connect: "true".to_owned(),
+            disconnect: "true".to_owned(),
+        };
+        // Triggered, the pauses after a reaction are skipped
+        let shutdown = Shutdown::new();
+        shutdown.trigger();
+        let status_path = temp_dir.path().join("data.status");
+        let supervisor = Supervisor::new(
+            Some(status_path.clone()),
+            false,
+            Duration::from_millis(1),
+            Duration::from_millis(10),
+            shutdown.clone(),
+        );
+        // The controller writes its command before running it
+        let wait_command = |command: &str| {
+            while !fs::read_to_string(&status_path)
+                .unwrap_or_default()
+                .contains(&format!("command: {command}\n"))
+            {
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+
+        let (sampler, snapshots) = mpsc::channel();
+        let controller_mode = mode.clone();
+        let handle = thread::spawn(move || {
+            controller(&snapshots, &shutdown, &store, &controller_mode, &actuator, &supervisor)
+        });
+        // Discharging above the limit, nothing to do in auto
+        sampler.send(snapshot(2700, BatteryState::Discharging)).unwrap();
+        wait_command("do_nothing");
+        mode.set(ChargerMode::Connect);
+        sampler.send(snapshot(2700, BatteryState::Discharging)).unwrap();
+        // DoNothing ends on the new mode, the next snapshot is the controller's
+        let outcome = loop {
+            if let ControllerEvent::Outcome(event) = events.recv().unwrap() {
+                break event;
+            }
+        };
+        assert_eq!(outcome.command, ArduSketch::DoNothing);
+        sampler.send(snapshot(2700, BatteryState::Discharging)).unwrap();
+        wait_command("connect_charger");
+        sampler.send(snapshot(2700, BatteryState::Charging)).unwrap();
+        drop(sampler);
+        handle.join().unwrap().unwrap();
+
+        let events = events
+            .try_iter()
+            .filter_map(|event| match event {
+                ControllerEvent::Outcome(event) => Some(event),
+                ControllerEvent::Decision(_) => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 1, "{events:?}");
+        assert_eq!(events[0].command, ArduSketch::Connect);
+        assert_eq!(events[0].error, None);
+        assert_eq!(events[0].percentage_after, Some(90.0));
+    }
+
+    #[test]
+    fn test_mqtt_config() {
+        let config = MqttConfig::new("broker.lan:8883").unwrap();
+        assert_eq!((config.host.as_str(), config.port), ("broker.lan", 8883));
+        assert_eq!(MqttConfig::new("localhost").unwrap().port, 1883);
+        assert!(MqttConfig::new("localhost:mqtt").is_err());
+        assert!(MqttConfig::new(":1883").is_err());
+
+        let config = MqttConfig {
+            password: Some("hunter2".to_owned()),
+            ..config
+        };
+        assert!(!format!("{config:?}").contains("hunter2"));
+    }
+}
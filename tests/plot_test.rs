@@ -0,0 +1,125 @@
+// Import the plots from the main module
+#[path = "../src/main.rs"]
+#[allow(dead_code)]
+mod main;
+
+use main::battery_health::BatteryState;
+use main::history::HistoryRecord;
+use main::plot::{self, PlotFormat};
+use main::rotation;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, Duration};
+    use std::path::Path;
+
+    fn record(minutes: i64, percentage: f32, state: BatteryState) -> HistoryRecord {
+        let start = DateTime::parse_from_rfc3339("2025-01-24T08:00:00+01:00").unwrap();
+        HistoryRecord {
+            time: start + Duration::minutes(minutes),
+            charge_full: 3000,
+            charge_full_design: 3500,
+            battery_health: 85.71 - minutes as f32 / 10000.0,
+            battery_percentage: Some(percentage),
+            battery_status: Some(state),
+            battery: None,
+        }
+    }
+
+    /// Two days of discharging from 80% and charging back, every 5 minutes
+    fn history() -> Vec<HistoryRecord> {
+        (0..2 * 24 * 12)
+            .map(|step| {
+                let minute = step * 5;
+                let hour = minute % (24 * 60) / 60;
+                match hour {
+                    0..=11 => record(minute, 80.0 - hour as f32 * 5.0, BatteryState::Discharging),
+                    _ => record(minute, 20.0 + (hour - 12) as f32 * 5.0, BatteryState::Charging),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_charge_points() {
+        let mut records = history();
+        records.reverse();
+        records[0].battery_percentage = None;
+
+        let all = plot::charge_points(&records, None);
+        assert_eq!(all.len(), records.len() - 1);
+        assert!(all.windows(2).all(|pair| pair[0].time < pair[1].time));
+
+        let last_day = plot::charge_points(&records, Some(Duration::days(1)));
+        assert_eq!(last_day.first().unwrap().time, all.last().unwrap().time - Duration::days(1));
+
+        let health = plot::daily_health(&records);
+        assert_eq!(health.len(), 3);
+        assert!(health[0].1 > health[2].1);
+    }
+
+    #[test]
+    fn test_terminal_chart() {
+        let chart = plot::terminal_chart(&history(), None, 80, false).unwrap();
+        let lines = chart.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines[0], "Charge, 24/01 08:00 to 26/01 07:55");
+        assert!(lines[1].starts_with(" 100.0 ┤"));
+        assert!(lines[10].starts_with("   0.0 ┤"));
+        assert_eq!(lines[1].chars().count(), 80);
+        // The bottom row has a block in every column
+        assert!(lines[10].chars().skip(8).all(|cell| cell != ' '), "{chart}");
+        assert_eq!(lines[13].trim(), "Charging  Discharging");
+        assert!(chart.contains("Battery health, 24/01/2025 to 26/01/2025"));
+        assert!(!chart.contains('\x1b'));
+
+        let colored = plot::terminal_chart(&history(), None, 80, true).unwrap();
+        assert!(colored.contains("\x1b[31m"));
+        assert!(colored.contains("\x1b[32m"));
+
+        assert!(plot::terminal_chart(&[], None, 80, false).is_err());
+    }
+
+    #[test]
+    fn test_plot_file() {
+        let temp_dir = tempdir::TempDir::new("plot").expect("Failed to create temporary directory");
+        let path = temp_dir.path().join("history.svg");
+        plot::plot_file(&history(), &path, Some(Duration::days(1))).unwrap();
+
+        let svg = std::fs::read_to_string(&path).unwrap();
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains("Charge"));
+        assert!(svg.contains("Battery health"));
+        assert!(svg.contains("Discharging"));
+
+        assert_eq!(PlotFormat::from_path(Path::new("a.png")).unwrap(), PlotFormat::Png);
+        assert!(PlotFormat::from_path(Path::new("a.pdf")).is_err());
+        assert!(plot::plot_file(&history(), &temp_dir.path().join("history"), None).is_err());
+    }
+
+    #[test]
+    fn test_health_only_history() {
+        // Written before the percentage was recorded
+        let records = rotation::read_rotated_history(Path::new("data/data_with_hour.csv")).unwrap();
+        assert!(plot::charge_points(&records, None).is_empty());
+
+        let chart = plot::terminal_chart(&records, None, 80, false).unwrap();
+        assert!(chart.starts_with("Battery health, 19/04/2024 to "), "{chart}");
+        assert!(!chart.contains("Charge"));
+
+        let temp_dir = tempdir::TempDir::new("plot").expect("Failed to create temporary directory");
+        let path = temp_dir.path().join("health.svg");
+        plot::plot_file(&records, &path, Some(Duration::days(7))).unwrap();
+        let svg = std::fs::read_to_string(&path).unwrap();
+        assert!(svg.contains("Battery health"));
+        assert!(!svg.contains("Charge"));
+    }
+
+    #[test]
+    fn test_span_before_the_calendar() {
+        let records = history();
+        let all = plot::charge_points(&records, None);
+        assert_eq!(plot::charge_points(&records, Some(Duration::max_value())), all);
+    }
+}
@@ -0,0 +1,102 @@
+// Import the power meter from the main module
+#[path = "../src/main.rs"]
+#[allow(dead_code)]
+mod main;
+
+use main::battery_health::{BatterySnapshot, BatteryState};
+use main::power::SessionMeter;
+use main::power_meter;
+use main::rotation::RotationPolicy;
+use main::sampler::{RecordedSource, Sampler};
+use main::shutdown::Shutdown;
+use main::store::{CsvStore, SharedStore};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Local, TimeZone};
+    use std::fs;
+    use std::time::Duration;
+
+    /// 10 W from the voltage and the current, unless `power_now` says otherwise
+    fn snapshot(minutes: i64, state: BatteryState, power_now: Option<u32>) -> BatterySnapshot {
+        let start = Local.with_ymd_and_hms(2025, 1, 25, 12, 0, 0).unwrap();
+        BatterySnapshot {
+            time: start + chrono::Duration::minutes(minutes),
+            charge_now: 2000 - 10 * minutes as u32,
+            state,
+            // 10 W
+            current_now: Some(800000),
+            voltage_now: Some(12500000),
+            power_now,
+            ..BatterySnapshot::default()
+        }
+    }
+
+    #[test]
+    fn test_session_energy() {
+        let mut meter = SessionMeter::new();
+        for minute in 0..=30 {
+            assert_eq!(meter.update(&snapshot(minute, BatteryState::Discharging, None)), None);
+        }
+        let session = meter.update(&snapshot(31, BatteryState::Charging, None)).unwrap();
+
+        assert_eq!(session.duration(), chrono::Duration::minutes(30));
+        assert_eq!(session.active, Duration::from_secs(30 * 60));
+        assert!((session.energy_wh - 5.0).abs() < 1e-9);
+        assert!((session.average_w() - 10.0).abs() < 1e-9);
+        assert!((session.peak_w - 10.0).abs() < 1e-9);
+        assert!((session.percentage_start - 66.67).abs() < 0.01);
+        assert!((session.percentage_end - 56.67).abs() < 0.01);
+        assert_eq!(meter.finish(), None);
+    }
+
+    #[test]
+    fn test_session_gaps() {
+        let mut meter = SessionMeter::new();
+        // power_now wins over voltage × current, the suspended hour isn't counted
+        for minute in [0, 5, 65, 70] {
+            meter.update(&snapshot(minute, BatteryState::Discharging, Some(20000000)));
+        }
+        let session = meter.finish().unwrap();
+        assert!((session.energy_wh - 20.0 * 10.0 / 60.0).abs() < 1e-9);
+        assert!((session.peak_w - 20.0).abs() < 1e-9);
+        // Averaged over the 10 minutes awake, not the 70 of the session
+        assert_eq!(session.active, Duration::from_secs(10 * 60));
+        assert!((session.average_w() - 20.0).abs() < 1e-9);
+
+        // The controller toggling at the upper limit doesn't make sessions
+        meter.update(&snapshot(0, BatteryState::Discharging, None));
+        meter.update(&snapshot(1, BatteryState::Discharging, None));
+        assert_eq!(meter.update(&snapshot(2, BatteryState::Charging, None)), None);
+    }
+
+    #[test]
+    fn test_power_meter_logs_sessions() {
+        let temp_dir = tempdir::TempDir::new("power").expect("Failed to create temporary directory");
+        let csv_store = CsvStore::new(temp_dir.path().join("data.csv"), false, RotationPolicy::default());
+        let sessions_path = csv_store.power_sessions_path();
+        let store = SharedStore::new(Box::new(csv_store));
+
+        // Still on battery when the sampler stops, the session is logged anyway
+        let snapshots = (0..=12)
+            .map(|minute| snapshot(minute * 5, BatteryState::Discharging, None))
+            .collect();
+        let mut sampler = Sampler::new();
+        let receiver = sampler.subscribe();
+        sampler
+            .run(&mut RecordedSource::new(snapshots), Duration::ZERO, &Shutdown::new())
+            .expect("Failed replaying snapshots");
+        power_meter(&store, &receiver).expect("power_meter failed");
+
+        let sessions = fs::read_to_string(sessions_path).unwrap();
+        let mut lines = sessions.lines();
+        assert_eq!(
+            lines.next(),
+            Some("Start,End,Duration_min,Energy_Wh,Average_W,Peak_W,Percentage_Start,Percentage_End")
+        );
+        let row = lines.next().unwrap().split(',').collect::<Vec<_>>();
+        assert_eq!(&row[2..6], ["60", "10.000", "10.00", "10.00"]);
+        assert_eq!(lines.next(), None);
+    }
+}
@@ -0,0 +1,124 @@
+// Import the history rotation from the main module
+#[path = "../src/main.rs"]
+#[allow(dead_code)]
+mod main;
+
+use main::battery_health::BatteryState;
+use main::history::HistoryRecord;
+use main::rotation::{self, Rotation, RotationPolicy};
+use main::store::{CsvStore, HistoryStore};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+    use std::fs::{self, File};
+    use std::time::{Duration, SystemTime};
+
+    fn record(time: &str) -> HistoryRecord {
+        HistoryRecord {
+            time: DateTime::parse_from_rfc3339(time).unwrap(),
+            charge_full: 3000,
+            charge_full_design: 3500,
+            battery_health: 85.71429,
+            battery_percentage: Some(80.0),
+            battery_status: Some(BatteryState::Discharging),
+            battery: None,
+        }
+    }
+
+    fn file_names(dir: &std::path::Path) -> Vec<String> {
+        let mut names = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_monthly_rotation_with_gzip() {
+        let temp_dir = tempdir::TempDir::new("rotation").expect("Failed to create temporary directory");
+        let path = temp_dir.path().join("data.csv");
+        let policy = RotationPolicy {
+            rotation: Rotation::Monthly,
+            compress: true,
+            retention: None,
+        };
+        let mut store = CsvStore::new(&path, false, policy);
+
+        let records = [
+            record("2025-01-30T10:00:00+01:00"),
+            record("2025-01-31T10:00:00+01:00"),
+            record("2025-02-01T10:00:00+01:00"),
+            record("2025-03-01T10:00:00+01:00"),
+        ];
+        for record in &records {
+            store.append_snapshot(record).unwrap();
+        }
+
+        assert_eq!(
+            file_names(temp_dir.path()),
+            ["data.2025-01.csv.gz", "data.2025-02.csv.gz", "data.csv"]
+        );
+        assert_eq!(rotation::read_rotated_history(&path).unwrap(), records);
+        // Every file, rotated or not, is a complete history file
+        assert!(fs::read_to_string(&path).unwrap().starts_with("# energy_monitor schema="));
+
+        let from = records[1].time;
+        let to = records[3].time;
+        assert_eq!(store.snapshots_between(from, to).unwrap(), records[1..3]);
+    }
+
+    #[test]
+    fn test_size_rotation_and_retention() {
+        let temp_dir = tempdir::TempDir::new("rotation").expect("Failed to create temporary directory");
+        let path = temp_dir.path().join("data.csv");
+        // Not rotated files, they are never listed nor deleted
+        fs::write(temp_dir.path().join("data.csv.bak"), "").unwrap();
+        fs::write(temp_dir.path().join("data.notifications.csv"), "").unwrap();
+
+        let policy = RotationPolicy {
+            rotation: Rotation::Size(1),
+            compress: false,
+            retention: Some(Duration::from_secs(24 * 60 * 60)),
+        };
+        let mut store = CsvStore::new(&path, false, policy);
+        store.append_snapshot(&record("2025-01-25T12:22:00+01:00")).unwrap();
+        store.append_snapshot(&record("2025-01-25T12:27:00+01:00")).unwrap();
+
+        let rotated = rotation::rotated_files(&path).unwrap();
+        assert_eq!(rotated, [temp_dir.path().join("data.2025-01-25T122200.csv")]);
+
+        // A rotated file last written two days ago is past the retention
+        let two_days_ago = SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60);
+        File::options()
+            .write(true)
+            .open(&rotated[0])
+            .unwrap()
+            .set_modified(two_days_ago)
+            .unwrap();
+        // Going back before the epoch everything is kept
+        let forever = RotationPolicy {
+            retention: Some(Duration::from_secs(u64::MAX)),
+            ..policy
+        };
+        assert!(forever.apply_retention(&path).unwrap().is_empty());
+        assert_eq!(policy.apply_retention(&path).unwrap(), rotated);
+        assert_eq!(
+            file_names(temp_dir.path()),
+            ["data.csv", "data.csv.bak", "data.notifications.csv"]
+        );
+    }
+
+    #[test]
+    fn test_rotation_match_string() {
+        assert_eq!(Rotation::match_string("never").unwrap(), Rotation::Never);
+        assert_eq!(Rotation::match_string("monthly").unwrap(), Rotation::Monthly);
+        assert_eq!(Rotation::match_string("512kb").unwrap(), Rotation::Size(512 * 1024));
+        assert_eq!(Rotation::match_string("10mb").unwrap(), Rotation::Size(10 * 1024 * 1024));
+        assert!(Rotation::match_string("0mb").is_err());
+        assert!(Rotation::match_string("weekly").is_err());
+        assert!(Rotation::match_string("b").is_err());
+    }
+}
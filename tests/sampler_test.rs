@@ -0,0 +1,123 @@
+// Import the sampler from the main module
+#[path = "../src/main.rs"]
+#[allow(dead_code)]
+mod main;
+
+use main::battery_health::BatteryState;
+use main::sampler::{recv_latest, Sampler, SnapshotSource, SysfsSource};
+use main::shutdown::{PowerEvents, Shutdown};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+    use std::time::Duration;
+
+    /// Fake /sys/class/power_supply/BAT1 with the files the sampler reads
+    fn write_battery(dir: &Path, charge_now: u32, status: &str) {
+        fs::write(dir.join("charge_now"), format!("{charge_now}\n")).unwrap();
+        fs::write(dir.join("charge_full"), "3073000\n").unwrap();
+        fs::write(dir.join("charge_full_design"), "3620000\n").unwrap();
+        fs::write(dir.join("status"), format!("{status}\n")).unwrap();
+    }
+
+    #[test]
+    fn test_sysfs_source() {
+        let temp_dir = tempdir::TempDir::new("BAT1").expect("Failed to create temporary directory");
+        write_battery(temp_dir.path(), 2899999, "Not charging");
+
+        let snapshot = SysfsSource::new(temp_dir.path())
+            .sample()
+            .expect("Failed reading fake battery")
+            .unwrap();
+        assert_eq!(snapshot.state, BatteryState::NotCharging);
+        assert!((snapshot.percentage() - 94.37).abs() < 0.01);
+        assert!((snapshot.health() - 84.8895).abs() < 0.001);
+        assert_eq!(snapshot.current_now, None);
+
+        // Some drivers report the discharge current as negative
+        fs::write(temp_dir.path().join("current_now"), "-1234000\n").unwrap();
+        let snapshot = SysfsSource::new(temp_dir.path()).sample().unwrap().unwrap();
+        assert_eq!(snapshot.current_now, Some(1234000));
+        assert_eq!(snapshot.power(), None);
+
+        // 1.234 A at 12 V
+        fs::write(temp_dir.path().join("voltage_now"), "12000000\n").unwrap();
+        let snapshot = SysfsSource::new(temp_dir.path()).sample().unwrap().unwrap();
+        assert!((snapshot.power().unwrap() - 14.808).abs() < 1e-9);
+        assert_eq!(snapshot.cycle_count, None);
+        fs::write(temp_dir.path().join("cycle_count"), "0\n").unwrap();
+        let snapshot = SysfsSource::new(temp_dir.path()).sample().unwrap().unwrap();
+        assert_eq!(snapshot.cycle_count, Some(0));
+
+        let identity = snapshot.identity.unwrap();
+        assert_eq!(identity.serial_number, "");
+        assert_eq!(identity.charge_full_design, 3620000);
+        fs::write(temp_dir.path().join("manufacturer"), "SMP\n").unwrap();
+        fs::write(temp_dir.path().join("model_name"), "L19M3PF3\n").unwrap();
+        fs::write(temp_dir.path().join("serial_number"), "1234\n").unwrap();
+        let snapshot = SysfsSource::new(temp_dir.path()).sample().unwrap().unwrap();
+        assert_eq!(snapshot.identity.unwrap().key(), "SMP;L19M3PF3;1234;3620000");
+
+        write_battery(temp_dir.path(), 2899999, "Sleeping");
+        assert!(SysfsSource::new(temp_dir.path()).sample().is_err());
+
+        // A laptop without BAT1 gets a message, not a raw IO error
+        let err = SysfsSource::new(temp_dir.path().join("BAT1")).sample().unwrap_err();
+        assert_eq!(format!("{err:?}"), format!("Battery error: Battery not found at {}", temp_dir.path().join("BAT1").display()));
+    }
+
+    #[test]
+    fn test_every_consumer_gets_the_same_snapshot() {
+        let temp_dir = tempdir::TempDir::new("BAT1").expect("Failed to create temporary directory");
+        write_battery(temp_dir.path(), 1500000, "Discharging");
+
+        let shutdown = Shutdown::new();
+        let mut sampler = Sampler::new();
+        let notifier = sampler.subscribe();
+        let controller = sampler.subscribe();
+
+        let sampler_shutdown = shutdown.clone();
+        let handle = std::thread::spawn(move || {
+            let mut source = SysfsSource::new(temp_dir.path());
+            sampler.run(&mut source, Duration::from_secs(60), &sampler_shutdown)
+        });
+
+        let first = notifier.recv().unwrap();
+        assert_eq!(controller.recv().unwrap(), first);
+
+        // Stopping the sampler closes the channels of the consumers
+        shutdown.trigger();
+        handle.join().unwrap().unwrap();
+        assert_eq!(recv_latest(&notifier), None);
+        assert_eq!(recv_latest(&controller), None);
+    }
+
+    #[test]
+    fn test_power_event_samples_again() {
+        let temp_dir = tempdir::TempDir::new("BAT1").expect("Failed to create temporary directory");
+        write_battery(temp_dir.path(), 1500000, "Discharging");
+
+        let shutdown = Shutdown::new();
+        let power_events = PowerEvents::new(&shutdown);
+        let mut sampler = Sampler::new().with_power_events(power_events.clone());
+        let receiver = sampler.subscribe();
+
+        let sampler_shutdown = shutdown.clone();
+        let battery = temp_dir.path().to_owned();
+        let handle = std::thread::spawn(move || {
+            let mut source = SysfsSource::new(battery);
+            sampler.run(&mut source, Duration::from_secs(60), &sampler_shutdown)
+        });
+        assert_eq!(receiver.recv().unwrap().state, BatteryState::Discharging);
+
+        // Plugged: without the event the next sample would come in a minute
+        write_battery(temp_dir.path(), 1500000, "Charging");
+        power_events.notify();
+        assert_eq!(receiver.recv().unwrap().state, BatteryState::Charging);
+
+        shutdown.trigger();
+        handle.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,302 @@
+// Import the history stores from the main module
+#[path = "../src/main.rs"]
+#[allow(dead_code)]
+mod main;
+
+use main::battery_health::{BatteryIdentity, BatteryState};
+use main::history::HistoryRecord;
+use main::rotation::RotationPolicy;
+use main::store::{CsvStore, HistoryBackend, HistoryStore};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, Duration, Utc};
+
+    fn record(time: &str, percentage: f32) -> HistoryRecord {
+        HistoryRecord {
+            time: DateTime::parse_from_rfc3339(time).unwrap(),
+            charge_full: 3000,
+            charge_full_design: 3500,
+            battery_health: 85.71429,
+            battery_percentage: Some(percentage),
+            battery_status: Some(BatteryState::Discharging),
+            battery: None,
+        }
+    }
+
+    #[test]
+    fn test_csv_store_between() {
+        let temp_dir =
+            tempdir::TempDir::new("store").expect("Failed to create temporary directory");
+        let mut store = CsvStore::new(temp_dir.path().join("data.csv"), false, RotationPolicy::default());
+        let first = record("2025-01-25T12:22:00+01:00", 80.0);
+        let second = record("2025-01-25T12:27:00+01:00", 78.0);
+        store.append_snapshot(&first).unwrap();
+        store.append_snapshot(&second).unwrap();
+
+        let all = store
+            .snapshots_between(first.time, second.time + Duration::seconds(1))
+            .unwrap();
+        assert_eq!(all, vec![first.clone(), second.clone()]);
+        assert_eq!(
+            store.snapshots_between(first.time, second.time).unwrap(),
+            vec![first.clone()]
+        );
+        let all_time = store
+            .snapshots_between(DateTime::<Utc>::MIN_UTC.fixed_offset(), DateTime::<Utc>::MAX_UTC.fixed_offset())
+            .unwrap();
+        assert_eq!(all_time, vec![first, second]);
+    }
+
+    #[test]
+    fn test_csv_store_segments() {
+        let temp_dir =
+            tempdir::TempDir::new("store").expect("Failed to create temporary directory");
+        let path = temp_dir.path().join("data.csv");
+        let mut first = record("2025-01-25T12:22:00+01:00", 80.0);
+        first.battery = Some(BatteryIdentity {
+            manufacturer: "SMP".to_owned(),
+            model_name: "L19M3PF3".to_owned(),
+            serial_number: "123".to_owned(),
+            charge_full_design: 3500,
+        });
+        let second = HistoryRecord {
+            time: first.time + Duration::minutes(5),
+            ..first.clone()
+        };
+
+        // A restarted daemon finds the battery of the file and doesn't start a segment
+        CsvStore::new(&path, false, RotationPolicy::default())
+            .append_snapshot(&first)
+            .unwrap();
+        CsvStore::new(&path, false, RotationPolicy::default())
+            .append_snapshot(&second)
+            .unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content.matches("# energy_monitor battery=").count(), 1);
+    }
+
+    #[test]
+    fn test_csv_actuator_events() {
+        use main::ardu::ArduSketch;
+        use main::store::ActuatorEvent;
+
+        let temp_dir =
+            tempdir::TempDir::new("store").expect("Failed to create temporary directory");
+        let mut store = CsvStore::new(temp_dir.path().join("data.csv"), false, RotationPolicy::default());
+        assert_eq!(store.last_actuator_events(5).unwrap(), vec![]);
+
+        let time = DateTime::parse_from_rfc3339("2025-01-25T12:22:00+01:00").unwrap();
+        let events = vec![
+            ActuatorEvent {
+                time,
+                command: ArduSketch::Connect,
+                error: None,
+                latency: std::time::Duration::from_millis(19500),
+                percentage_before: 60.0,
+                percentage_after: Some(60.2),
+            },
+            ActuatorEvent {
+                time: time + Duration::hours(1),
+                command: ArduSketch::Disconnect,
+                error: Some("Failed flashing \"disconnect_charger\", port busy".to_owned()),
+                latency: std::time::Duration::from_millis(800),
+                percentage_before: 74.1,
+                percentage_after: None,
+            },
+            ActuatorEvent {
+                time: time + Duration::hours(2),
+                command: ArduSketch::DoNothing,
+                error: None,
+                latency: std::time::Duration::from_millis(0),
+                percentage_before: 74.0,
+                percentage_after: Some(74.0),
+            },
+        ];
+        for event in &events {
+            store.append_actuator_event(event).unwrap();
+        }
+
+        assert_eq!(store.last_actuator_events(2).unwrap(), events[1..]);
+        assert_eq!(store.last_actuator_events(10).unwrap(), events);
+
+        // The daemon crashed writing the last row
+        let path = store.actuator_events_path();
+        let complete = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("{complete}2025-01-25T15:22:00+01:00,connect_char")).unwrap();
+        assert_eq!(store.last_actuator_events(2).unwrap(), events[1..]);
+        // Anywhere else it's an error
+        std::fs::write(&path, format!("{complete}2025-01-25T15:22:00+01:00,connect_char\n{complete}")).unwrap();
+        assert!(store.last_actuator_events(10).is_err());
+    }
+
+    #[test]
+    fn test_history_backend() {
+        assert_eq!(
+            HistoryBackend::match_string("csv").unwrap(),
+            HistoryBackend::Csv
+        );
+        assert_eq!(
+            HistoryBackend::match_string("sqlite").unwrap(),
+            HistoryBackend::Sqlite
+        );
+        assert!(HistoryBackend::match_string("postgres").is_err());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_store() {
+        use main::ardu::ArduSketch;
+        use main::store::sqlite::{ImportReport, SqliteStore};
+        use main::store::{ActuatorEvent, NotificationEvent, PowerSession};
+        use std::fs;
+
+        let temp_dir =
+            tempdir::TempDir::new("store").expect("Failed to create temporary directory");
+        let csv_path = temp_dir.path().join("data.csv");
+        let db_path = temp_dir.path().join("data.db");
+        fs::write(
+            &csv_path,
+            "\
+Date,Hour,Charge_Full,Charge_Full_Design,Battery_Health,Battery_Percentage,Battery_Status
+19/04/2024,08:00,3165000,3620000,87.43094,N/A,N/A
+25/04/2024,09:53,3190000,3620000,88.121544,54.670845,Charging
+",
+        )
+        .unwrap();
+
+        // A database from before the battery column
+        rusqlite::Connection::open(&db_path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE snapshots (time TEXT NOT NULL, epoch INTEGER NOT NULL,
+                    charge_full INTEGER NOT NULL, charge_full_design INTEGER NOT NULL,
+                    battery_health REAL NOT NULL, battery_percentage REAL, battery_status TEXT)",
+            )
+            .unwrap();
+        let mut store = SqliteStore::open(&db_path).unwrap();
+        assert_eq!(
+            store.import_csv(&csv_path).unwrap(),
+            ImportReport { imported: 2, skipped: 0 }
+        );
+        // Importing again doesn't duplicate the rows, and says so
+        assert_eq!(
+            store.import_csv(&csv_path).unwrap(),
+            ImportReport { imported: 0, skipped: 2 }
+        );
+
+        let mut snapshot = record("2025-01-25T12:22:00+01:00", 80.0);
+        snapshot.battery = Some(BatteryIdentity {
+            manufacturer: "SMP".to_owned(),
+            model_name: "L19M3PF3".to_owned(),
+            serial_number: "123".to_owned(),
+            charge_full_design: 3500,
+        });
+        store.append_snapshot(&snapshot).unwrap();
+        store
+            .append_actuator_event(&ActuatorEvent {
+                time: snapshot.time,
+                command: ArduSketch::Connect,
+                error: None,
+                latency: std::time::Duration::from_millis(19500),
+                percentage_before: 80.0,
+                percentage_after: Some(80.2),
+            })
+            .unwrap();
+        store
+            .append_notification(&NotificationEvent {
+                time: snapshot.time,
+                level: "20%".to_owned(),
+                message: "Connetti il caricatore!!".to_owned(),
+            })
+            .unwrap();
+        store
+            .append_power_session(&PowerSession {
+                start: snapshot.time,
+                end: snapshot.time + Duration::hours(2),
+                active: std::time::Duration::from_secs(2 * 3600),
+                energy_wh: 18.5,
+                peak_w: 21.0,
+                percentage_start: 80.0,
+                percentage_end: 35.0,
+            })
+            .unwrap();
+
+        let from = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap();
+        let records = store
+            .snapshots_between(from, snapshot.time + Duration::days(1))
+            .unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].battery_status, None);
+        assert_eq!(records[2], snapshot);
+        let recent = store
+            .snapshots_between(snapshot.time, snapshot.time + Duration::days(1))
+            .unwrap();
+        assert_eq!(recent, vec![snapshot.clone()]);
+        // The whole history, as the reports read it
+        let all_time = store
+            .snapshots_between(DateTime::<Utc>::MIN_UTC.fixed_offset(), DateTime::<Utc>::MAX_UTC.fixed_offset())
+            .unwrap();
+        assert_eq!(all_time, records);
+        let events = store.last_actuator_events(5).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].command, ArduSketch::Connect);
+        assert_eq!(events[0].percentage_after, Some(80.2));
+
+        // The store is a plain SQLite database
+        drop(store);
+        let connection = rusqlite::Connection::open(&db_path).unwrap();
+        let command: String = connection
+            .query_row("SELECT command FROM actuator_events", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(command, "connect_charger");
+        let notifications: i64 = connection
+            .query_row("SELECT COUNT(*) FROM notifications", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(notifications, 1);
+        let energy: f64 = connection
+            .query_row("SELECT energy_wh FROM power_sessions", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(energy, 18.5);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_same_second() {
+        use main::store::sqlite::SqliteStore;
+
+        let temp_dir =
+            tempdir::TempDir::new("store").expect("Failed to create temporary directory");
+        let db_path = temp_dir.path().join("data.db");
+        // A database with the unique epoch index, it dropped the rows of the same second
+        rusqlite::Connection::open(&db_path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE snapshots (time TEXT NOT NULL, epoch INTEGER NOT NULL,
+                    charge_full INTEGER NOT NULL, charge_full_design INTEGER NOT NULL,
+                    battery_health REAL NOT NULL, battery_percentage REAL, battery_status TEXT,
+                    battery TEXT);
+                CREATE UNIQUE INDEX snapshots_epoch ON snapshots (epoch);",
+            )
+            .unwrap();
+        let mut store = SqliteStore::open(&db_path).unwrap();
+
+        // The last row of the old battery and the first of the new one after a swap
+        let old_battery = record("2025-01-25T12:22:00+01:00", 80.0);
+        let mut new_battery = record("2025-01-25T12:22:00+01:00", 100.0);
+        new_battery.battery = Some(BatteryIdentity {
+            manufacturer: "SMP".to_owned(),
+            model_name: "L19M3PF3".to_owned(),
+            serial_number: "456".to_owned(),
+            charge_full_design: 3500,
+        });
+        store.append_snapshot(&old_battery).unwrap();
+        store.append_snapshot(&new_battery).unwrap();
+
+        let records = store
+            .snapshots_between(old_battery.time, old_battery.time + Duration::minutes(1))
+            .unwrap();
+        assert_eq!(records, vec![old_battery, new_battery]);
+    }
+}
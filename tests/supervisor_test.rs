@@ -0,0 +1,170 @@
+// Import the supervisor from the main module
+#[path = "../src/main.rs"]
+#[allow(dead_code)]
+mod main;
+
+use main::shutdown::Shutdown;
+use main::supervisor::{self, Supervisor, WorkerHealth};
+use main::daemon_status;
+use main::utils::MyError;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    fn test_supervisor(status_path: Option<std::path::PathBuf>, shutdown: Shutdown) -> Supervisor {
+        Supervisor::new(
+            status_path,
+            false,
+            Duration::from_millis(1),
+            Duration::from_millis(10),
+            shutdown,
+        )
+    }
+
+    #[test]
+    fn test_worker_restarted_after_panic_and_error() {
+        let supervisor = test_supervisor(None, Shutdown::new());
+        let runs = Arc::new(Mutex::new(0));
+
+        let worker_runs = runs.clone();
+        let handle = supervisor.spawn("controller", move || {
+            let runs = {
+                let mut runs = worker_runs.lock().unwrap();
+                *runs += 1;
+                *runs
+            };
+            match runs {
+                1 => panic!("Invalid str_state"),
+                2 => Err(MyError::BatteryError("Not charging".to_owned())),
+                _ => Ok(()),
+            }
+        });
+        handle.join().unwrap();
+
+        assert_eq!(*runs.lock().unwrap(), 3);
+        assert_eq!(supervisor.health("controller"), Some(WorkerHealth::Stopped));
+        assert!(!supervisor.is_degraded());
+    }
+
+    #[test]
+    fn test_degraded_status_written() {
+        let temp_dir = tempdir::TempDir::new("test_status").expect("Failed to create temporary directory");
+        let status_path = temp_dir.path().join("data.status");
+        let shutdown = Shutdown::new();
+        let supervisor = test_supervisor(Some(status_path.clone()), shutdown.clone());
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let handle = supervisor.spawn("controller", move || {
+            // Block on the second run so that the degraded state can be observed
+            receiver.recv().map_err(|err| MyError::ActuatorError(err.to_string()))?;
+            Err(MyError::ActuatorError("avrdude failed".to_owned()))
+        });
+        sender.send(()).unwrap();
+
+        // Wait for the first failure to be written
+        let status = loop {
+            let status = fs::read_to_string(&status_path).unwrap_or_default();
+            if status.starts_with("degraded") {
+                break status;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        assert!(status.starts_with("degraded: controller restarting (1 failures)"));
+        assert!(status.contains("avrdude failed"));
+        assert!(supervisor.is_degraded());
+
+        // Dropping the sender makes the worker fail forever, the shutdown stops it
+        drop(sender);
+        shutdown.trigger();
+        handle.join().unwrap();
+        assert_eq!(supervisor.health("controller"), Some(WorkerHealth::Stopped));
+        assert_eq!(fs::read_to_string(&status_path).unwrap(), "ok\n");
+    }
+
+    #[test]
+    fn test_status_details() {
+        let temp_dir = tempdir::TempDir::new("test_details").expect("Failed to create temporary directory");
+        let status_path = temp_dir.path().join("data.status");
+        let supervisor = test_supervisor(Some(status_path.clone()), Shutdown::new());
+
+        supervisor.set_detail("mode", "disconnect".to_owned());
+        supervisor.set_detail("command", "do_nothing".to_owned());
+        // The health changes keep the details
+        supervisor.spawn("notifier", || Ok(())).join().unwrap();
+        let status = fs::read_to_string(&status_path).unwrap();
+        assert_eq!(status, "ok\ncommand: do_nothing\nmode: disconnect\n");
+
+        let (summary, details) = supervisor::parse_status(&status);
+        assert_eq!(summary, "ok");
+        assert_eq!(details["mode"], "disconnect");
+        assert_eq!(details.len(), 2);
+        // The status files written before the details
+        assert!(supervisor::parse_status("ok\n").1.is_empty());
+    }
+
+    #[test]
+    fn test_daemon_status_printed() {
+        assert_eq!(daemon_status(None), "Daemon: not running\n");
+        assert_eq!(daemon_status(Some("ok\n")), "Daemon: ok\n");
+        let status = "degraded: controller restarting (2 failures): avrdude failed\ncommand: connect\nmode: auto\n";
+        assert_eq!(
+            daemon_status(Some(status)),
+            "Daemon: degraded: controller restarting (2 failures): avrdude failed\n  command: connect\n  mode: auto\n"
+        );
+    }
+
+    #[test]
+    fn test_stale_and_removed_status() {
+        let temp_dir = tempdir::TempDir::new("test_stale").expect("Failed to create temporary directory");
+        let status_path = temp_dir.path().join("data.status");
+        let supervisor = test_supervisor(Some(status_path.clone()), Shutdown::new());
+        let max_age = Duration::from_secs(180);
+
+        supervisor.set_detail("mode", "connect".to_owned());
+        assert_eq!(supervisor::read_status(&status_path, max_age).unwrap(), "ok\nmode: connect\n");
+
+        // Left behind by a daemon that was killed
+        let old = std::time::SystemTime::now() - Duration::from_secs(600);
+        fs::File::options().write(true).open(&status_path).unwrap().set_modified(old).unwrap();
+        assert_eq!(supervisor::read_status(&status_path, max_age), None);
+        // A running daemon writes it again even when nothing changed
+        supervisor.refresh_status();
+        assert_eq!(supervisor::read_status(&status_path, max_age).unwrap(), "ok\nmode: connect\n");
+
+        // A clean shutdown
+        supervisor.remove_status();
+        assert!(!status_path.exists());
+        assert_eq!(supervisor::read_status(&status_path, max_age), None);
+        supervisor.remove_status();
+    }
+
+    #[test]
+    fn test_shutdown_stops_workers() {
+        let shutdown = Shutdown::new();
+        let supervisor = test_supervisor(None, shutdown.clone());
+
+        // A worker looping like the controller does
+        let worker_shutdown = shutdown.clone();
+        let looping = supervisor.spawn("controller", move || loop {
+            if worker_shutdown.sleep(Duration::from_secs(60)) {
+                return Ok(());
+            }
+        });
+        // A worker that keeps failing and waits in the backoff
+        let failing = supervisor.spawn("notifier", || {
+            Err(MyError::NotifierError("notify-send failed".to_owned()))
+        });
+
+        shutdown.trigger();
+        looping.join().unwrap();
+        failing.join().unwrap();
+
+        assert!(shutdown.sleep(Duration::from_secs(60)));
+        assert_eq!(supervisor.health("controller"), Some(WorkerHealth::Stopped));
+        assert_eq!(supervisor.health("notifier"), Some(WorkerHealth::Stopped));
+    }
+}
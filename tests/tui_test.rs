@@ -0,0 +1,142 @@
+// Import the dashboard from the main module
+#[path = "../src/main.rs"]
+#[allow(dead_code)]
+mod main;
+
+use main::ardu::{ArduSketch, ChargerMode};
+use main::battery_health::{BatterySnapshot, BatteryState};
+use main::estimate::Estimate;
+use main::history::HistoryRecord;
+use main::store::ActuatorEvent;
+use main::tui::{self, Dashboard, Limits};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Local, TimeZone};
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+    use std::time::Duration;
+
+    fn snapshot(charge_now: u32, state: BatteryState) -> BatterySnapshot {
+        BatterySnapshot {
+            time: Local.with_ymd_and_hms(2025, 1, 25, 12, 0, 0).unwrap(),
+            charge_now,
+            charge_full: 3000000,
+            charge_full_design: 3500000,
+            state,
+            current_now: Some(1000000),
+            voltage_now: Some(12000000),
+            ..BatterySnapshot::default()
+        }
+    }
+
+    fn record(snapshot: &BatterySnapshot, hours_ago: i64, percentage: f32) -> HistoryRecord {
+        HistoryRecord {
+            time: snapshot.time.fixed_offset() - chrono::Duration::hours(hours_ago),
+            charge_full: 3000000,
+            charge_full_design: 3500000,
+            battery_health: 85.71,
+            battery_percentage: Some(percentage),
+            battery_status: Some(BatteryState::Discharging),
+            battery: None,
+        }
+    }
+
+    fn screen(dashboard: &Dashboard) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(70, 24)).unwrap();
+        terminal.draw(|frame| dashboard.render(frame)).unwrap();
+        let buffer = terminal.backend().buffer();
+        (0..buffer.area.height)
+            .map(|y| {
+                (0..buffer.area.width)
+                    .map(|x| buffer[(x, y)].symbol())
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_render_dashboard() {
+        let snapshot = snapshot(1500000, BatteryState::Discharging);
+        let dashboard = Dashboard {
+            estimate: Some(Estimate::Empty(Duration::from_secs(90 * 60))),
+            limits: Limits {
+                lower: 20.0,
+                upper: 74.0,
+                critical: 5.0,
+            },
+            mode: ChargerMode::Auto,
+            command: ArduSketch::Connect,
+            daemon_status: Some("ok".to_owned()),
+            events: vec![ActuatorEvent {
+                time: snapshot.time.fixed_offset() - chrono::Duration::minutes(5),
+                command: ArduSketch::Disconnect,
+                error: None,
+                latency: Duration::from_millis(13200),
+                percentage_before: 74.1,
+                percentage_after: Some(74.0),
+            }],
+            history: vec![record(&snapshot, 20, 70.0), record(&snapshot, 2, 55.0)],
+            snapshot,
+        };
+        let screen = screen(&dashboard);
+
+        assert!(screen.contains("50.0%"), "{screen}");
+        assert!(screen.contains("Discharging, 12.00 W"), "{screen}");
+        assert!(screen.contains("empty in ~1 h 30 min"), "{screen}");
+        assert!(screen.contains("Limits: 20% - 74%, critical 5%"), "{screen}");
+        assert!(screen.contains("Controller: connect_charger (charging up to 74%)"), "{screen}");
+        assert!(screen.contains("Daemon: ok"), "{screen}");
+        assert!(
+            screen.contains("01-25 11:55:00 disconnect_charger ok 13.2s 74.1% -> 74.0%"),
+            "{screen}"
+        );
+        assert!(screen.contains("Charge, last 24 h"), "{screen}");
+    }
+
+    #[test]
+    fn test_render_without_history() {
+        let snapshot = snapshot(2220000, BatteryState::NotCharging);
+        let dashboard = Dashboard {
+            estimate: None,
+            limits: Limits {
+                lower: 20.0,
+                upper: 74.0,
+                critical: 5.0,
+            },
+            // Set over MQTT, the charger stays off
+            mode: ChargerMode::Disconnect,
+            command: ArduSketch::DoNothing,
+            daemon_status: None,
+            events: Vec::new(),
+            history: Vec::new(),
+            snapshot,
+        };
+        let screen = screen(&dashboard);
+
+        assert!(screen.contains("no estimate"), "{screen}");
+        assert!(screen.contains("Controller: do_nothing (disconnect mode)"), "{screen}");
+        assert!(screen.contains("Daemon: not running"), "{screen}");
+        assert!(screen.contains("No actuator events yet"), "{screen}");
+    }
+
+    #[test]
+    fn test_charge_buckets() {
+        let snapshot = snapshot(1500000, BatteryState::Discharging);
+        let end = snapshot.time.fixed_offset();
+        let records = vec![
+            record(&snapshot, 23, 80.0),
+            record(&snapshot, 22, 70.0),
+            record(&snapshot, 1, 41.0),
+            record(&snapshot, 0, 40.0),
+            // Out of the span
+            record(&snapshot, 30, 100.0),
+        ];
+
+        let buckets = tui::charge_buckets(&records, end, tui::SPARKLINE_SPAN, 4);
+        assert_eq!(buckets, vec![Some(75), None, None, Some(41)]);
+        assert!(tui::charge_buckets(&records, end, tui::SPARKLINE_SPAN, 0).is_empty());
+    }
+}
@@ -0,0 +1,65 @@
+// Import the uevent listener from the main module
+#[path = "../src/main.rs"]
+#[allow(dead_code)]
+mod main;
+
+use main::shutdown::{PowerEvents, Shutdown, Wake};
+use main::uevent::{listen, PowerEvent, SyntheticSource};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    const AC_UNPLUGGED: &[u8] = b"change@/devices/LNXSYSTM:00/ACPI0003:00/power_supply/ACAD\0\
+ACTION=change\0DEVPATH=/devices/LNXSYSTM:00/ACPI0003:00/power_supply/ACAD\0\
+SUBSYSTEM=power_supply\0POWER_SUPPLY_NAME=ACAD\0POWER_SUPPLY_TYPE=Mains\0\
+POWER_SUPPLY_ONLINE=0\0SEQNUM=4242\0";
+
+    fn ac_event(online: bool) -> PowerEvent {
+        PowerEvent {
+            action: "change".to_owned(),
+            supply_name: "ACAD".to_owned(),
+            online: Some(online),
+            status: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_power_supply_uevent() {
+        assert_eq!(PowerEvent::parse(AC_UNPLUGGED), Some(ac_event(false)));
+
+        let usb = b"add@/devices/usb1/1-1\0ACTION=add\0DEVPATH=/devices/usb1/1-1\0SUBSYSTEM=usb\0";
+        assert_eq!(PowerEvent::parse(usb), None);
+    }
+
+    #[test]
+    fn test_event_wakes_up_polling_loop() {
+        let shutdown = Shutdown::new();
+        let power_events = PowerEvents::new(&shutdown);
+        let (sender, receiver) = mpsc::channel();
+        listen(SyntheticSource::new(receiver), power_events.clone()).unwrap();
+
+        // Counted before the event, the waiter wakes up whether it starts waiting before or after
+        let seen = power_events.count();
+        let waiter_events = power_events.clone();
+        let waiter = std::thread::spawn(move || {
+            let started = Instant::now();
+            let wake = waiter_events.wait(seen, Duration::from_secs(60));
+            (wake, started.elapsed())
+        });
+        sender.send(ac_event(true)).unwrap();
+
+        let (wake, elapsed) = waiter.join().unwrap();
+        assert_eq!(wake, Wake::PowerEvent);
+        assert!(elapsed < Duration::from_secs(60));
+
+        // Without new events the wait falls back to polling
+        let seen = power_events.count();
+        assert_eq!(power_events.wait(seen, Duration::from_millis(10)), Wake::Timeout);
+        // The shutdown ends the wait too, and plain sleeps ignore the power events
+        shutdown.trigger();
+        assert_eq!(power_events.wait(seen, Duration::from_secs(60)), Wake::Shutdown);
+    }
+}
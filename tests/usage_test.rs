@@ -0,0 +1,116 @@
+// Import the usage report from the main module
+#[path = "../src/main.rs"]
+#[allow(dead_code)]
+mod main;
+
+use main::battery_health::BatteryState;
+use main::history::HistoryRecord;
+use main::usage::{self, UsagePeriod};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, Duration};
+
+    const WINDOW: (f32, f32) = (20.0, 74.0);
+
+    fn record(minutes: i64, percentage: f32, state: BatteryState) -> HistoryRecord {
+        // A Friday
+        let start = DateTime::parse_from_rfc3339("2025-01-24T08:00:00+01:00").unwrap();
+        HistoryRecord {
+            time: start + Duration::minutes(minutes),
+            charge_full: 3000,
+            charge_full_design: 3500,
+            battery_health: 85.71,
+            battery_percentage: Some(percentage),
+            battery_status: Some(state),
+            battery: None,
+        }
+    }
+
+    /// 2 hours on battery from 80% to 60%, 1 hour charging back to 74%,
+    /// 30 minutes holding, then the laptop is off until the next day
+    fn one_day(day: i64) -> Vec<HistoryRecord> {
+        let day = day * 24 * 60;
+        let mut records = (0..=4)
+            .map(|step| record(day + step * 30, 80.0 - 5.0 * step as f32, BatteryState::Discharging))
+            .collect::<Vec<_>>();
+        records[4].battery_status = Some(BatteryState::Charging);
+        records.push(record(day + 150, 67.0, BatteryState::Charging));
+        records.push(record(day + 180, 74.0, BatteryState::NotCharging));
+        records.push(record(day + 210, 74.0, BatteryState::NotCharging));
+        records
+    }
+
+    #[test]
+    fn test_daily_usage() {
+        let mut records = one_day(0);
+        records.extend(one_day(1));
+        records.reverse();
+        let summaries = usage::usage_report(&records, UsagePeriod::Daily, WINDOW).unwrap();
+
+        assert_eq!(summaries.len(), 2);
+        let friday = &summaries[0];
+        assert_eq!(friday.period, "2025-01-24");
+        assert!((friday.logged_hours - 3.5).abs() < 1e-9);
+        assert!((friday.discharging_hours - 2.0).abs() < 1e-9);
+        assert!((friday.charging_hours - 1.0).abs() < 1e-9);
+        assert!((friday.not_charging_hours - 0.5).abs() < 1e-9);
+        assert_eq!(friday.full_hours, 0.0);
+        assert!((friday.ac_hours() - 1.5).abs() < 1e-9);
+        assert!((friday.discharge_rate.unwrap() - 10.0).abs() < 1e-9);
+        // The night isn't logged time, and 74% to 80% isn't a drop
+        assert!((friday.cycles - 0.2).abs() < 1e-6);
+        // Everything but the first hour, above 74%
+        assert!((friday.window_hours - 2.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_drop_over_a_gap() {
+        // Suspended for 17 days, the battery drained meanwhile
+        let records = vec![
+            record(0, 89.5, BatteryState::Discharging),
+            record(17 * 24 * 60, 22.5, BatteryState::Discharging),
+            record(17 * 24 * 60 + 30, 17.5, BatteryState::Discharging),
+        ];
+        let summaries = usage::usage_report(&records, UsagePeriod::Daily, WINDOW).unwrap();
+
+        assert_eq!(summaries[0].logged_hours, 0.0);
+        assert_eq!(summaries[0].cycles, 0.0);
+        assert!((summaries[1].cycles - 0.05).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_weekly_usage() {
+        // Friday to Monday, the Monday is in the next ISO week
+        let records = (0..4).flat_map(one_day).collect::<Vec<_>>();
+        let summaries = usage::usage_report(&records, UsagePeriod::Weekly, WINDOW).unwrap();
+
+        let periods = summaries.iter().map(|summary| summary.period.as_str()).collect::<Vec<_>>();
+        assert_eq!(periods, ["2025-W04", "2025-W05"]);
+        assert!((summaries[0].discharging_hours - 6.0).abs() < 1e-9);
+        assert!((summaries[1].discharging_hours - 2.0).abs() < 1e-9);
+        assert!((summaries[0].cycles - 0.6).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_usage_output() {
+        let records = vec![
+            record(0, 50.0, BatteryState::Full),
+            record(30, 50.0, BatteryState::Full),
+        ];
+        let summaries = usage::usage_report(&records, UsagePeriod::Daily, WINDOW).unwrap();
+        assert_eq!(summaries[0].discharge_rate, None);
+
+        let table = usage::format_table(&summaries, WINDOW);
+        assert!(table.lines().next().unwrap().ends_with("20-74%"));
+        assert!(table.contains("2025-01-24"));
+
+        let json: serde_json::Value = serde_json::from_str(&usage::to_json(&summaries).unwrap()).unwrap();
+        assert_eq!(json[0]["period"], "2025-01-24");
+        assert_eq!(json[0]["full_hours"], 0.5);
+        assert!(json[0]["discharge_rate"].is_null());
+
+        assert!(usage::usage_report(&[], UsagePeriod::Daily, WINDOW).is_err());
+    }
+}